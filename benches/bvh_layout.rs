@@ -0,0 +1,301 @@
+//! Rays per second of the flattened 32-byte BVH nodes against the previous layout, where each
+//! node stored its bounds, two `usize` and a `bool` (48 bytes), and of the 4-wide BVH with each
+//! of the SIMD paths supported by the CPU.
+//!
+//! ```text
+//! cargo bench --bench bvh_layout [-- scene.toml|mesh.obj ...]
+//! ```
+//!
+//! Without arguments, the bundled scenes are used when their meshes are present, and a
+//! generated triangle soup otherwise. Both layouts trace the same rays through the same tree,
+//! on a single thread.
+
+use std::time::Instant;
+use toy_mesh_path_tracer::aabb::Aabb;
+use toy_mesh_path_tracer::bvh::HitType;
+use toy_mesh_path_tracer::hit::Hit;
+use toy_mesh_path_tracer::random::random_float01;
+use toy_mesh_path_tracer::ray::Ray;
+use toy_mesh_path_tracer::scene_file::{self, SceneDescription};
+use toy_mesh_path_tracer::triangle::Triangle;
+use toy_mesh_path_tracer::wide_bvh::{SimdPath, WideBvh};
+use toy_mesh_path_tracer::*;
+
+const RAY_MIN: f32 = 0.01;
+const RAY_MAX: f32 = 100.0;
+const RUNS: usize = 7;
+
+/// Node layout before the BVH was flattened.
+struct LegacyNode {
+    v: Aabb,
+    d1: usize,
+    d2: usize,
+    is_leaf: bool,
+}
+
+fn legacy_nodes(bvh: &Bvh) -> Vec<LegacyNode> {
+    bvh.nodes()
+        .iter()
+        .enumerate()
+        .map(|(idx, node)| {
+            if node.is_leaf() {
+                let triangles = node.triangles();
+                LegacyNode {
+                    v: *node.bounds(),
+                    d1: triangles.start,
+                    d2: triangles.len(),
+                    is_leaf: true,
+                }
+            } else {
+                LegacyNode {
+                    v: *node.bounds(),
+                    d1: idx + 1,
+                    d2: node.right_child(),
+                    is_leaf: false,
+                }
+            }
+        })
+        .collect()
+}
+
+/// Same traversal as `Bvh::intersect`, over the legacy nodes.
+fn legacy_intersect(
+    nodes: &[LegacyNode],
+    ray: &Ray,
+    tmin: f32,
+    tmax: f32,
+    hit_type: HitType,
+    triangles: &[Triangle],
+) -> Option<Hit> {
+    let mut tmax = tmax;
+    nodes.first()?.v.intersect_distance(ray, tmin, tmax)?;
+    let mut stack = [(0usize, 0f32); 64];
+    let mut stack_len = 0;
+    let mut hit = None;
+    let mut idx = 0;
+    loop {
+        let node = &nodes[idx];
+        if node.is_leaf {
+            for triangle in &triangles[node.d1..(node.d1 + node.d2)] {
+                if let Some(tri_hit) = triangle.intersect(ray, tmin, tmax) {
+                    tmax = tri_hit.t;
+                    hit = Some(tri_hit);
+                    if let HitType::Any = hit_type {
+                        return hit;
+                    }
+                }
+            }
+        } else {
+            let left = nodes[node.d1].v.intersect_distance(ray, tmin, tmax);
+            let right = nodes[node.d2].v.intersect_distance(ray, tmin, tmax);
+            match (left, right) {
+                (Some(t_left), Some(t_right)) => {
+                    let (near, far, t_far) = if t_left <= t_right {
+                        (node.d1, node.d2, t_right)
+                    } else {
+                        (node.d2, node.d1, t_left)
+                    };
+                    stack[stack_len] = (far, t_far);
+                    stack_len += 1;
+                    idx = near;
+                    continue;
+                }
+                (Some(_), None) => {
+                    idx = node.d1;
+                    continue;
+                }
+                (None, Some(_)) => {
+                    idx = node.d2;
+                    continue;
+                }
+                (None, None) => {}
+            }
+        }
+        loop {
+            if stack_len == 0 {
+                return hit;
+            }
+            stack_len -= 1;
+            let (far, t_far) = stack[stack_len];
+            if t_far <= tmax {
+                idx = far;
+                break;
+            }
+        }
+    }
+}
+
+/// Camera rays, plus a diffuse bounce from each primary hit so that incoherent rays are
+/// measured as well.
+fn make_rays(desc: &SceneDescription) -> Vec<Ray> {
+    let (width, height) = (320, 180);
+    let scene = &desc.scene;
+    let bvh = scene.bvh.as_ref().unwrap();
+    let camera = desc.camera.build(width as f32 / height as f32);
+    let mut rng = 0x9E3779B9u32;
+    let mut rays = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let u = (x as f32 + random_float01(&mut rng)) / width as f32;
+            let v = 1.0 - (y as f32 + random_float01(&mut rng)) / height as f32;
+            let ray = match camera.get_ray(u, v, &mut rng) {
+                Some(ray) => ray,
+                None => continue,
+            };
+            let hit = bvh.intersect(
+                &ray,
+                RAY_MIN,
+                RAY_MAX,
+                HitType::Closest,
+                &scene.triangle_list,
+            );
+            if let Some(hit) = hit {
+                let normal = hit.normal * -Vec3::dot(&hit.normal, &ray.dir()).signum();
+                let mut dir = normal + Vec3::rand_unit(&mut rng);
+                if dir.length_sq() < 1e-6 {
+                    dir = normal;
+                }
+                rays.push(Ray::new(&hit.pos, &dir.normalize()));
+            }
+            rays.push(ray);
+        }
+    }
+    rays
+}
+
+/// Rays per second of one run, and the number of hits as a checksum.
+fn measure<F: Fn(&Ray) -> Option<Hit>>(rays: &[Ray], intersect: F) -> (f64, usize) {
+    let begin = Instant::now();
+    let hits = rays.iter().filter(|ray| intersect(ray).is_some()).count();
+    let seconds = begin.elapsed().as_secs_f64();
+    (rays.len() as f64 / seconds, hits)
+}
+
+fn bench(name: &str, desc: &SceneDescription) {
+    let scene = &desc.scene;
+    let bvh = scene.bvh.as_ref().unwrap();
+    let triangles = &scene.triangle_list[..];
+    let legacy = legacy_nodes(bvh);
+    let wide_bvhs: Vec<WideBvh> = [SimdPath::Scalar, SimdPath::Sse, SimdPath::Avx]
+        .iter()
+        .filter(|path| path.is_supported())
+        .map(|&path| WideBvh::from_bvh(bvh).with_simd_path(path))
+        .collect();
+    let rays = make_rays(desc);
+
+    println!(
+        "{} ({} tris, {} nodes, {} rays)",
+        name,
+        triangles.len(),
+        bvh.nodes().len(),
+        rays.len()
+    );
+    for (label, hit_type) in [("closest", HitType::Closest), ("any", HitType::Any)] {
+        // the layouts take turns so that both suffer the same frequency changes, and the best
+        // run of each is kept
+        let mut legacy_rate = 0.0f64;
+        let mut flat_rate = 0.0f64;
+        let mut wide_rates = vec![0.0f64; wide_bvhs.len()];
+        for _ in 0..RUNS {
+            let (rate, legacy_hits) = measure(&rays, |ray| {
+                legacy_intersect(&legacy, ray, RAY_MIN, RAY_MAX, hit_type, triangles)
+            });
+            legacy_rate = legacy_rate.max(rate);
+            let (rate, flat_hits) = measure(&rays, |ray| {
+                bvh.intersect(ray, RAY_MIN, RAY_MAX, hit_type, triangles)
+            });
+            flat_rate = flat_rate.max(rate);
+            assert_eq!(legacy_hits, flat_hits);
+            for (wide_bvh, wide_rate) in wide_bvhs.iter().zip(wide_rates.iter_mut()) {
+                let (rate, wide_hits) = measure(&rays, |ray| {
+                    wide_bvh.intersect(ray, RAY_MIN, RAY_MAX, hit_type, triangles)
+                });
+                *wide_rate = wide_rate.max(rate);
+                assert_eq!(legacy_hits, wide_hits);
+            }
+        }
+        println!(
+            "  {:8} legacy {:8.0} K rays/s, flattened {:8.0} K rays/s ({:+.1}%)",
+            label,
+            legacy_rate / 1000.0,
+            flat_rate / 1000.0,
+            (flat_rate / legacy_rate - 1.0) * 100.0
+        );
+        for (wide_bvh, wide_rate) in wide_bvhs.iter().zip(wide_rates.iter()) {
+            println!(
+                "  {:8} 4-wide {:?} {:8.0} K rays/s ({:+.1}%)",
+                "",
+                wide_bvh.simd_path(),
+                wide_rate / 1000.0,
+                (wide_rate / legacy_rate - 1.0) * 100.0
+            );
+        }
+    }
+}
+
+fn load(filename: &str) -> Result<SceneDescription, String> {
+    if filename.ends_with(".toml") {
+        scene_file::load(filename).map_err(|err| err.to_string())
+    } else {
+        obj_loader::load_scene(filename)
+            .map_err(|err| err.to_string())
+            .map(|mesh| SceneDescription::from_mesh(&mesh))
+    }
+}
+
+fn generated_scene() -> SceneDescription {
+    let mut rng = 0x1234567u32;
+    let mut random_point = |scale: f32| {
+        Vec3::new(
+            random_float01(&mut rng) - 0.5,
+            random_float01(&mut rng) - 0.5,
+            random_float01(&mut rng) - 0.5,
+        ) * scale
+    };
+    let triangles: Vec<Triangle> = (0..100_000)
+        .map(|_| {
+            let center = random_point(10.0);
+            Triangle::new(
+                center + random_point(0.3),
+                center + random_point(0.3),
+                center + random_point(0.3),
+            )
+        })
+        .collect();
+    let mesh = obj_loader::ObjMesh {
+        triangles,
+        materials: vec![mtl_loader::MtlMaterial::new("")],
+        warnings: Vec::new(),
+    };
+    SceneDescription::from_mesh(&mesh)
+}
+
+fn main() {
+    // cargo bench passes `--bench` to the harness
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let filenames = if args.is_empty() {
+        vec![
+            String::from("scenes/suzanne.toml"),
+            String::from("scenes/sponza.toml"),
+        ]
+    } else {
+        args.clone()
+    };
+
+    let mut measured = 0;
+    for filename in filenames.iter() {
+        match load(filename) {
+            Ok(desc) => {
+                bench(filename, &desc);
+                measured += 1;
+            }
+            Err(err) => println!("skipping {}: {}", filename, err),
+        }
+    }
+    if measured == 0 && args.is_empty() {
+        bench("generated triangle soup", &generated_scene());
+    }
+}
@@ -1,6 +1,7 @@
 use crate::ray::*;
 use crate::vec3::*;
 
+#[derive(Clone, Copy)]
 pub struct Aabb {
     min: Vec3,
     max: Vec3,
@@ -23,6 +24,18 @@ impl Aabb {
         return false;
     }
 
+    pub fn min(&self) -> Vec3 {
+        self.min
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.max
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
     pub fn size(&self) -> Vec3 {
         self.max - self.min
     }
@@ -56,8 +69,8 @@ impl Aabb {
             }
         } else {
             Aabb {
-                min: self.min.min(&point),
-                max: self.max.max(&point),
+                min: self.min.min(point),
+                max: self.max.max(point),
             }
         }
     }
@@ -77,6 +90,13 @@ impl Aabb {
     }
 
     pub fn test_intersection(&self, ray: &Ray, tmin: f32, tmax: f32) -> bool {
+        self.intersect_distance(ray, tmin, tmax).is_some()
+    }
+
+    /// Distance along `ray` at which it enters the box, or `tmin` when it starts inside. `None`
+    /// when the ray misses the box between `tmin` and `tmax`.
+    #[inline]
+    pub fn intersect_distance(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<f32> {
         let t0 = (self.min - ray.origin()) * ray.dir_inv();
         let t1 = (self.max - ray.origin()) * ray.dir_inv();
 
@@ -86,7 +106,10 @@ impl Aabb {
         let tmin = tmin.max(tsmaller.hmax());
         let tmax = tmax.min(tbigger.hmin());
 
-        return tmin <= tmax;
+        if tmin <= tmax {
+            return Some(tmin);
+        }
+        return None;
     }
 }
 
@@ -152,5 +175,10 @@ mod tests {
 
         let ray = Ray::new(&Vec3::new(0.0, 0.0, -5.0), &Vec3::new(0.0, 1.0, 0.0));
         assert!(!aabb.test_intersection(&ray, 0.0, 100.0));
+
+        let ray = Ray::new(&Vec3::new(0.0, 0.0, -5.0), &Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(Some(4.0), aabb.intersect_distance(&ray, 0.0, 100.0));
+        assert_eq!(Some(0.0), aabb.intersect_distance(&ray_inside, 0.0, 100.0));
+        assert_eq!(None, aabb.intersect_distance(&ray, 0.0, 3.0));
     }
 }
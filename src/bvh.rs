@@ -1,10 +1,12 @@
 use crate::aabb::*;
 use crate::hit::*;
-use crate::random::*;
 use crate::ray::*;
 use crate::triangle::*;
+use crate::vec3::*;
 
-use std::cmp;
+use rayon::prelude::*;
+use std::fmt;
+use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone)]
 pub enum HitType {
@@ -14,183 +16,809 @@ pub enum HitType {
 
 pub struct Bvh {
     nodes: Vec<BvhNode>,
+    /// Duration of `Bvh::create`, unknown for trees loaded from a cache.
+    build_time: Option<Duration>,
 }
 
-struct BvhNode {
-    v: Aabb,
-    d1: usize,
-    d2: usize,
-    is_leaf: bool,
+/// Quality report of a tree, see `Bvh::stats`.
+pub struct BvhStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    /// Number of leaves at each depth, the root being at depth 0.
+    pub depth_histogram: Vec<usize>,
+    pub average_leaf_size: f32,
+    pub max_leaf_size: usize,
+    /// Expected cost of a random ray hitting the root, in triangle intersections: the traversal
+    /// and intersection costs of each node, weighted by its area relative to the root's.
+    pub sah_cost: f32,
+    pub build_time: Option<Duration>,
 }
 
-impl Bvh {
-    fn order_triangle(a: &Triangle, b: &Triangle, idx: usize) -> cmp::Ordering {
-        let a_min = a
-            .vertices
-            .iter()
-            .fold(std::f32::INFINITY, |min, vertice| min.min(vertice.get(idx)));
-        let b_min = b
-            .vertices
-            .iter()
-            .fold(std::f32::INFINITY, |min, vertice| min.min(vertice.get(idx)));
-        a_min.partial_cmp(&b_min).unwrap()
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} nodes, {} leaves, SAH cost {:.2}",
+            self.node_count, self.leaf_count, self.sah_cost
+        )?;
+        writeln!(
+            f,
+            "triangles per leaf: {:.2} on average, {} at most",
+            self.average_leaf_size, self.max_leaf_size
+        )?;
+        match self.build_time {
+            Some(build_time) => writeln!(f, "built in {:.3}s", build_time.as_secs_f32())?,
+            None => writeln!(f, "loaded from a cache")?,
+        }
+        writeln!(f, "leaves per depth:")?;
+        let max_count = self.depth_histogram.iter().copied().max().unwrap_or(0);
+        for (depth, &count) in self.depth_histogram.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let bar_length = (count * 40).div_ceil(max_count);
+            writeln!(f, "{:4} {:8} {}", depth, count, "#".repeat(bar_length))?;
+        }
+        Ok(())
+    }
+}
+
+/// Work done by `Bvh::intersect_counted`.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct TraversalCounts {
+    /// Nodes entered, leaves included.
+    pub nodes: usize,
+    /// Triangles tested against the ray.
+    pub triangles: usize,
+}
+
+/// Node of the flattened tree, 32 bytes so that two of them fit in a cache line. Nodes are
+/// stored in depth-first order, the left child of an internal node directly following it.
+#[derive(Clone, Copy)]
+#[repr(C, align(32))]
+pub struct BvhNode {
+    bounds: Aabb,
+    /// Index of the right child for internal nodes, of the first triangle for leaves.
+    offset: u32,
+    /// Number of triangles of leaves, 0 for internal nodes.
+    count: u32,
+}
+
+impl BvhNode {
+    fn internal(bounds: Aabb, right_child: usize) -> BvhNode {
+        BvhNode {
+            bounds,
+            offset: right_child as u32,
+            count: 0,
+        }
+    }
+
+    fn leaf(bounds: Aabb, first_triangle: usize, count: usize) -> BvhNode {
+        BvhNode {
+            bounds,
+            offset: first_triangle as u32,
+            count: count as u32,
+        }
+    }
+
+    /// Node with the raw `offset` and `count` fields, as read back from `raw`.
+    pub(crate) fn from_raw(bounds: Aabb, offset: u32, count: u32) -> BvhNode {
+        BvhNode {
+            bounds,
+            offset,
+            count,
+        }
+    }
+
+    pub(crate) fn raw(&self) -> (u32, u32) {
+        (self.offset, self.count)
+    }
+
+    pub fn bounds(&self) -> &Aabb {
+        &self.bounds
     }
 
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+
+    /// Index of the right child of an internal node, the left one being the next node.
+    pub fn right_child(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Triangles of a leaf, as a range in the triangle list the tree was built from.
+    pub fn triangles(&self) -> std::ops::Range<usize> {
+        self.offset as usize..(self.offset + self.count) as usize
+    }
+}
+
+/// Number of candidate split positions along the split axis.
+const BIN_COUNT: usize = 32;
+/// Nodes with at most this many triangles are not split.
+const MAX_LEAF_SIZE: usize = 4;
+/// Cost of traversing a node, relative to intersecting a triangle.
+const TRAVERSAL_COST: f32 = 0.125;
+/// Maximum depth of the tree, which bounds the traversal stack. Deeper nodes are made leaves
+/// whatever their size, which only happens for degenerate meshes.
+pub(crate) const MAX_DEPTH: usize = 64;
+/// Nodes with more triangles build their children, and bin their triangles, in parallel.
+const PARALLEL_THRESHOLD: usize = 4096;
+/// Number of triangles binned by each task of the parallel passes.
+const PARALLEL_CHUNK_SIZE: usize = 1024;
+/// Number of intervals the motion of moving triangles is split into to bound it.
+const MOTION_STEPS: usize = 16;
+
+/// Triangle being sorted into the tree.
+struct BuildPrimitive {
+    index: usize,
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+impl Bin {
+    fn empty_bins() -> [Bin; BIN_COUNT] {
+        [Bin {
+            bounds: Aabb::empty(),
+            count: 0,
+        }; BIN_COUNT]
+    }
+
+    fn merge(mut bins: [Bin; BIN_COUNT], other: [Bin; BIN_COUNT]) -> [Bin; BIN_COUNT] {
+        for (bin, other) in bins.iter_mut().zip(other.iter()) {
+            bin.bounds = Aabb::union(&bin.bounds, &other.bounds);
+            bin.count += other.count;
+        }
+        bins
+    }
+}
+
+/// Accumulate `primitives` with `accumulate`, splitting the work in chunks reduced with `reduce`
+/// when `parallel` is set. `reduce` must be associative and commutative for the result not to depend
+/// on the scheduling: unions of bounds and sums of counts are.
+fn fold_primitives<T, F, R>(
+    primitives: &[BuildPrimitive],
+    parallel: bool,
+    identity: T,
+    accumulate: F,
+    reduce: R,
+) -> T
+where
+    T: Copy + Send + Sync,
+    F: Fn(&mut T, &BuildPrimitive) + Sync,
+    R: Fn(T, T) -> T + Sync,
+{
+    let accumulate_chunk = |chunk: &[BuildPrimitive]| {
+        let mut value = identity;
+        for prim in chunk {
+            accumulate(&mut value, prim);
+        }
+        value
+    };
+    if parallel {
+        primitives
+            .par_chunks(PARALLEL_CHUNK_SIZE)
+            .map(accumulate_chunk)
+            .reduce(|| identity, &reduce)
+    } else {
+        accumulate_chunk(primitives)
+    }
+}
+
+impl Bvh {
+    /// Bounds of the triangle during all of its motion, sampled at `MOTION_STEPS + 1` times.
+    /// Between two samples, each vertex is bounded by the sphere whose diameter joins its two
+    /// positions, which contains the arc it follows while turning by less than half a turn.
     fn triangle_aabb(triangle: &Triangle) -> Aabb {
-        triangle
-            .vertices
-            .iter()
-            .fold(Aabb::empty(), |aabb, v| aabb.extend(v))
+        let motion = match &triangle.motion {
+            Some(motion) => motion,
+            None => {
+                return triangle
+                    .vertices
+                    .iter()
+                    .fold(Aabb::empty(), |aabb, v| aabb.extend(v));
+            }
+        };
+        let mut bounds = Aabb::empty();
+        let mut previous = motion.vertices_at(0.0);
+        for step in 1..=MOTION_STEPS {
+            let vertices = motion.vertices_at(step as f32 / MOTION_STEPS as f32);
+            for (start, end) in previous.iter().zip(vertices.iter()) {
+                let center = (*start + *end) * 0.5;
+                let radius = Vec3::fill((*end - *start).length() * 0.5);
+                bounds = bounds.extend(&(center - radius)).extend(&(center + radius));
+            }
+            previous = vertices;
+        }
+        return bounds;
+    }
+
+    /// Binned SAH split: the primitives are sorted in `BIN_COUNT` bins along the axis where
+    /// their centroids spread the most, and the best of the bin boundaries is chosen. Returns
+    /// the number of primitives moved to the left side, or `None` when splitting costs more
+    /// than intersecting them all. The bins are filled in parallel above `parallel_threshold`
+    /// primitives.
+    fn split(
+        primitives: &mut [BuildPrimitive],
+        bounds: &Aabb,
+        parallel_threshold: usize,
+    ) -> Option<usize> {
+        let parallel = primitives.len() > parallel_threshold;
+        let centroid_bounds = fold_primitives(
+            primitives,
+            parallel,
+            Aabb::empty(),
+            |aabb, prim| *aabb = aabb.extend(&prim.centroid),
+            |a, b| Aabb::union(&a, &b),
+        );
+        let extent = centroid_bounds.size();
+        let mut axis = 0;
+        for idx in 1..3 {
+            if extent.get(idx) > extent.get(axis) {
+                axis = idx;
+            }
+        }
+        let axis_min = centroid_bounds.min().get(axis);
+        let axis_extent = extent.get(axis);
+        if axis_extent <= 0.0 {
+            // all the centroids are on the same spot, split in the middle
+            return Some(primitives.len() / 2);
+        }
+        let scale = BIN_COUNT as f32 / axis_extent;
+        let bin_index = |prim: &BuildPrimitive| {
+            let bin = ((prim.centroid.get(axis) - axis_min) * scale) as usize;
+            bin.min(BIN_COUNT - 1)
+        };
+
+        let bins = fold_primitives(
+            primitives,
+            parallel,
+            Bin::empty_bins(),
+            |bins, prim| {
+                let bin = &mut bins[bin_index(prim)];
+                bin.bounds = Aabb::union(&bin.bounds, &prim.bounds);
+                bin.count += 1;
+            },
+            Bin::merge,
+        );
+
+        // sweep from the right to get the cost of the right side of each boundary, then from
+        // the left to find the best one
+        let mut right_costs = [0f32; BIN_COUNT];
+        let mut right_bounds = Aabb::empty();
+        let mut right_count = 0;
+        for idx in (1..BIN_COUNT).rev() {
+            right_bounds = Aabb::union(&right_bounds, &bins[idx].bounds);
+            right_count += bins[idx].count;
+            right_costs[idx] = if right_count > 0 {
+                right_count as f32 * right_bounds.surface_area()
+            } else {
+                0.0
+            };
+        }
+        let mut left_bounds = Aabb::empty();
+        let mut left_count = 0;
+        let mut best_cost = f32::INFINITY;
+        let mut best_boundary = 0;
+        for idx in 1..BIN_COUNT {
+            left_bounds = Aabb::union(&left_bounds, &bins[idx - 1].bounds);
+            left_count += bins[idx - 1].count;
+            if left_count == 0 || left_count == primitives.len() {
+                continue;
+            }
+            let cost = left_count as f32 * left_bounds.surface_area() + right_costs[idx];
+            if cost < best_cost {
+                best_cost = cost;
+                best_boundary = idx;
+            }
+        }
+        if best_boundary == 0 {
+            return Some(primitives.len() / 2);
+        }
+        let split_cost = TRAVERSAL_COST + best_cost / bounds.surface_area();
+        if primitives.len() <= MAX_LEAF_SIZE && split_cost >= primitives.len() as f32 {
+            return None;
+        }
+
+        // partition in place
+        let mut left_end = 0;
+        for idx in 0..primitives.len() {
+            if bin_index(&primitives[idx]) < best_boundary {
+                primitives.swap(idx, left_end);
+                left_end += 1;
+            }
+        }
+        return Some(left_end);
     }
 
+    /// Append the nodes of the subtree over `primitives` to `bvh` in depth-first order, and
+    /// return the index of its root. Large subtrees build their two children in parallel, into
+    /// separate lists which are then appended in the same order as a serial build would.
     fn create_impl(
         tri_start: usize,
-        tri_count: usize,
-        triangle_list: &mut [Triangle],
+        primitives: &mut [BuildPrimitive],
+        depth: usize,
         bvh: &mut Vec<BvhNode>,
-        rng: &mut u32,
+        parallel_threshold: usize,
     ) -> usize {
-        *rng = xor_shift_32(*rng);
-        let idx = (*rng % 3) as usize;
+        let node_idx = bvh.len();
+        bvh.push(BvhNode::internal(Aabb::empty(), 0));
+        let parallel = primitives.len() > parallel_threshold;
+        let bounds = fold_primitives(
+            primitives,
+            parallel,
+            Aabb::empty(),
+            |aabb, prim| *aabb = Aabb::union(aabb, &prim.bounds),
+            |a, b| Aabb::union(&a, &b),
+        );
+        let split = if primitives.len() <= 1 || depth + 1 >= MAX_DEPTH {
+            None
+        } else {
+            Bvh::split(primitives, &bounds, parallel_threshold)
+        };
+        let node = match split {
+            Some(split_idx) if parallel => {
+                let (left, right) = primitives.split_at_mut(split_idx);
+                let build = |tri_start: usize, primitives: &mut [BuildPrimitive]| {
+                    let mut nodes = Vec::with_capacity(2 * primitives.len());
+                    Bvh::create_impl(
+                        tri_start,
+                        primitives,
+                        depth + 1,
+                        &mut nodes,
+                        parallel_threshold,
+                    );
+                    nodes
+                };
+                let (left_nodes, right_nodes) = rayon::join(
+                    || build(tri_start, left),
+                    || build(tri_start + split_idx, right),
+                );
+                Bvh::append_subtree(bvh, &left_nodes);
+                let right_child = Bvh::append_subtree(bvh, &right_nodes);
+                BvhNode::internal(bounds, right_child)
+            }
+            Some(split_idx) => {
+                let (left, right) = primitives.split_at_mut(split_idx);
+                Bvh::create_impl(tri_start, left, depth + 1, bvh, parallel_threshold);
+                let right_child = Bvh::create_impl(
+                    tri_start + split_idx,
+                    right,
+                    depth + 1,
+                    bvh,
+                    parallel_threshold,
+                );
+                BvhNode::internal(bounds, right_child)
+            }
+            None => BvhNode::leaf(bounds, tri_start, primitives.len()),
+        };
+        bvh[node_idx] = node;
+        return node_idx;
+    }
 
-        triangle_list.sort_unstable_by(|a, b| Bvh::order_triangle(a, b, idx));
+    /// Append `subtree`, whose root is its first node, to `bvh` and return the new index of
+    /// the root.
+    fn append_subtree(bvh: &mut Vec<BvhNode>, subtree: &[BvhNode]) -> usize {
+        let offset = bvh.len();
+        bvh.extend(subtree.iter().map(|node| {
+            let mut node = *node;
+            if !node.is_leaf() {
+                node.offset += offset as u32;
+            }
+            node
+        }));
+        return offset;
+    }
 
-        let node_idx = bvh.len();
-        {
-            let dummy = BvhNode {
-                v: Aabb::empty(),
-                d1: usize::max_value(),
-                d2: usize::max_value(),
-                is_leaf: false,
+    fn create_with_threshold(triangle_list: &mut [Triangle], parallel_threshold: usize) -> Bvh {
+        assert!(
+            triangle_list.len() < u32::MAX as usize / 2,
+            "too many triangles for the BVH node layout"
+        );
+        let begin = Instant::now();
+        if triangle_list.is_empty() {
+            return Bvh {
+                nodes: Vec::new(),
+                build_time: Some(begin.elapsed()),
             };
-            bvh.push(dummy);
         }
-        let mut node = BvhNode {
-            v: Aabb::empty(),
-            d1: usize::max_value(),
-            d2: usize::max_value(),
-            is_leaf: false,
-        };
-        if tri_count <= 4 {
-            node.d1 = tri_start;
-            node.d2 = tri_count;
-            node.is_leaf = true;
-            node.v = triangle_list.iter().fold(Aabb::empty(), |aabb, tr| {
-                Aabb::union(&aabb, &Bvh::triangle_aabb(tr))
-            });
-        } else {
-            //let split_idx = triangle_list.len() / 2;
-            let split_idx = {
-                let mut split_cost = Vec::with_capacity(triangle_list.len());
-                for idx in 0..triangle_list.len() {
-                    let left_aabb = triangle_list
-                        .iter()
-                        .take(idx)
-                        .fold(Aabb::empty(), |aabb, tr| {
-                            Aabb::union(&aabb, &Bvh::triangle_aabb(tr))
-                        });
-                    let right_aabb = triangle_list
-                        .iter()
-                        .skip(idx)
-                        .fold(Aabb::empty(), |aabb, tr| {
-                            Aabb::union(&aabb, &Bvh::triangle_aabb(tr))
-                        });
-                    let total_aabb = Aabb::union(&left_aabb, &right_aabb);
-                    let cost: f32 = 0.125f32
-                        + (idx as f32 * left_aabb.surface_area()
-                            + (triangle_list.len() - idx) as f32 * right_aabb.surface_area())
-                            / total_aabb.surface_area();
-                    split_cost.push(cost);
+        let mut primitives: Vec<BuildPrimitive> = triangle_list
+            .par_iter()
+            .enumerate()
+            .map(|(index, triangle)| {
+                let bounds = Bvh::triangle_aabb(triangle);
+                BuildPrimitive {
+                    index,
+                    bounds,
+                    centroid: bounds.center(),
                 }
-                let split_min_cost = split_cost
-                    .iter()
-                    .fold(std::f32::INFINITY, |min_val, &val| min_val.min(val));
-                split_cost
-                    .iter()
-                    .position(|&val| val == split_min_cost)
-                    .unwrap()
-            };
-            let (left, right) = triangle_list.split_at_mut(split_idx);
-            node.d1 = Bvh::create_impl(tri_start, split_idx, left, bvh, rng);
-            node.d2 = Bvh::create_impl(
-                tri_start + split_idx,
-                tri_count - split_idx,
-                right,
-                bvh,
-                rng,
-            );
-            node.is_leaf = false;
-            node.v = Aabb::union(&bvh[node.d1].v, &bvh[node.d2].v);
+            })
+            .collect();
+        let mut bvh: Vec<BvhNode> = Vec::with_capacity(2 * triangle_list.len());
+        Bvh::create_impl(0, &mut primitives, 0, &mut bvh, parallel_threshold);
+
+        let reordered: Vec<Triangle> = primitives
+            .par_iter()
+            .map(|prim| triangle_list[prim.index].clone())
+            .collect();
+        triangle_list.clone_from_slice(&reordered);
+        return Bvh {
+            nodes: bvh,
+            build_time: Some(begin.elapsed()),
+        };
+    }
+
+    /// Tree from nodes previously returned by `nodes`.
+    pub(crate) fn from_nodes(nodes: Vec<BvhNode>) -> Bvh {
+        Bvh {
+            nodes,
+            build_time: None,
         }
+    }
 
-        bvh[node_idx] = node;
-        return node_idx;
+    /// Nodes in depth-first order, the root first.
+    pub fn nodes(&self) -> &[BvhNode] {
+        &self.nodes
     }
 
+    /// Build the tree, reordering `triangle_list` so that each leaf covers a contiguous range.
+    /// Large meshes are built on the rayon thread pool, with the same result as a serial build.
     pub fn create(triangle_list: &mut [Triangle]) -> Bvh {
-        let mut bvh: Vec<BvhNode> = Vec::new();
-        let mut rng = 0xF215C12Eu32;
-        Bvh::create_impl(0, triangle_list.len(), triangle_list, &mut bvh, &mut rng);
-        return Bvh { nodes: bvh };
+        Bvh::create_with_threshold(triangle_list, PARALLEL_THRESHOLD)
+    }
+
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            node_count: self.nodes.len(),
+            leaf_count: 0,
+            depth_histogram: Vec::new(),
+            average_leaf_size: 0.0,
+            max_leaf_size: 0,
+            sah_cost: 0.0,
+            build_time: self.build_time,
+        };
+        let root_area = match self.nodes.first() {
+            Some(root) => root.bounds.surface_area(),
+            None => return stats,
+        };
+        let mut triangle_count = 0;
+        // nodes are in depth-first order, so each node's depth is known when it is reached
+        let mut depths = vec![0usize; self.nodes.len()];
+        for (idx, node) in self.nodes.iter().enumerate() {
+            let relative_area = if root_area > 0.0 {
+                node.bounds.surface_area() / root_area
+            } else {
+                1.0
+            };
+            if node.is_leaf() {
+                let count = node.count as usize;
+                stats.leaf_count += 1;
+                stats.max_leaf_size = stats.max_leaf_size.max(count);
+                triangle_count += count;
+                stats.sah_cost += relative_area * count as f32;
+                let depth = depths[idx];
+                if stats.depth_histogram.len() <= depth {
+                    stats.depth_histogram.resize(depth + 1, 0);
+                }
+                stats.depth_histogram[depth] += 1;
+            } else {
+                stats.sah_cost += relative_area * TRAVERSAL_COST;
+                depths[idx + 1] = depths[idx] + 1;
+                depths[node.right_child()] = depths[idx] + 1;
+            }
+        }
+        stats.average_leaf_size = triangle_count as f32 / stats.leaf_count as f32;
+        return stats;
     }
 
-    fn intersect_impl(
+    /// Closest (or any) hit of `ray` between `tmin` and `tmax`. The tree is walked with an
+    /// explicit stack, visiting the nearer child first and skipping the subtrees entered
+    /// farther than the closest hit found so far.
+    pub fn intersect(
         &self,
-        idx: usize,
         ray: &Ray,
         tmin: f32,
-        tmax: &mut f32,
+        tmax: f32,
         hit_type: HitType,
         triangles: &[Triangle],
     ) -> Option<Hit> {
-        let node = &self.nodes[idx];
-        if !node.v.test_intersection(ray, tmin, *tmax) {
-            return None;
-        }
+        let mut counts = TraversalCounts::default();
+        self.traverse::<false>(ray, tmin, tmax, hit_type, triangles, &mut counts)
+    }
 
+    /// Same as `intersect`, adding the nodes entered and the triangles tested to `counts`.
+    pub fn intersect_counted(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        hit_type: HitType,
+        triangles: &[Triangle],
+        counts: &mut TraversalCounts,
+    ) -> Option<Hit> {
+        self.traverse::<true>(ray, tmin, tmax, hit_type, triangles, counts)
+    }
+
+    #[inline(always)]
+    fn traverse<const COUNT: bool>(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        hit_type: HitType,
+        triangles: &[Triangle],
+        counts: &mut TraversalCounts,
+    ) -> Option<Hit> {
+        let mut tmax = tmax;
+        self.nodes
+            .first()?
+            .bounds
+            .intersect_distance(ray, tmin, tmax)?;
+        // far children still to visit, with their entry distance
+        let mut stack = [(0usize, 0f32); MAX_DEPTH];
+        let mut stack_len = 0;
         let mut hit = None;
-        if node.is_leaf {
-            for tri_idx in node.d1..(node.d1 + node.d2) {
-                let tri_hit = triangles[tri_idx].intersect(ray, tmin, *tmax);
-                if tri_hit.is_some() {
-                    let tri_hit = tri_hit.unwrap();
-                    *tmax = tri_hit.t;
-                    hit = Some(tri_hit);
-                    if let HitType::Any = hit_type {
-                        return hit;
+        let mut idx = 0;
+        loop {
+            let node = &self.nodes[idx];
+            if COUNT {
+                counts.nodes += 1;
+            }
+            if node.is_leaf() {
+                for triangle in &triangles[node.triangles()] {
+                    if COUNT {
+                        counts.triangles += 1;
+                    }
+                    if let Some(tri_hit) = triangle.intersect_distance(ray, tmin, tmax) {
+                        tmax = tri_hit.t;
+                        if let HitType::Any = hit_type {
+                            return Some(triangle.hit(ray, &tri_hit));
+                        }
+                        hit = Some((triangle, tri_hit));
+                    }
+                }
+            } else {
+                let (left_idx, right_idx) = (idx + 1, node.right_child());
+                let left = self.nodes[left_idx]
+                    .bounds
+                    .intersect_distance(ray, tmin, tmax);
+                let right = self.nodes[right_idx]
+                    .bounds
+                    .intersect_distance(ray, tmin, tmax);
+                match (left, right) {
+                    (Some(t_left), Some(t_right)) => {
+                        let (near, far, t_far) = if t_left <= t_right {
+                            (left_idx, right_idx, t_right)
+                        } else {
+                            (right_idx, left_idx, t_left)
+                        };
+                        stack[stack_len] = (far, t_far);
+                        stack_len += 1;
+                        idx = near;
+                        continue;
+                    }
+                    (Some(_), None) => {
+                        idx = left_idx;
+                        continue;
+                    }
+                    (None, Some(_)) => {
+                        idx = right_idx;
+                        continue;
                     }
+                    (None, None) => {}
                 }
             }
-        } else {
-            let left_hit = self.intersect_impl(node.d1, ray, tmin, tmax, hit_type, triangles);
-            if left_hit.is_some() {
-                hit = left_hit;
-                if let HitType::Any = hit_type {
-                    return hit;
+
+            // resume with the nearest pending subtree which can still hold a closer hit
+            loop {
+                if stack_len == 0 {
+                    // shading data for the closest hit only
+                    return hit.map(|(triangle, tri_hit)| triangle.hit(ray, &tri_hit));
+                }
+                stack_len -= 1;
+                let (far, t_far) = stack[stack_len];
+                if t_far <= tmax {
+                    idx = far;
+                    break;
                 }
             }
-            let right_hit = self.intersect_impl(node.d2, ray, tmin, tmax, hit_type, triangles);
-            if right_hit.is_some() {
-                hit = right_hit;
-                if let HitType::Any = hit_type {
-                    return hit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::random_float01;
+    use crate::transform::Placement;
+
+    fn random_point(rng: &mut u32, scale: f32) -> Vec3 {
+        Vec3::new(
+            random_float01(rng) - 0.5,
+            random_float01(rng) - 0.5,
+            random_float01(rng) - 0.5,
+        ) * scale
+    }
+
+    fn random_triangles(count: usize, rng: &mut u32) -> Vec<Triangle> {
+        (0..count)
+            .map(|_| {
+                let center = random_point(rng, 10.0);
+                Triangle::new(
+                    center + random_point(rng, 1.0),
+                    center + random_point(rng, 1.0),
+                    center + random_point(rng, 1.0),
+                )
+            })
+            .collect()
+    }
+
+    fn brute_force(ray: &Ray, triangles: &[Triangle]) -> Option<Hit> {
+        let mut closest: Option<Hit> = None;
+        for triangle in triangles {
+            let tmax = closest.map_or(100.0, |hit| hit.t);
+            if let Some(hit) = triangle.intersect(ray, 0.0, tmax) {
+                closest = Some(hit);
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn leaves_cover_triangles() {
+        let mut rng = 0x1234567u32;
+        let mut triangles = random_triangles(1000, &mut rng);
+        let bvh = Bvh::create(&mut triangles);
+        let mut covered = vec![0; triangles.len()];
+        for node in bvh.nodes.iter().filter(|node| node.is_leaf()) {
+            assert!(node.triangles().len() <= MAX_LEAF_SIZE);
+            for idx in node.triangles() {
+                covered[idx] += 1;
+                for v in triangles[idx].vertices.iter() {
+                    assert!(node.bounds.contain(v));
                 }
             }
         }
+        assert!(covered.iter().all(|&count| count == 1));
+    }
 
-        return hit;
+    #[test]
+    fn node_layout() {
+        assert_eq!(32, std::mem::size_of::<BvhNode>());
+        let mut rng = 0x1234567u32;
+        let mut triangles = random_triangles(100, &mut rng);
+        let bvh = Bvh::create(&mut triangles);
+        for (idx, node) in bvh.nodes.iter().enumerate() {
+            if !node.is_leaf() {
+                // children are inside their parent, the left one right after it
+                for child in [&bvh.nodes[idx + 1], &bvh.nodes[node.right_child()]] {
+                    assert!(node.bounds.contain(&child.bounds.min()));
+                    assert!(node.bounds.contain(&child.bounds.max()));
+                }
+                assert!(node.right_child() > idx + 1);
+            }
+        }
+        assert!(Bvh::create(&mut [])
+            .intersect(
+                &Ray::new(&Vec3::zero(), &Vec3::new(0.0, 0.0, 1.0)),
+                0.0,
+                1.0,
+                HitType::Any,
+                &[]
+            )
+            .is_none());
     }
 
-    pub fn intersect(
-        &self,
-        ray: &Ray,
-        tmin: f32,
-        tmax: f32,
-        hit_type: HitType,
-        triangles: &[Triangle],
-    ) -> Option<Hit> {
-        let mut local_tmax = tmax;
-        self.intersect_impl(0, ray, tmin, &mut local_tmax, hit_type, triangles)
+    #[test]
+    fn parallel_build_is_deterministic() {
+        let mut rng = 0x1234567u32;
+        let triangles = random_triangles(8000, &mut rng);
+        let mut serial_triangles = triangles.clone();
+        let serial = Bvh::create_with_threshold(&mut serial_triangles, usize::MAX);
+        for _ in 0..2 {
+            let mut parallel_triangles = triangles.clone();
+            let parallel = Bvh::create_with_threshold(&mut parallel_triangles, 64);
+            assert_eq!(serial.nodes.len(), parallel.nodes.len());
+            for (a, b) in serial.nodes.iter().zip(parallel.nodes.iter()) {
+                assert_eq!((a.offset, a.count), (b.offset, b.count));
+                assert_eq!(
+                    (a.bounds.min(), a.bounds.max()),
+                    (b.bounds.min(), b.bounds.max())
+                );
+            }
+            for (a, b) in serial_triangles.iter().zip(parallel_triangles.iter()) {
+                assert_eq!(a.vertices, b.vertices);
+            }
+        }
     }
-}
 
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = 0x1234567u32;
+        let mut triangles = random_triangles(500, &mut rng);
+        let bvh = Bvh::create(&mut triangles);
+        for _ in 0..500 {
+            let origin = random_point(&mut rng, 20.0);
+            let target = random_point(&mut rng, 5.0);
+            let ray = Ray::new(&origin, &(target - origin).normalize());
+            let expected = brute_force(&ray, &triangles);
+            let hit = bvh.intersect(&ray, 0.0, 100.0, HitType::Closest, &triangles);
+            assert_eq!(expected.map(|hit| hit.t), hit.map(|hit| hit.t));
+            let any = bvh.intersect(&ray, 0.0, 100.0, HitType::Any, &triangles);
+            assert_eq!(expected.is_some(), any.is_some());
+        }
+    }
+
+    #[test]
+    fn moving_triangles() {
+        let mut rng = 0x1234567u32;
+        let mut triangles = random_triangles(500, &mut rng);
+        // moving and turning by up to a turn and a half around a pivot away from them
+        for triangle in triangles.iter_mut().step_by(3) {
+            let pivot = triangle.vertices[0] + random_point(&mut rng, 3.0);
+            triangle.motion = Some(Box::new(TriangleMotion {
+                vertices: [
+                    triangle.vertices[0] - pivot,
+                    triangle.vertices[1] - pivot,
+                    triangle.vertices[2] - pivot,
+                ],
+                normals: None,
+                start: Placement {
+                    translate: pivot,
+                    ..Placement::default()
+                },
+                end: Placement {
+                    translate: pivot + random_point(&mut rng, 4.0),
+                    rotate: random_point(&mut rng, 540.0),
+                    ..Placement::default()
+                },
+            }));
+        }
+        let bvh = Bvh::create(&mut triangles);
+        for _ in 0..500 {
+            let origin = random_point(&mut rng, 20.0);
+            let target = random_point(&mut rng, 5.0);
+            let ray = Ray::new(&origin, &(target - origin).normalize())
+                .with_time(random_float01(&mut rng));
+            let expected = brute_force(&ray, &triangles);
+            let hit = bvh.intersect(&ray, 0.0, 100.0, HitType::Closest, &triangles);
+            assert_eq!(expected.map(|hit| hit.t), hit.map(|hit| hit.t));
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let mut rng = 0x1234567u32;
+        let mut triangles = random_triangles(1000, &mut rng);
+        let bvh = Bvh::create(&mut triangles);
+        let stats = bvh.stats();
+        assert_eq!(bvh.nodes.len(), stats.node_count);
+        // a binary tree has one more leaf than internal nodes
+        assert_eq!(stats.node_count + 1, 2 * stats.leaf_count);
+        assert_eq!(
+            stats.leaf_count,
+            stats.depth_histogram.iter().sum::<usize>()
+        );
+        assert_eq!(1000.0 / stats.leaf_count as f32, stats.average_leaf_size);
+        assert!(stats.max_leaf_size <= MAX_LEAF_SIZE);
+        // at least the root traversal and one triangle, at most every triangle
+        assert!(stats.sah_cost > 1.0 && stats.sah_cost < 1000.0);
+        assert!(stats.build_time.is_some());
+        assert!(stats.to_string().contains("leaves per depth"));
+
+        let mut counts = TraversalCounts::default();
+        let ray = Ray::new(&Vec3::new(0.0, 0.0, -20.0), &Vec3::new(0.0, 0.0, 1.0));
+        let hit =
+            bvh.intersect_counted(&ray, 0.0, 100.0, HitType::Closest, &triangles, &mut counts);
+        let expected = bvh.intersect(&ray, 0.0, 100.0, HitType::Closest, &triangles);
+        assert_eq!(expected.map(|hit| hit.t), hit.map(|hit| hit.t));
+        assert!(counts.nodes > 0 && counts.triangles > 0);
+        assert!(counts.nodes <= stats.node_count && counts.triangles <= triangles.len());
+    }
+}
@@ -0,0 +1,573 @@
+//! Binary cache of a built `Bvh`, so that static meshes are not rebuilt at each launch.
+//!
+//! The file holds the flattened nodes and the triangle list in the order the tree was built
+//! for, and is keyed by a hash of the source OBJ. All values are little-endian:
+//!
+//! ```text
+//! magic "TMPTBVH\0", version u32, key u64, node count u32, triangle count u32,
+//! nodes: min [f32; 3], max [f32; 3], offset u32, count u32
+//! triangles: vertices [f32; 9], flags u8 (1: normals, 2: uvs, 4: motion, 8: motion normals),
+//!            normals [f32; 9] if any, uvs [f32; 6] if any, material u32,
+//!            if moving: vertices [f32; 9] and normals [f32; 9] if any in the space of the mesh,
+//!            start and end placements: translate [f32; 3], rotate [f32; 3], scale [f32; 3]
+//! FNV-1a hash of everything between the header and itself, u64
+//! ```
+
+use crate::aabb::*;
+use crate::bvh::*;
+use crate::transform::Placement;
+use crate::triangle::*;
+use crate::vec3::*;
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"TMPTBVH\0";
+/// Bumped whenever the format, or the way the tree is built, changes.
+const VERSION: u32 = 3;
+const HEADER_SIZE: usize = 8 + 4 + 8 + 4 + 4;
+const NODE_SIZE: usize = 6 * 4 + 2 * 4;
+const HAS_NORMALS: u8 = 1;
+const HAS_UVS: u8 = 2;
+const HAS_MOTION: u8 = 4;
+const HAS_MOTION_NORMALS: u8 = 8;
+
+/// 64-bit FNV-1a hash, fast enough to hash large meshes at load time.
+pub struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for Fnv1a {
+    fn default() -> Fnv1a {
+        Fnv1a::new()
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.data.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> std::io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < count {
+            return Err(invalid("truncated file"));
+        }
+        let bytes = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        return Ok(bytes);
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f32s<const N: usize>(&mut self) -> std::io::Result<[f32; N]> {
+        let mut values = [0f32; N];
+        for value in values.iter_mut() {
+            *value = f32::from_le_bytes(self.bytes(4)?.try_into().unwrap());
+        }
+        Ok(values)
+    }
+
+    fn vec3s<const N: usize>(&mut self) -> std::io::Result<[Vec3; N]> {
+        let mut values = [Vec3::zero(); N];
+        for value in values.iter_mut() {
+            *value = Vec3::from(self.f32s::<3>()?);
+        }
+        Ok(values)
+    }
+}
+
+/// Write `bvh` and the `triangles` it was built for to `filename`. The file is written next to
+/// its final location and then renamed, so that an interrupted save leaves no partial cache.
+pub fn save(filename: &Path, key: u64, bvh: &Bvh, triangles: &[Triangle]) -> std::io::Result<()> {
+    let mut writer = Writer { data: Vec::new() };
+    writer.data.extend_from_slice(MAGIC);
+    writer.u32(VERSION);
+    writer.u64(key);
+    writer.u32(bvh.nodes().len() as u32);
+    writer.u32(triangles.len() as u32);
+    for node in bvh.nodes() {
+        writer.f32s(&node.bounds().min().to_array());
+        writer.f32s(&node.bounds().max().to_array());
+        let (offset, count) = node.raw();
+        writer.u32(offset);
+        writer.u32(count);
+    }
+    for triangle in triangles {
+        for vertex in triangle.vertices.iter() {
+            writer.f32s(&vertex.to_array());
+        }
+        let mut flags = 0;
+        if triangle.normals.is_some() {
+            flags |= HAS_NORMALS;
+        }
+        if triangle.uvs.is_some() {
+            flags |= HAS_UVS;
+        }
+        if let Some(motion) = &triangle.motion {
+            flags |= HAS_MOTION;
+            if motion.normals.is_some() {
+                flags |= HAS_MOTION_NORMALS;
+            }
+        }
+        writer.data.push(flags);
+        if let Some(normals) = &triangle.normals {
+            for normal in normals.iter() {
+                writer.f32s(&normal.to_array());
+            }
+        }
+        if let Some(uvs) = &triangle.uvs {
+            for uv in uvs.iter() {
+                writer.f32s(uv);
+            }
+        }
+        writer.u32(triangle.material as u32);
+        if let Some(motion) = &triangle.motion {
+            for vertex in motion.vertices.iter() {
+                writer.f32s(&vertex.to_array());
+            }
+            if let Some(normals) = &motion.normals {
+                for normal in normals.iter() {
+                    writer.f32s(&normal.to_array());
+                }
+            }
+            for placement in [&motion.start, &motion.end].iter() {
+                writer.f32s(&placement.translate.to_array());
+                writer.f32s(&placement.rotate.to_array());
+                writer.f32s(&placement.scale.to_array());
+            }
+        }
+    }
+    let mut hasher = Fnv1a::new();
+    hasher.write(&writer.data[HEADER_SIZE..]);
+    writer.u64(hasher.finish());
+
+    let mut temp_name = filename.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp_name = PathBuf::from(temp_name);
+    std::fs::write(&temp_name, &writer.data)?;
+    return std::fs::rename(&temp_name, filename);
+}
+
+/// Check that `nodes` form a tree the traversal can walk: each node is reached once, less than
+/// `MAX_DEPTH` levels deep, and the leaves cover each of the `triangle_count` triangles once.
+fn check_tree(nodes: &[BvhNode], triangle_count: usize) -> std::io::Result<()> {
+    if nodes.is_empty() {
+        return Ok(());
+    }
+    let mut node_reached = vec![false; nodes.len()];
+    let mut triangle_covered = vec![false; triangle_count];
+    let mut stack = vec![(0, 0)];
+    while let Some((idx, depth)) = stack.pop() {
+        if depth >= MAX_DEPTH {
+            return Err(invalid("tree too deep"));
+        }
+        if std::mem::replace(&mut node_reached[idx], true) {
+            return Err(invalid("node reached twice"));
+        }
+        let node = &nodes[idx];
+        if node.is_leaf() {
+            for covered in &mut triangle_covered[node.triangles()] {
+                if std::mem::replace(covered, true) {
+                    return Err(invalid("triangle in several leaves"));
+                }
+            }
+        } else {
+            stack.push((idx + 1, depth + 1));
+            stack.push((node.right_child(), depth + 1));
+        }
+    }
+    if node_reached.contains(&false) {
+        return Err(invalid("node not in the tree"));
+    }
+    if triangle_covered.contains(&false) {
+        return Err(invalid("triangle not in the tree"));
+    }
+    return Ok(());
+}
+
+/// Read a cache written by `save` with the same `key`. Files from another version or source,
+/// corrupt or truncated files, and trees that do not match their triangles are rejected with
+/// an `InvalidData` error.
+pub fn load(filename: &Path, key: u64) -> std::io::Result<(Bvh, Vec<Triangle>)> {
+    let data = std::fs::read(filename)?;
+    let mut reader = Reader {
+        data: &data,
+        pos: 0,
+    };
+    if reader.bytes(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a BVH cache"));
+    }
+    if reader.u32()? != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    if reader.u64()? != key {
+        return Err(invalid("built from another source"));
+    }
+    let node_count = reader.u32()? as usize;
+    let triangle_count = reader.u32()? as usize;
+    if data.len() < HEADER_SIZE + node_count * NODE_SIZE + 8 {
+        return Err(invalid("truncated file"));
+    }
+    let mut hasher = Fnv1a::new();
+    hasher.write(&data[HEADER_SIZE..data.len() - 8]);
+    let checksum = u64::from_le_bytes(data[data.len() - 8..].try_into().unwrap());
+    if hasher.finish() != checksum {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let mut nodes = Vec::with_capacity(node_count);
+    for idx in 0..node_count {
+        let [min, max] = reader.vec3s::<2>()?;
+        let bounds = Aabb::empty().extend(&min).extend(&max);
+        let (offset, count) = (reader.u32()?, reader.u32()?);
+        let valid = if count > 0 {
+            offset as usize + count as usize <= triangle_count
+        } else {
+            offset as usize > idx + 1 && (offset as usize) < node_count
+        };
+        if !valid {
+            return Err(invalid("node out of range"));
+        }
+        nodes.push(BvhNode::from_raw(bounds, offset, count));
+    }
+    if (node_count == 0) != (triangle_count == 0) {
+        return Err(invalid("node out of range"));
+    }
+    check_tree(&nodes, triangle_count)?;
+
+    let mut triangles = Vec::with_capacity(triangle_count.min(data.len()));
+    for _ in 0..triangle_count {
+        let vertices = reader.vec3s::<3>()?;
+        let flags = reader.u8()?;
+        let normals = if flags & HAS_NORMALS != 0 {
+            Some(reader.vec3s::<3>()?)
+        } else {
+            None
+        };
+        let uvs = if flags & HAS_UVS != 0 {
+            let uvs = reader.f32s::<6>()?;
+            Some([[uvs[0], uvs[1]], [uvs[2], uvs[3]], [uvs[4], uvs[5]]])
+        } else {
+            None
+        };
+        let material = reader.u32()? as usize;
+        let motion = if flags & HAS_MOTION != 0 {
+            let vertices = reader.vec3s::<3>()?;
+            let normals = if flags & HAS_MOTION_NORMALS != 0 {
+                Some(reader.vec3s::<3>()?)
+            } else {
+                None
+            };
+            let [translate, rotate, scale] = reader.vec3s::<3>()?;
+            let start = Placement {
+                translate,
+                rotate,
+                scale,
+            };
+            let [translate, rotate, scale] = reader.vec3s::<3>()?;
+            let end = Placement {
+                translate,
+                rotate,
+                scale,
+            };
+            Some(Box::new(TriangleMotion {
+                vertices,
+                normals,
+                start,
+                end,
+            }))
+        } else {
+            None
+        };
+        triangles.push(Triangle {
+            vertices,
+            normals,
+            uvs,
+            material,
+            motion,
+        });
+    }
+    if reader.pos != data.len() - 8 {
+        return Err(invalid("unexpected trailing data"));
+    }
+    return Ok((Bvh::from_nodes(nodes), triangles));
+}
+
+/// Where a built tree is cached, and the key it is valid for.
+pub struct BvhCache {
+    pub path: PathBuf,
+    pub key: u64,
+}
+
+/// Whether `BvhCache::load_or_build` reused the cache or built the tree.
+pub enum CacheStatus {
+    Loaded,
+    /// The cache was missing or invalid, with the error it failed with. The new tree was saved,
+    /// unless saving failed too.
+    Built {
+        reason: Error,
+        saved: std::io::Result<()>,
+    },
+}
+
+impl BvhCache {
+    /// Cache of a tree built from the mesh `source`, stored next to it with a `.bvh` extension
+    /// appended, and keyed by the hash of its contents.
+    pub fn for_source(source: &Path) -> std::io::Result<BvhCache> {
+        let mut hasher = Fnv1a::new();
+        hasher.write(&std::fs::read(source)?);
+        let mut path = source.as_os_str().to_owned();
+        path.push(".bvh");
+        Ok(BvhCache {
+            path: PathBuf::from(path),
+            key: hasher.finish(),
+        })
+    }
+
+    /// Load the tree of `triangle_list`, replacing it by the cached list, or build it with
+    /// `Bvh::create` and save it. A cache whose triangle count or materials do not match is
+    /// rebuilt as well.
+    pub fn load_or_build(
+        &self,
+        triangle_list: &mut Vec<Triangle>,
+        material_count: usize,
+    ) -> (Bvh, CacheStatus) {
+        let reason = match load(&self.path, self.key) {
+            Ok((bvh, triangles)) => {
+                if triangles.len() != triangle_list.len() {
+                    invalid("triangle count mismatch")
+                } else if triangles
+                    .iter()
+                    .any(|triangle| triangle.material >= material_count)
+                {
+                    invalid("material out of range")
+                } else {
+                    *triangle_list = triangles;
+                    return (bvh, CacheStatus::Loaded);
+                }
+            }
+            Err(err) => err,
+        };
+        let bvh = Bvh::create(&mut triangle_list[..]);
+        let saved = save(&self.path, self.key, &bvh, triangle_list);
+        return (bvh, CacheStatus::Built { reason, saved });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangles() -> Vec<Triangle> {
+        let mut triangles: Vec<Triangle> = (0..20)
+            .map(|idx| {
+                let offset = Vec3::new(idx as f32, (idx % 3) as f32, 0.0);
+                Triangle::new(
+                    offset,
+                    offset + Vec3::new(1.0, 0.0, 0.0),
+                    offset + Vec3::new(0.0, 1.0, 0.0),
+                )
+            })
+            .collect();
+        triangles[3].normals = Some([Vec3::new(0.0, 0.0, 1.0); 3]);
+        triangles[4].uvs = Some([[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]]);
+        triangles[5].material = 1;
+        triangles[6].motion = Some(Box::new(TriangleMotion {
+            vertices: triangles[6].vertices,
+            normals: None,
+            start: Placement::default(),
+            end: Placement {
+                translate: Vec3::new(0.0, 0.0, 1.0),
+                ..Placement::default()
+            },
+        }));
+        triangles[7].normals = Some([Vec3::new(0.0, 0.0, 1.0); 3]);
+        triangles[7].motion = Some(Box::new(TriangleMotion {
+            vertices: [
+                Vec3::zero(),
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+            ],
+            normals: triangles[7].normals,
+            start: Placement {
+                translate: triangles[7].vertices[0],
+                ..Placement::default()
+            },
+            end: Placement {
+                translate: Vec3::new(7.0, 1.0, 1.0),
+                rotate: Vec3::new(90.0, 0.0, 0.0),
+                scale: Vec3::fill(2.0),
+            },
+        }));
+        triangles
+    }
+
+    #[test]
+    fn fnv1a() {
+        let mut hasher = Fnv1a::new();
+        assert_eq!(0xcbf29ce484222325, hasher.finish());
+        hasher.write(b"foobar");
+        assert_eq!(0x85944171f73967e8, hasher.finish());
+    }
+
+    #[test]
+    fn round_trip() {
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_round_trip.bvh");
+        let mut triangle_list = triangles();
+        let bvh = Bvh::create(&mut triangle_list);
+        save(&filename, 42, &bvh, &triangle_list).unwrap();
+        let loaded = load(&filename, 42);
+        let other_key = load(&filename, 43);
+        std::fs::remove_file(&filename).unwrap();
+
+        let (loaded_bvh, loaded_triangles) = loaded.unwrap();
+        assert_eq!(bvh.nodes().len(), loaded_bvh.nodes().len());
+        for (node, loaded_node) in bvh.nodes().iter().zip(loaded_bvh.nodes()) {
+            assert_eq!(node.raw(), loaded_node.raw());
+            assert_eq!(node.bounds().min(), loaded_node.bounds().min());
+            assert_eq!(node.bounds().max(), loaded_node.bounds().max());
+        }
+        for (triangle, loaded) in triangle_list.iter().zip(loaded_triangles.iter()) {
+            assert_eq!(triangle.vertices, loaded.vertices);
+            assert_eq!(triangle.normals, loaded.normals);
+            assert_eq!(triangle.uvs, loaded.uvs);
+            assert_eq!(triangle.material, loaded.material);
+            assert_eq!(
+                triangle.motion.as_ref().map(|motion| (
+                    motion.vertices,
+                    motion.normals,
+                    motion.start,
+                    motion.end
+                )),
+                loaded.motion.as_ref().map(|motion| (
+                    motion.vertices,
+                    motion.normals,
+                    motion.start,
+                    motion.end
+                ))
+            );
+        }
+        assert_eq!(ErrorKind::InvalidData, other_key.err().unwrap().kind());
+    }
+
+    #[test]
+    fn stale_or_corrupt() {
+        let source = std::env::temp_dir().join("toy_mesh_path_tracer_cache_source.obj");
+        std::fs::write(&source, "v 0 0 0\n").unwrap();
+        let cache = BvhCache::for_source(&source).unwrap();
+
+        let mut triangle_list = triangles();
+        let (_, status) = cache.load_or_build(&mut triangle_list, 2);
+        assert!(matches!(status, CacheStatus::Built { saved: Ok(()), .. }));
+        let mut cached_list = triangles();
+        let (_, status) = cache.load_or_build(&mut cached_list, 2);
+        assert!(matches!(status, CacheStatus::Loaded));
+        assert_eq!(triangle_list[7].vertices, cached_list[7].vertices);
+
+        // a flipped byte is caught by the checksum
+        let mut data = std::fs::read(&cache.path).unwrap();
+        data[HEADER_SIZE + 3] ^= 0x10;
+        std::fs::write(&cache.path, &data).unwrap();
+        let (_, status) = cache.load_or_build(&mut triangles(), 2);
+        assert!(matches!(status, CacheStatus::Built { .. }));
+        // truncated
+        let data = std::fs::read(&cache.path).unwrap();
+        std::fs::write(&cache.path, &data[..data.len() / 2]).unwrap();
+        let (_, status) = cache.load_or_build(&mut triangles(), 2);
+        assert!(matches!(status, CacheStatus::Built { .. }));
+        // materials removed since
+        let (_, status) = cache.load_or_build(&mut triangles(), 1);
+        assert!(matches!(status, CacheStatus::Built { .. }));
+
+        // the source changed
+        std::fs::write(&source, "v 1 0 0\n").unwrap();
+        let changed = BvhCache::for_source(&source).unwrap();
+        let loaded = load(&changed.path, changed.key);
+        std::fs::remove_file(&cache.path).unwrap();
+        std::fs::remove_file(&source).unwrap();
+        assert_ne!(cache.key, changed.key);
+        assert_eq!(ErrorKind::InvalidData, loaded.err().unwrap().kind());
+    }
+
+    #[test]
+    fn malformed_tree() {
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_malformed.bvh");
+        let bounds = Aabb::empty().extend(&Vec3::zero());
+        let internal = |right_child| BvhNode::from_raw(bounds, right_child, 0);
+        let leaf = |first_triangle| BvhNode::from_raw(bounds, first_triangle, 1);
+        let mut trees = vec![
+            // both leaves on the same triangle, the other one in none
+            vec![internal(2), leaf(0), leaf(0)],
+            // the right child of the root also reached from its left child
+            vec![internal(3), internal(3), leaf(0), leaf(1)],
+        ];
+        // a chain deeper than the traversal stack
+        let mut chain = Vec::new();
+        for idx in 0..MAX_DEPTH as u32 {
+            chain.push(internal(2 * idx + 2));
+            chain.push(leaf(idx));
+        }
+        chain.push(leaf(MAX_DEPTH as u32));
+        trees.push(chain);
+        for nodes in trees {
+            let triangle_count = nodes.iter().filter(|node| node.is_leaf()).count();
+            let triangle_list =
+                vec![Triangle::new(Vec3::zero(), Vec3::zero(), Vec3::zero()); triangle_count];
+            save(&filename, 42, &Bvh::from_nodes(nodes), &triangle_list).unwrap();
+            let loaded = load(&filename, 42);
+            assert_eq!(ErrorKind::InvalidData, loaded.err().unwrap().kind());
+        }
+        std::fs::remove_file(&filename).unwrap();
+    }
+}
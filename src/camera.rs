@@ -1,6 +1,67 @@
+use crate::random::random_float01;
 use crate::ray::Ray;
 use crate::vec3::*;
 
+use core::f32::consts::PI;
+
+/// Shape of the lens aperture, which gives its shape to the out of focus highlights.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ApertureShape {
+    /// Number of diaphragm blades making a regular polygon, 0 for a circular aperture.
+    pub blades: usize,
+    /// Rotation of the polygon, in degrees.
+    pub rotation: f32,
+    /// Height of the aperture divided by its width, above 1 for the oval highlights of
+    /// anamorphic lenses.
+    pub squeeze: f32,
+}
+
+impl Default for ApertureShape {
+    fn default() -> ApertureShape {
+        ApertureShape {
+            blades: 0,
+            rotation: 0.0,
+            squeeze: 1.0,
+        }
+    }
+}
+
+impl ApertureShape {
+    /// Uniform point of the aperture, whose width is 2.
+    fn sample(&self, state: &mut u32) -> (f32, f32) {
+        let (x, y) = if self.blades < 3 {
+            let disk = Vec3::rand_unit_2d(state);
+            (disk.x(), disk.y())
+        } else {
+            // uniform in one of the triangles joining the center to a side of the polygon
+            let side_angle = 2.0 * PI / self.blades as f32;
+            let side = ((random_float01(state) * self.blades as f32) as usize).min(self.blades - 1);
+            let angle = self.rotation.to_radians() + side as f32 * side_angle;
+            let (sin0, cos0) = angle.sin_cos();
+            let (sin1, cos1) = (angle + side_angle).sin_cos();
+            let su = random_float01(state).sqrt();
+            let b0 = su * (1.0 - random_float01(state));
+            let b1 = su - b0;
+            (b0 * cos0 + b1 * cos1, b0 * sin0 + b1 * sin1)
+        };
+        (x, y * self.squeeze)
+    }
+}
+
+/// How the camera maps the image to the rays leaving it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Projection {
+    /// Rays through a pinhole or a thin lens, covering the vertical field of view.
+    Perspective,
+    /// Parallel rays along the view direction, from a rectangle `height` units tall.
+    Orthographic { height: f32 },
+    /// Equidistant fisheye: the angle to the view direction grows linearly from the center of
+    /// the image, up to half of `fov` degrees on the circle inscribed in the image.
+    Fisheye { fov: f32 },
+    /// Latitude-longitude panorama of all the directions, centered on the view direction.
+    Equirectangular,
+}
+
 pub struct Camera {
     origin: Vec3,
     lower_left_corner: Vec3,
@@ -8,10 +69,20 @@ pub struct Camera {
     vertical: Vec3,
     u: Vec3,
     v: Vec3,
+    w: Vec3,
+    aspect: f32,
     lens_radius: f32,
+    pub aperture_shape: ApertureShape,
+    pub projection: Projection,
+    /// Times at which the shutter opens and closes, the rays being cast uniformly in between.
+    /// The scene moves from the time 0 to the time 1.
+    pub shutter_open: f32,
+    pub shutter_close: f32,
 }
 
 impl Camera {
+    /// Thin lens camera, of diameter `aperture`, sharp at the distance `focus_dist` along the
+    /// view direction. A null aperture makes a pinhole camera, sharp everywhere.
     pub fn look_at(
         look_from: &Vec3,
         look_at: &Vec3,
@@ -19,32 +90,235 @@ impl Camera {
         vfov: f32,
         aspect: f32,
         aperture: f32,
-        _focus_dist: f32,
+        focus_dist: f32,
     ) -> Camera {
         let lens_radius = aperture / 2.0;
         let theta = vfov * core::f32::consts::PI / 180.0;
         let half_height = (theta / 2.0).tan();
         let half_width = aspect * half_height;
         let w = (*look_from - *look_at).normalize();
-        let u = Vec3::cross(&up, &w).normalize();
+        let u = Vec3::cross(up, &w).normalize();
         let v = Vec3::cross(&w, &u);
+        // the image plane is the plane in focus
         Camera {
             origin: *look_from,
-            lower_left_corner: *look_from - u * half_width - v * half_height - w,
-            horizontal: u * 2.0 * half_width,
-            vertical: v * 2.0 * half_height,
-            u: u,
-            v: v,
-            lens_radius: lens_radius,
+            lower_left_corner: *look_from
+                - u * (half_width * focus_dist)
+                - v * (half_height * focus_dist)
+                - w * focus_dist,
+            horizontal: u * (2.0 * half_width * focus_dist),
+            vertical: v * (2.0 * half_height * focus_dist),
+            u,
+            v,
+            w,
+            aspect,
+            lens_radius,
+            aperture_shape: ApertureShape::default(),
+            projection: Projection::Perspective,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+
+    /// Ray through the point of the image at `(s, t)`, both in [0, 1] from the bottom left
+    /// corner, at a random time while the shutter is open. Returns `None` outside of the
+    /// circular image of the fisheye projection.
+    pub fn get_ray(&self, s: f32, t: f32, state: &mut u32) -> Option<Ray> {
+        let ray = self.projected_ray(s, t, state)?;
+        if self.shutter_close > self.shutter_open {
+            let time = self.shutter_open
+                + random_float01(state) * (self.shutter_close - self.shutter_open);
+            return Some(ray.with_time(time));
+        }
+        Some(ray.with_time(self.shutter_open))
+    }
+
+    fn projected_ray(&self, s: f32, t: f32, state: &mut u32) -> Option<Ray> {
+        match self.projection {
+            Projection::Perspective => Some(self.perspective_ray(s, t, state)),
+            Projection::Orthographic { height } => {
+                let offset =
+                    self.u * ((s - 0.5) * self.aspect * height) + self.v * ((t - 0.5) * height);
+                Some(Ray::new(&(self.origin + offset), &(self.w * -1.0)))
+            }
+            Projection::Fisheye { fov } => {
+                let x = (2.0 * s - 1.0) * self.aspect;
+                let y = 2.0 * t - 1.0;
+                let r = (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                let (sin_theta, cos_theta) = (r * fov.to_radians() / 2.0).sin_cos();
+                let (cos_phi, sin_phi) = if r > 0.0 { (x / r, y / r) } else { (1.0, 0.0) };
+                let dir = self.u * (sin_theta * cos_phi) + self.v * (sin_theta * sin_phi)
+                    - self.w * cos_theta;
+                Some(Ray::new(&self.origin, &dir.normalize()))
+            }
+            Projection::Equirectangular => {
+                let (sin_theta, cos_theta) = (PI * (1.0 - t)).sin_cos();
+                let (sin_phi, cos_phi) = (2.0 * PI * (s - 0.5)).sin_cos();
+                let dir = self.u * (sin_theta * sin_phi) + self.v * cos_theta
+                    - self.w * (sin_theta * cos_phi);
+                Some(Ray::new(&self.origin, &dir.normalize()))
+            }
         }
     }
 
-    pub fn get_ray(&self, s: f32, t: f32, state: &mut u32) -> Ray {
-        let rd = Vec3::rand_unit_2d(state) * self.lens_radius;
-        let offset = self.u * rd.x() + self.v * rd.y();
+    fn perspective_ray(&self, s: f32, t: f32, state: &mut u32) -> Ray {
+        let (lens_x, lens_y) = if self.lens_radius > 0.0 {
+            self.aperture_shape.sample(state)
+        } else {
+            (0.0, 0.0)
+        };
+        let offset = (self.u * lens_x + self.v * lens_y) * self.lens_radius;
         let ray_origin = self.origin + offset;
         let ray_normal =
             self.lower_left_corner + self.horizontal * s + self.vertical * t - self.origin - offset;
         Ray::new(&ray_origin, &ray_normal.normalize())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(aperture: f32, focus_dist: f32) -> Camera {
+        Camera::look_at(
+            &Vec3::new(0.0, 0.0, 5.0),
+            &Vec3::zero(),
+            &Vec3::new(0.0, 1.0, 0.0),
+            60.0,
+            2.0,
+            aperture,
+            focus_dist,
+        )
+    }
+
+    #[test]
+    fn focus_plane() {
+        let mut state = 0x12345678u32;
+        let pinhole = camera(0.0, 1.0);
+        let lens = camera(0.5, 3.0);
+        for (s, t) in [(0.5, 0.5), (0.1, 0.8), (1.0, 0.0)] {
+            let pinhole_ray = pinhole.get_ray(s, t, &mut state).unwrap();
+            // the point of the pinhole ray on the focus plane, at z = 2
+            let focus_t = 3.0 / -pinhole_ray.dir().z();
+            let in_focus = pinhole_ray.point_at(focus_t);
+            for _ in 0..10 {
+                let ray = lens.get_ray(s, t, &mut state).unwrap();
+                assert!((ray.origin() - Vec3::new(0.0, 0.0, 5.0)).length() <= 0.25 + 1e-5);
+                let ray_t = (ray.origin().z() - 2.0) / -ray.dir().z();
+                assert!((ray.point_at(ray_t) - in_focus).length() < 1e-4);
+            }
+        }
+    }
+
+    fn assert_close(expected: Vec3, actual: Vec3) {
+        assert!(
+            (expected - actual).length() < 1e-5,
+            "{:?} {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn projections() {
+        let mut state = 0x12345678u32;
+        let from = Vec3::new(0.0, 0.0, 5.0);
+        let forward = Vec3::new(0.0, 0.0, -1.0);
+
+        let mut ortho = camera(0.0, 5.0);
+        ortho.projection = Projection::Orthographic { height: 4.0 };
+        for (s, t, x, y) in [
+            (0.5, 0.5, 0.0, 0.0),
+            (0.0, 0.0, -4.0, -2.0),
+            (1.0, 0.75, 4.0, 1.0),
+        ] {
+            let ray = ortho.get_ray(s, t, &mut state).unwrap();
+            assert_close(from + Vec3::new(x, y, 0.0), ray.origin());
+            assert_close(forward, ray.dir());
+        }
+
+        let mut fisheye = camera(0.0, 5.0);
+        fisheye.projection = Projection::Fisheye { fov: 180.0 };
+        let center = fisheye.get_ray(0.5, 0.5, &mut state).unwrap();
+        assert_close(from, center.origin());
+        assert_close(forward, center.dir());
+        // the top of the image is 90 degrees away from the view direction
+        let top = fisheye.get_ray(0.5, 1.0, &mut state).unwrap();
+        assert_close(Vec3::new(0.0, 1.0, 0.0), top.dir());
+        // halfway to the right edge of the circle, at 45 degrees
+        let right = fisheye.get_ray(0.625, 0.5, &mut state).unwrap();
+        assert_close(Vec3::new(1.0, 0.0, -1.0).normalize(), right.dir());
+        // the corners are out of the circle, with the aspect of 2
+        assert!(fisheye.get_ray(0.0, 0.0, &mut state).is_none());
+        assert!(fisheye.get_ray(0.9, 0.5, &mut state).is_none());
+
+        let mut panorama = camera(0.0, 5.0);
+        panorama.projection = Projection::Equirectangular;
+        for (s, t, dir) in [
+            (0.5, 0.5, forward),
+            (0.75, 0.5, Vec3::new(1.0, 0.0, 0.0)),
+            (0.25, 0.5, Vec3::new(-1.0, 0.0, 0.0)),
+            (0.0, 0.5, Vec3::new(0.0, 0.0, 1.0)),
+            (0.5, 1.0, Vec3::new(0.0, 1.0, 0.0)),
+            (0.5, 0.0, Vec3::new(0.0, -1.0, 0.0)),
+            (0.5, 0.75, Vec3::new(0.0, 1.0, -1.0).normalize()),
+        ] {
+            let ray = panorama.get_ray(s, t, &mut state).unwrap();
+            assert_close(from, ray.origin());
+            assert_close(dir, ray.dir());
+        }
+    }
+
+    #[test]
+    fn shutter() {
+        let mut state = 0x12345678u32;
+        let mut camera = camera(0.0, 5.0);
+        assert_eq!(0.0, camera.get_ray(0.5, 0.5, &mut state).unwrap().time());
+        camera.shutter_open = 0.25;
+        camera.shutter_close = 0.75;
+        let (mut min_time, mut max_time) = (1.0f32, 0.0f32);
+        for _ in 0..1000 {
+            let time = camera.get_ray(0.5, 0.5, &mut state).unwrap().time();
+            assert!((0.25..=0.75).contains(&time));
+            min_time = min_time.min(time);
+            max_time = max_time.max(time);
+        }
+        assert!(min_time < 0.3 && max_time > 0.7);
+    }
+
+    #[test]
+    fn aperture_shapes() {
+        let mut state = 0x12345678u32;
+        let hexagon = ApertureShape {
+            blades: 6,
+            rotation: 0.0,
+            squeeze: 1.0,
+        };
+        let apothem = (PI / 6.0).cos();
+        let mut max_y: f32 = 0.0;
+        for _ in 0..1000 {
+            let (x, y) = hexagon.sample(&mut state);
+            // inside of the flat sides, at the top and bottom for this rotation
+            assert!(y.abs() <= apothem + 1e-5);
+            assert!((x * x + y * y).sqrt() <= 1.0 + 1e-5);
+            max_y = max_y.max(y.abs());
+        }
+        assert!(max_y > 0.8);
+
+        let anamorphic = ApertureShape {
+            squeeze: 2.0,
+            ..ApertureShape::default()
+        };
+        let (mut max_x, mut max_y): (f32, f32) = (0.0, 0.0);
+        for _ in 0..1000 {
+            let (x, y) = anamorphic.sample(&mut state);
+            assert!(x * x + y * y / 4.0 <= 1.0 + 1e-5);
+            max_x = max_x.max(x.abs());
+            max_y = max_y.max(y.abs());
+        }
+        assert!(max_y > 1.8 && max_x < 1.0);
+    }
+}
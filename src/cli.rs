@@ -0,0 +1,505 @@
+use std::fmt;
+use std::time::Duration;
+use toy_mesh_path_tracer::render::{AdaptiveSettings, ProgressiveSettings, RenderMode};
+use toy_mesh_path_tracer::scene_file::CameraParams;
+use toy_mesh_path_tracer::RenderSettings;
+
+pub const USAGE: &str = "\
+Usage: toy_mesh_path_tracer [OPTIONS] [INPUT]
+
+Arguments:
+  [INPUT]                 Scene description (.toml) or Wavefront OBJ mesh to render
+                          [default: data/suzanne.obj]
+
+Options (the render options override the ones of the scene description):
+  -o, --output <FILE>     Output PPM image [default: test.ppm]
+      --width <PIXELS>    Image width [default: 640]
+      --height <PIXELS>   Image height [default: 360]
+      --spp <COUNT>       Samples per pixel [default: 4]
+      --depth <COUNT>     Maximum number of bounces per path [default: 10]
+      --tile-size <PIXELS>
+                          Side of the square tiles rendered by each task [default: 8]
+      --threads <COUNT>   Number of worker threads [default: one per core]
+      --aperture <SIZE>   Diameter of the camera lens, 0 for a pinhole camera [default: 0]
+      --focus-distance <DISTANCE>
+                          Distance at which the camera lens is sharp [default: distance to the
+                          camera target]
+      --autofocus         Focus the camera lens on the surface seen at the center of the image
+      --progressive       Render one sample per pixel at a time, up to --spp, and write the
+                          image rendered so far as asked by the options below
+      --time-limit <SECONDS>
+                          Stop a progressive render after this long, even before reaching --spp
+      --preview-every <SECONDS>
+                          Write the image of a progressive render at this interval
+      --preview-passes <COUNT>
+                          Write the image of a progressive render every this many samples per
+                          pixel
+      --adaptive          Take more samples in the noisiest tiles, between --min-spp and
+                          --max-spp samples per pixel, instead of --spp everywhere
+      --min-spp <COUNT>   Samples per pixel of an adaptive render before measuring the noise
+                          [default: 4]
+      --max-spp <COUNT>   Most samples per pixel of an adaptive render [default: 256]
+      --error-threshold <ERROR>
+                          Relative standard error under which an adaptive render leaves a tile
+                          alone [default: 0.02]
+      --sample-map <FILE> Write the samples taken in each pixel by an adaptive render to a PPM
+                          image, from blue for none to red for --max-spp
+      --no-bvh-cache      Always build the BVH of OBJ inputs, instead of reusing the one cached
+                          next to the mesh as <INPUT>.bvh
+      --bvh-stats         Print the node count, depth histogram, SAH cost, ... of the BVH
+      --heatmap           Color the pixels by the number of BVH nodes and triangles visited by
+                          the camera rays in the 4-wide BVH used to render, from blue to red
+                          for 200 or more
+  -h, --help              Print this help
+";
+
+pub struct Options {
+    pub input: String,
+    pub output: String,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub spp: Option<usize>,
+    pub depth: Option<usize>,
+    pub tile_size: Option<usize>,
+    pub threads: Option<usize>,
+    pub aperture: Option<f32>,
+    pub focus_distance: Option<f32>,
+    pub autofocus: bool,
+    pub progressive: bool,
+    pub time_limit: Option<f32>,
+    pub preview_every: Option<f32>,
+    pub preview_passes: Option<usize>,
+    pub adaptive: bool,
+    pub min_spp: Option<usize>,
+    pub max_spp: Option<usize>,
+    pub error_threshold: Option<f32>,
+    pub sample_map: Option<String>,
+    pub bvh_cache: bool,
+    pub bvh_stats: bool,
+    pub heatmap: bool,
+    pub help: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArgError {
+    UnknownFlag(String),
+    MissingValue(String),
+    UnexpectedValue { flag: String, value: String },
+    InvalidValue { flag: String, value: String },
+    InvalidNumber { flag: String, value: String },
+    Conflict(String, String),
+    UnexpectedArgument(String),
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgError::UnknownFlag(flag) => write!(f, "unknown option '{}'", flag),
+            ArgError::MissingValue(flag) => write!(f, "option '{}' expects a value", flag),
+            ArgError::UnexpectedValue { flag, value } => write!(
+                f,
+                "option '{}' does not take a value, got '{}'",
+                flag, value
+            ),
+            ArgError::InvalidValue { flag, value } => write!(
+                f,
+                "invalid value '{}' for '{}': expected a positive integer",
+                value, flag
+            ),
+            ArgError::InvalidNumber { flag, value } => write!(
+                f,
+                "invalid value '{}' for '{}': expected a positive number",
+                value, flag
+            ),
+            ArgError::Conflict(first, second) => {
+                write!(f, "'{}' cannot be used with '{}'", first, second)
+            }
+            ArgError::UnexpectedArgument(arg) => write!(f, "unexpected argument '{}'", arg),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            input: String::from("data/suzanne.obj"),
+            output: String::from("test.ppm"),
+            width: None,
+            height: None,
+            spp: None,
+            depth: None,
+            tile_size: None,
+            threads: None,
+            aperture: None,
+            focus_distance: None,
+            autofocus: false,
+            progressive: false,
+            time_limit: None,
+            preview_every: None,
+            preview_passes: None,
+            adaptive: false,
+            min_spp: None,
+            max_spp: None,
+            error_threshold: None,
+            sample_map: None,
+            bvh_cache: true,
+            bvh_stats: false,
+            heatmap: false,
+            help: false,
+        }
+    }
+}
+
+fn parse_count(flag: &str, value: &str) -> Result<usize, ArgError> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(ArgError::InvalidValue {
+            flag: flag.to_string(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+/// Finite number, either positive or, when `allow_zero` is set, null.
+fn parse_number(flag: &str, value: &str, allow_zero: bool) -> Result<f32, ArgError> {
+    match value.parse::<f32>() {
+        Ok(number) if number.is_finite() && (number > 0.0 || (allow_zero && number == 0.0)) => {
+            Ok(number)
+        }
+        _ => Err(ArgError::InvalidNumber {
+            flag: flag.to_string(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+impl Options {
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Options, ArgError> {
+        let mut options = Options::default();
+        let mut input: Option<String> = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                if input.is_some() {
+                    return Err(ArgError::UnexpectedArgument(arg));
+                }
+                input = Some(arg);
+                continue;
+            }
+            // accept both "--flag value" and "--flag=value"
+            let (flag, inline_value) = match arg.find('=') {
+                Some(pos) if arg.starts_with("--") => {
+                    (arg[..pos].to_string(), Some(arg[pos + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let switch = match flag.as_str() {
+                "-h" | "--help" => Some(&mut options.help),
+                "--no-bvh-cache" => Some(&mut options.bvh_cache),
+                "--bvh-stats" => Some(&mut options.bvh_stats),
+                "--heatmap" => Some(&mut options.heatmap),
+                "--autofocus" => Some(&mut options.autofocus),
+                "--progressive" => Some(&mut options.progressive),
+                "--adaptive" => Some(&mut options.adaptive),
+                _ => None,
+            };
+            if let Some(switch) = switch {
+                if let Some(value) = inline_value {
+                    return Err(ArgError::UnexpectedValue { flag, value });
+                }
+                // --no-* flags turn an option off
+                *switch = !flag.starts_with("--no-");
+                continue;
+            }
+            let known = [
+                "-o",
+                "--output",
+                "--width",
+                "--height",
+                "--spp",
+                "--depth",
+                "--tile-size",
+                "--threads",
+                "--aperture",
+                "--focus-distance",
+                "--time-limit",
+                "--preview-every",
+                "--preview-passes",
+                "--min-spp",
+                "--max-spp",
+                "--error-threshold",
+                "--sample-map",
+            ];
+            if !known.contains(&flag.as_str()) {
+                return Err(ArgError::UnknownFlag(flag));
+            }
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(ArgError::MissingValue(flag)),
+            };
+            match flag.as_str() {
+                "-o" | "--output" => options.output = value,
+                "--width" => options.width = Some(parse_count(&flag, &value)?),
+                "--height" => options.height = Some(parse_count(&flag, &value)?),
+                "--spp" => options.spp = Some(parse_count(&flag, &value)?),
+                "--depth" => options.depth = Some(parse_count(&flag, &value)?),
+                "--tile-size" => options.tile_size = Some(parse_count(&flag, &value)?),
+                "--threads" => options.threads = Some(parse_count(&flag, &value)?),
+                "--aperture" => options.aperture = Some(parse_number(&flag, &value, true)?),
+                "--focus-distance" => {
+                    options.focus_distance = Some(parse_number(&flag, &value, false)?)
+                }
+                "--time-limit" => options.time_limit = Some(parse_number(&flag, &value, false)?),
+                "--preview-every" => {
+                    options.preview_every = Some(parse_number(&flag, &value, false)?)
+                }
+                "--preview-passes" => options.preview_passes = Some(parse_count(&flag, &value)?),
+                "--min-spp" => options.min_spp = Some(parse_count(&flag, &value)?),
+                "--max-spp" => options.max_spp = Some(parse_count(&flag, &value)?),
+                "--error-threshold" => {
+                    options.error_threshold = Some(parse_number(&flag, &value, true)?)
+                }
+                "--sample-map" => options.sample_map = Some(value),
+                _ => unreachable!(),
+            }
+        }
+        if let Some(input) = input {
+            options.input = input;
+        }
+        if options.progressive().is_some() && options.adaptive().is_some() {
+            return Err(ArgError::Conflict(
+                String::from("--progressive"),
+                String::from("--adaptive"),
+            ));
+        }
+        Ok(options)
+    }
+
+    pub fn apply(&self, settings: &mut RenderSettings) {
+        let overrides = [
+            (self.width, &mut settings.width),
+            (self.height, &mut settings.height),
+            (self.spp, &mut settings.spp),
+            (self.depth, &mut settings.max_depth),
+            (self.tile_size, &mut settings.tile_size),
+        ];
+        for (value, setting) in overrides {
+            if let Some(value) = value {
+                *setting = value;
+            }
+        }
+        if self.heatmap {
+            settings.mode = RenderMode::Heatmap;
+        }
+    }
+
+    pub fn apply_camera(&self, camera: &mut CameraParams) {
+        if let Some(aperture) = self.aperture {
+            camera.aperture = aperture;
+        }
+        if let Some(focus_distance) = self.focus_distance {
+            camera.focus_distance = focus_distance;
+            camera.autofocus = false;
+        }
+        if self.autofocus {
+            camera.autofocus = true;
+        }
+    }
+
+    /// Settings of the progressive render asked by `--progressive`, or implied by the options
+    /// which only apply to it.
+    pub fn progressive(&self) -> Option<ProgressiveSettings> {
+        let progressive = self.progressive
+            || self.time_limit.is_some()
+            || self.preview_every.is_some()
+            || self.preview_passes.is_some();
+        if !progressive {
+            return None;
+        }
+        Some(ProgressiveSettings {
+            time_limit: self.time_limit.map(Duration::from_secs_f32),
+            preview_interval: self.preview_every.map(Duration::from_secs_f32),
+            preview_passes: self.preview_passes,
+        })
+    }
+
+    /// Settings of the adaptive render asked by `--adaptive`, or implied by the options which
+    /// only apply to it.
+    pub fn adaptive(&self) -> Option<AdaptiveSettings> {
+        let adaptive = self.adaptive
+            || self.min_spp.is_some()
+            || self.max_spp.is_some()
+            || self.error_threshold.is_some()
+            || self.sample_map.is_some();
+        if !adaptive {
+            return None;
+        }
+        let defaults = AdaptiveSettings::default();
+        let min_spp = self.min_spp.unwrap_or(defaults.min_spp);
+        Some(AdaptiveSettings {
+            min_spp,
+            max_spp: self.max_spp.unwrap_or(defaults.max_spp).max(min_spp),
+            error_threshold: self.error_threshold.unwrap_or(defaults.error_threshold),
+        })
+    }
+
+    pub fn is_scene_description(&self) -> bool {
+        self.input.ends_with(".toml")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toy_mesh_path_tracer::Vec3;
+
+    fn parse(args: &[&str]) -> Result<Options, ArgError> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+        assert_eq!("data/suzanne.obj", options.input);
+        assert_eq!("test.ppm", options.output);
+        assert_eq!(None, options.threads);
+        assert!(options.bvh_cache);
+        assert!(!options.bvh_stats);
+        let mut settings = RenderSettings::default();
+        options.apply(&mut settings);
+        assert_eq!((640, 360), (settings.width, settings.height));
+        assert_eq!(4, settings.spp);
+        assert_eq!(10, settings.max_depth);
+        assert_eq!(8, settings.tile_size);
+        assert_eq!(RenderMode::Shaded, settings.mode);
+        assert!(options.progressive().is_none());
+        assert!(options.adaptive().is_none());
+    }
+
+    #[test]
+    fn flags() {
+        let options = parse(&[
+            "data/sponza.obj",
+            "-o",
+            "out.ppm",
+            "--width=1280",
+            "--height",
+            "720",
+            "--spp",
+            "16",
+            "--depth=4",
+            "--tile-size",
+            "13",
+            "--threads",
+            "2",
+            "--no-bvh-cache",
+            "--bvh-stats",
+            "--heatmap",
+            "--aperture=0.1",
+            "--focus-distance",
+            "2.5",
+        ])
+        .unwrap();
+        assert_eq!("data/sponza.obj", options.input);
+        assert_eq!("out.ppm", options.output);
+        assert_eq!(Some(2), options.threads);
+        assert!(!options.bvh_cache);
+        assert!(options.bvh_stats);
+        let mut settings = RenderSettings::default();
+        options.apply(&mut settings);
+        assert_eq!((1280, 720), (settings.width, settings.height));
+        assert_eq!(16, settings.spp);
+        assert_eq!(4, settings.max_depth);
+        assert_eq!(13, settings.tile_size);
+        assert_eq!(RenderMode::Heatmap, settings.mode);
+        let mut camera = CameraParams::new(&Vec3::new(0.0, 0.0, 5.0), &Vec3::zero());
+        camera.autofocus = true;
+        options.apply_camera(&mut camera);
+        assert_eq!(0.1, camera.aperture);
+        assert_eq!(2.5, camera.focus_distance);
+        assert!(!camera.autofocus);
+        assert!(parse(&["--autofocus"]).unwrap().autofocus);
+
+        let progressive = parse(&["--progressive"]).unwrap().progressive().unwrap();
+        assert_eq!(None, progressive.time_limit);
+        assert_eq!(None, progressive.preview_passes);
+        let progressive = parse(&["--time-limit=90", "--preview-every", "2.5"])
+            .unwrap()
+            .progressive()
+            .unwrap();
+        assert_eq!(Some(Duration::from_secs(90)), progressive.time_limit);
+        assert_eq!(
+            Some(Duration::from_millis(2500)),
+            progressive.preview_interval
+        );
+        let options = parse(&["--preview-passes", "8"]).unwrap();
+        assert_eq!(Some(8), options.progressive().unwrap().preview_passes);
+
+        let adaptive = parse(&["--adaptive"]).unwrap().adaptive().unwrap();
+        assert_eq!((4, 256), (adaptive.min_spp, adaptive.max_spp));
+        let options = parse(&[
+            "--min-spp=16",
+            "--max-spp",
+            "8",
+            "--error-threshold",
+            "0.1",
+            "--sample-map",
+            "samples.ppm",
+        ])
+        .unwrap();
+        let adaptive = options.adaptive().unwrap();
+        assert_eq!((16, 16), (adaptive.min_spp, adaptive.max_spp));
+        assert_eq!(0.1, adaptive.error_threshold);
+        assert_eq!(Some(String::from("samples.ppm")), options.sample_map);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Some(ArgError::UnknownFlag(String::from("--foo"))),
+            parse(&["--foo"]).err()
+        );
+        assert_eq!(
+            Some(ArgError::MissingValue(String::from("--spp"))),
+            parse(&["--spp"]).err()
+        );
+        assert_eq!(
+            Some(ArgError::UnexpectedValue {
+                flag: String::from("--no-bvh-cache"),
+                value: String::from("false"),
+            }),
+            parse(&["--no-bvh-cache=false"]).err()
+        );
+        assert!(parse(&["--heatmap="]).is_err());
+        assert_eq!(
+            Some(ArgError::InvalidValue {
+                flag: String::from("--width"),
+                value: String::from("0"),
+            }),
+            parse(&["--width", "0"]).err()
+        );
+        assert!(parse(&["--height=abc"]).is_err());
+        assert_eq!(
+            Some(ArgError::InvalidNumber {
+                flag: String::from("--focus-distance"),
+                value: String::from("0"),
+            }),
+            parse(&["--focus-distance", "0"]).err()
+        );
+        assert!(parse(&["--aperture", "0"]).is_ok());
+        assert!(parse(&["--aperture", "-1"]).is_err());
+        assert!(parse(&["--time-limit", "0"]).is_err());
+        assert!(parse(&["--preview-passes", "0"]).is_err());
+        assert_eq!(
+            Some(ArgError::Conflict(
+                String::from("--progressive"),
+                String::from("--adaptive"),
+            )),
+            parse(&["--time-limit", "10", "--max-spp", "64"]).err()
+        );
+        assert_eq!(
+            Some(ArgError::UnexpectedArgument(String::from("b.obj"))),
+            parse(&["a.obj", "b.obj"]).err()
+        );
+    }
+}
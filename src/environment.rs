@@ -0,0 +1,217 @@
+use crate::random::random_float01;
+use crate::texture::{Texture, WrapMode};
+use crate::transform::Transform;
+use crate::vec3::*;
+
+use core::f32::consts::PI;
+use std::path::Path;
+
+/// Piecewise constant distribution over `0..n`, sampled by inverting its cumulative function.
+struct Distribution1D {
+    /// Normalized cumulative weights, starting with 0 and ending with 1.
+    cdf: Vec<f32>,
+}
+
+impl Distribution1D {
+    /// Proportional to `weights`, or uniform when they are all null.
+    fn new(weights: &[f32]) -> Distribution1D {
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        let mut sum = 0f64;
+        cdf.push(0.0);
+        for weight in weights.iter() {
+            sum += weight.max(0.0) as f64;
+            cdf.push(sum);
+        }
+        let cdf = if sum > 0.0 {
+            cdf.iter().map(|value| (value / sum) as f32).collect()
+        } else {
+            let count = weights.len() as f32;
+            (0..=weights.len()).map(|idx| idx as f32 / count).collect()
+        };
+        Distribution1D { cdf }
+    }
+
+    fn len(&self) -> usize {
+        self.cdf.len() - 1
+    }
+
+    /// Index picked by `u` in [0, 1), with the position of `u` within its interval.
+    fn sample(&self, u: f32) -> (usize, f32) {
+        let idx = (self.cdf.partition_point(|&value| value <= u) - 1).min(self.len() - 1);
+        let pmf = self.pmf(idx);
+        let offset = if pmf > 0.0 {
+            ((u - self.cdf[idx]) / pmf).clamp(0.0, 1.0)
+        } else {
+            0.5
+        };
+        (idx, offset)
+    }
+
+    fn pmf(&self, idx: usize) -> f32 {
+        self.cdf[idx + 1] - self.cdf[idx]
+    }
+}
+
+/// Equirectangular image lighting the scene from infinitely far away. The center of the image
+/// is toward -z, and its top toward +y, before the rotation.
+///
+/// Directions are importance sampled proportionally to the luminance of the texels, weighted
+/// by the solid angle they cover: a row is picked with the marginal distribution, then a texel
+/// of the row with its conditional distribution.
+pub struct EnvironmentMap {
+    texture: Texture,
+    /// Multiplies the radiance of the image.
+    pub intensity: f32,
+    to_world: Transform,
+    to_map: Transform,
+    rows: Distribution1D,
+    columns: Vec<Distribution1D>,
+}
+
+impl EnvironmentMap {
+    /// `rotate` gives Euler angles in degrees, applied in the x, y, z order.
+    pub fn new(mut texture: Texture, rotate: &Vec3, intensity: f32) -> EnvironmentMap {
+        texture.wrap = WrapMode::Repeat;
+        let (width, height) = (texture.width(), texture.height());
+        let mut columns = Vec::with_capacity(height);
+        let mut row_weights = Vec::with_capacity(height);
+        for y in 0..height {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let weights: Vec<f32> = (0..width)
+                .map(|x| texture.texel(x as i64, y as i64).luminance().max(0.0))
+                .collect();
+            row_weights.push(weights.iter().sum::<f32>() * sin_theta);
+            columns.push(Distribution1D::new(&weights));
+        }
+        let to_world = Transform::rotate_xyz(rotate);
+        let to_map = Transform::rotate(2, -rotate.z())
+            .then(&Transform::rotate(1, -rotate.y()))
+            .then(&Transform::rotate(0, -rotate.x()));
+        EnvironmentMap {
+            texture,
+            intensity,
+            to_world,
+            to_map,
+            rows: Distribution1D::new(&row_weights),
+            columns,
+        }
+    }
+
+    /// Load a Radiance HDR or OpenEXR image, or any other format supported by `Texture`.
+    pub fn load(filename: &Path, rotate: &Vec3, intensity: f32) -> std::io::Result<EnvironmentMap> {
+        let texture = Texture::load(filename, true)?;
+        Ok(EnvironmentMap::new(texture, rotate, intensity))
+    }
+
+    /// Position in the image of `dir`, in [0, 1] from the left and from the top.
+    fn image_coords(&self, dir: &Vec3) -> (f32, f32) {
+        let dir = self.to_map.transform_vector(dir);
+        let u = 0.5 + dir.x().atan2(-dir.z()) / (2.0 * PI);
+        let v = dir.y().clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn direction(&self, u: f32, v: f32) -> Vec3 {
+        let (sin_theta, cos_theta) = (PI * v).sin_cos();
+        let (sin_phi, cos_phi) = (2.0 * PI * (u - 0.5)).sin_cos();
+        let dir = Vec3::new(sin_theta * sin_phi, cos_theta, -sin_theta * cos_phi);
+        self.to_world.transform_vector(&dir)
+    }
+
+    pub fn radiance(&self, dir: &Vec3) -> Vec3 {
+        let (u, v) = self.image_coords(dir);
+        // keep away from the poles, where the bilinear filtering would wrap vertically
+        let half_texel = 0.5 / self.texture.height() as f32;
+        let v = v.clamp(half_texel, 1.0 - half_texel);
+        self.texture.sample([u, 1.0 - v]) * self.intensity
+    }
+
+    /// Density of the texel at `(x, y)`, per unit solid angle of the directions it covers at
+    /// the polar angle of sine `sin_theta`.
+    fn texel_pdf(&self, x: usize, y: usize, sin_theta: f32) -> f32 {
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let texel_count = (self.texture.width() * self.texture.height()) as f32;
+        let pmf = self.rows.pmf(y) * self.columns[y].pmf(x);
+        pmf * texel_count / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Direction sampled proportionally to the radiance, with its probability density per unit
+    /// solid angle.
+    pub fn sample(&self, rng_state: &mut u32) -> (Vec3, f32) {
+        let (y, offset_y) = self.rows.sample(random_float01(rng_state));
+        let (x, offset_x) = self.columns[y].sample(random_float01(rng_state));
+        let u = (x as f32 + offset_x) / self.texture.width() as f32;
+        let v = (y as f32 + offset_y) / self.texture.height() as f32;
+        let pdf = self.texel_pdf(x, y, (PI * v).sin());
+        (self.direction(u, v), pdf)
+    }
+
+    /// Probability density of `sample` returning `dir`.
+    pub fn pdf(&self, dir: &Vec3) -> f32 {
+        let (u, v) = self.image_coords(dir);
+        let (width, height) = (self.texture.width(), self.texture.height());
+        let x = ((u * width as f32) as usize).min(width - 1);
+        let y = ((v * height as f32) as usize).min(height - 1);
+        self.texel_pdf(x, y, (PI * v).sin())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dark map with a single bright texel.
+    fn spot_map(rotate: &Vec3) -> EnvironmentMap {
+        let (width, height) = (16, 8);
+        let mut texels = vec![Vec3::fill(0.01); width * height];
+        texels[5 + 2 * width] = Vec3::fill(100.0);
+        EnvironmentMap::new(Texture::new(width, height, texels), rotate, 2.0)
+    }
+
+    #[test]
+    fn directions() {
+        let map = spot_map(&Vec3::zero());
+        let center = map.direction(0.5, 0.5);
+        assert!((center - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-5);
+        assert!((map.direction(0.5, 0.0) - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-5);
+        let (u, v) = map.image_coords(&map.direction(0.3, 0.7));
+        assert!((u - 0.3).abs() < 1e-5 && (v - 0.7).abs() < 1e-5);
+
+        let rotated = spot_map(&Vec3::new(0.0, 90.0, 0.0));
+        let dir = Vec3::new(0.3, 0.2, -0.9).normalize();
+        let turned = Transform::rotate(1, 90.0).transform_vector(&dir);
+        assert!((map.radiance(&dir) - rotated.radiance(&turned)).length() < 1e-3);
+        assert!((map.pdf(&dir) - rotated.pdf(&turned)).abs() < 1e-4 * map.pdf(&dir));
+    }
+
+    #[test]
+    fn importance_sampling() {
+        let map = spot_map(&Vec3::new(10.0, 30.0, 0.0));
+        let mut rng_state = 0x12345678u32;
+        let mut bright = 0;
+        for _ in 0..1000 {
+            let (dir, pdf) = map.sample(&mut rng_state);
+            assert!((dir.length() - 1.0).abs() < 1e-4);
+            assert!((map.pdf(&dir) - pdf).abs() < 1e-2 * pdf);
+            if map.radiance(&dir).x() > 10.0 {
+                bright += 1;
+            }
+        }
+        assert!(bright > 900);
+
+        // the density integrates to 1 over the sphere
+        let (columns, rows) = (160, 80);
+        let mut integral = 0.0;
+        for y in 0..rows {
+            let v = (y as f32 + 0.5) / rows as f32;
+            for x in 0..columns {
+                let u = (x as f32 + 0.5) / columns as f32;
+                let solid_angle = 2.0 * PI * PI * (PI * v).sin() / (columns * rows) as f32;
+                integral += map.pdf(&map.direction(u, v)) * solid_angle;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+    }
+}
@@ -1,7 +1,21 @@
 use crate::vec3::Vec3;
 
+#[derive(Clone, Copy)]
 pub struct Hit {
     pub pos: Vec3,
+    /// Normal of the triangle plane.
     pub normal: Vec3,
+    /// Interpolated vertex normal, equal to `normal` for flat shaded triangles.
+    pub shading_normal: Vec3,
+    /// Direction of increasing `u` on the surface, used to orient normal maps. Normalized and
+    /// perpendicular to `normal`.
+    pub tangent: Vec3,
+    /// Weights of the three vertices of the triangle.
+    pub barycentric: [f32; 3],
+    /// Interpolated texture coordinates, or the barycentric coordinates of the second and third
+    /// vertices when the triangle has none.
+    pub uv: [f32; 2],
     pub t: f32,
+    /// Index of the material in the scene.
+    pub material: usize,
 }
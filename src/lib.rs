@@ -0,0 +1,31 @@
+#![allow(clippy::needless_return)]
+
+extern crate rayon;
+
+pub mod aabb;
+pub mod bvh;
+pub mod bvh_cache;
+pub mod camera;
+pub mod environment;
+pub mod hit;
+pub mod light;
+pub mod material;
+pub mod mtl_loader;
+pub mod obj_loader;
+pub mod ppm_writer;
+pub mod random;
+pub mod ray;
+pub mod render;
+pub mod scene;
+pub mod scene_file;
+pub mod texture;
+pub mod transform;
+pub mod triangle;
+pub mod vec3;
+pub mod wide_bvh;
+
+pub use bvh::Bvh;
+pub use camera::Camera;
+pub use render::{render, Image, RenderSettings};
+pub use scene::Scene;
+pub use vec3::Vec3;
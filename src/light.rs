@@ -0,0 +1,353 @@
+use crate::material::{around, Material};
+use crate::random::random_float01;
+use crate::triangle::{Triangle, TriangleMotion};
+use crate::vec3::*;
+
+/// Light reduced to a point, or coming from a small cone of directions, which the paths never
+/// hit and that are only found with shadow rays.
+///
+/// Their colors are their irradiance divided by pi, so that a white light lights a white
+/// lambertian surface facing it in white, at a distance of 1 for the point and spot lights.
+pub enum Light {
+    /// Light coming from infinitely far away, like the sun.
+    Directional {
+        /// Normalized direction toward the light.
+        direction: Vec3,
+        color: Vec3,
+        /// Half of the angle the light covers, in radians, 0 for sharp shadows.
+        angular_radius: f32,
+    },
+    /// Light emitted uniformly in all directions.
+    Point { position: Vec3, color: Vec3 },
+    /// Point light restricted to a cone, fading out from the inner to the outer angle.
+    Spot {
+        position: Vec3,
+        /// Normalized axis of the cone.
+        direction: Vec3,
+        color: Vec3,
+        /// Cosines of the inner and outer half angles of the cone.
+        cos_inner: f32,
+        cos_outer: f32,
+    },
+}
+
+/// Light reaching a point from one of the `Light`s.
+pub struct Illumination {
+    /// Normalized direction toward the light.
+    pub direction: Vec3,
+    /// Distance to the light, infinite for the directional lights.
+    pub distance: f32,
+    /// Irradiance divided by pi, as taken by `Material::shade`.
+    pub color: Vec3,
+}
+
+impl Light {
+    pub fn directional(direction: &Vec3, color: &Vec3, angular_radius: f32) -> Light {
+        Light::Directional {
+            direction: direction.normalize(),
+            color: *color,
+            angular_radius,
+        }
+    }
+
+    pub fn point(position: &Vec3, color: &Vec3) -> Light {
+        Light::Point {
+            position: *position,
+            color: *color,
+        }
+    }
+
+    /// `inner_angle` and `outer_angle` are the half angles of the cone, in radians.
+    pub fn spot(
+        position: &Vec3,
+        direction: &Vec3,
+        color: &Vec3,
+        inner_angle: f32,
+        outer_angle: f32,
+    ) -> Light {
+        Light::Spot {
+            position: *position,
+            direction: direction.normalize(),
+            color: *color,
+            cos_inner: inner_angle.min(outer_angle).cos(),
+            cos_outer: outer_angle.cos(),
+        }
+    }
+
+    /// Light received at `pos`, sampling a direction of the cone of the soft directional
+    /// lights. Returns `None` when `pos` is out of the cone of a spot light.
+    pub fn illuminate(&self, pos: &Vec3, rng_state: &mut u32) -> Option<Illumination> {
+        match self {
+            Light::Directional {
+                direction,
+                color,
+                angular_radius,
+            } => {
+                let direction = if *angular_radius > 0.0 {
+                    // uniform in the cone
+                    let cos_max = angular_radius.cos();
+                    let cos_theta = 1.0 - random_float01(rng_state) * (1.0 - cos_max);
+                    around(direction, cos_theta, rng_state).normalize()
+                } else {
+                    *direction
+                };
+                Some(Illumination {
+                    direction,
+                    distance: f32::INFINITY,
+                    color: *color,
+                })
+            }
+            Light::Point { position, color } => {
+                let (direction, distance) = toward(pos, position)?;
+                Some(Illumination {
+                    direction,
+                    distance,
+                    color: *color * (1.0 / (distance * distance)),
+                })
+            }
+            Light::Spot {
+                position,
+                direction: axis,
+                color,
+                cos_inner,
+                cos_outer,
+            } => {
+                let (direction, distance) = toward(pos, position)?;
+                let cos_theta = -Vec3::dot(&direction, axis);
+                if cos_theta <= *cos_outer {
+                    return None;
+                }
+                let falloff = if cos_theta >= *cos_inner {
+                    1.0
+                } else {
+                    let t = (cos_theta - cos_outer) / (cos_inner - cos_outer);
+                    t * t * (3.0 - 2.0 * t)
+                };
+                Some(Illumination {
+                    direction,
+                    distance,
+                    color: *color * (falloff / (distance * distance)),
+                })
+            }
+        }
+    }
+}
+
+/// Normalized direction and distance from `from` to `to`, unless they are at the same place.
+fn toward(from: &Vec3, to: &Vec3) -> Option<(Vec3, f32)> {
+    let offset = *to - *from;
+    let distance = offset.length();
+    if distance > 0.0 {
+        Some((offset * (1.0 / distance), distance))
+    } else {
+        None
+    }
+}
+
+/// Emissive triangle of the scene.
+pub struct AreaLight {
+    pub vertices: [Vec3; 3],
+    /// Motion of the moving triangles.
+    pub motion: Option<Box<TriangleMotion>>,
+    /// Radiance emitted on both sides.
+    pub radiance: Vec3,
+    pub area: f32,
+}
+
+/// Point sampled on an area light, as seen from the shaded point.
+pub struct LightSample {
+    /// Normalized direction toward the sampled point.
+    pub direction: Vec3,
+    pub distance: f32,
+    pub radiance: Vec3,
+    /// Probability density of the sample, per unit solid angle around the shaded point.
+    pub pdf: f32,
+}
+
+/// Emissive triangles of a scene, one of them being picked for each explicit light sample with
+/// a probability proportional to its power.
+#[derive(Default)]
+pub struct AreaLights {
+    pub lights: Vec<AreaLight>,
+    /// Power of the lights accumulated in order, used to pick them.
+    cumulated_power: Vec<f32>,
+}
+
+impl AreaLights {
+    /// Lights made of the triangles whose material emits some light.
+    pub fn from_triangles(triangles: &[Triangle], materials: &[Box<dyn Material>]) -> AreaLights {
+        let mut area_lights = AreaLights::default();
+        let mut total_power = 0.0;
+        for triangle in triangles.iter() {
+            let radiance = materials[triangle.material].emission();
+            let v = &triangle.vertices;
+            let area = Vec3::cross(&(v[1] - v[0]), &(v[2] - v[0])).length() * 0.5;
+            let power = radiance.luminance() * area;
+            if power.is_nan() || power <= 0.0 {
+                continue;
+            }
+            total_power += power;
+            area_lights.lights.push(AreaLight {
+                vertices: *v,
+                motion: triangle.motion.clone(),
+                radiance,
+                area,
+            });
+            area_lights.cumulated_power.push(total_power);
+        }
+        return area_lights;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    fn total_power(&self) -> f32 {
+        self.cumulated_power.last().copied().unwrap_or(0.0)
+    }
+
+    /// Pick a light and a point uniformly on it, where it is at `time`, as seen from `pos`.
+    /// Returns `None` when there is no light, or when the sampled point is seen edge-on.
+    ///
+    /// The moving lights are picked, and their density computed, with their area at the time 0,
+    /// which is exact as long as their motion does not scale them.
+    pub fn sample(&self, pos: &Vec3, time: f32, rng_state: &mut u32) -> Option<LightSample> {
+        if self.is_empty() {
+            return None;
+        }
+        let target = random_float01(rng_state) * self.total_power();
+        let idx = self
+            .cumulated_power
+            .partition_point(|&power| power <= target)
+            .min(self.lights.len() - 1);
+        let light = &self.lights[idx];
+
+        // uniform point on the triangle
+        let su = random_float01(rng_state).sqrt();
+        let b1 = random_float01(rng_state) * su;
+        let b0 = 1.0 - su;
+        let v = match &light.motion {
+            Some(motion) => motion.vertices_at(time),
+            None => light.vertices,
+        };
+        let point = v[0] * b0 + v[1] * b1 + v[2] * (1.0 - b0 - b1);
+
+        let to_light = point - *pos;
+        let distance_sq = to_light.length_sq();
+        let distance = distance_sq.sqrt();
+        if distance.is_nan() || distance <= 0.0 {
+            return None;
+        }
+        let direction = to_light * (1.0 / distance);
+        let normal = Vec3::cross(&(v[1] - v[0]), &(v[2] - v[0])).normalize();
+        let cos_light = Vec3::dot(&normal, &direction).abs();
+        if cos_light < 1e-6 {
+            return None;
+        }
+        // area density converted to solid angle
+        let pdf = self.pdf_area(&light.radiance) * distance_sq / cos_light;
+        Some(LightSample {
+            direction,
+            distance,
+            radiance: light.radiance,
+            pdf,
+        })
+    }
+
+    /// Probability density, per unit area, of sampling a point emitting `radiance`. As the
+    /// lights are picked by power, it only depends on the radiance of the point.
+    pub fn pdf_area(&self, radiance: &Vec3) -> f32 {
+        radiance.luminance() / self.total_power()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Emissive, Lambertian};
+
+    #[test]
+    fn light_types() {
+        let mut rng_state = 0x12345678u32;
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let sun = Light::directional(&Vec3::new(0.0, 2.0, 0.0), &Vec3::fill(0.5), 0.0);
+        let lit = sun.illuminate(&Vec3::zero(), &mut rng_state).unwrap();
+        assert_eq!(up, lit.direction);
+        assert_eq!(Vec3::fill(0.5), lit.color);
+        assert!(lit.distance.is_infinite());
+
+        let soft_sun = Light::directional(&up, &Vec3::fill(0.5), 0.1);
+        for _ in 0..100 {
+            let lit = soft_sun.illuminate(&Vec3::zero(), &mut rng_state).unwrap();
+            assert!(lit.direction.y() >= 0.1f32.cos() - 1e-5);
+        }
+
+        let point = Light::point(&Vec3::new(0.0, 2.0, 0.0), &Vec3::fill(1.0));
+        let lit = point.illuminate(&Vec3::zero(), &mut rng_state).unwrap();
+        assert_eq!(up, lit.direction);
+        assert_eq!(2.0, lit.distance);
+        assert_eq!(Vec3::fill(0.25), lit.color);
+
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let spot = Light::spot(
+            &Vec3::new(0.0, 1.0, 0.0),
+            &down,
+            &Vec3::fill(1.0),
+            30f32.to_radians(),
+            45f32.to_radians(),
+        );
+        let lit = spot.illuminate(&Vec3::zero(), &mut rng_state).unwrap();
+        assert_eq!(Vec3::fill(1.0), lit.color);
+        // 40 degrees off the axis, in the falloff
+        let pos = Vec3::new(40f32.to_radians().tan(), 0.0, 0.0);
+        let lit = spot.illuminate(&pos, &mut rng_state).unwrap();
+        assert!(lit.color.x() > 0.0 && lit.color.x() < 0.5 / lit.distance.powi(2));
+        assert!(spot
+            .illuminate(&Vec3::new(2.0, 0.0, 0.0), &mut rng_state)
+            .is_none());
+    }
+
+    #[test]
+    fn sample_by_power() {
+        let materials: Vec<Box<dyn Material>> = vec![
+            Box::new(Lambertian::new(&Vec3::fill(0.5))),
+            Box::new(Emissive {
+                color: Vec3::fill(1.0),
+                intensity: 1.0,
+            }),
+            Box::new(Emissive {
+                color: Vec3::fill(1.0),
+                intensity: 3.0,
+            }),
+        ];
+        let quad = |y: f32, material: usize| {
+            let mut triangle = Triangle::new(
+                Vec3::new(0.0, y, 0.0),
+                Vec3::new(1.0, y, 0.0),
+                Vec3::new(0.0, y, 1.0),
+            );
+            triangle.material = material;
+            triangle
+        };
+        let triangles = vec![quad(1.0, 1), quad(0.0, 0), quad(-1.0, 2)];
+        let lights = AreaLights::from_triangles(&triangles, &materials);
+        assert_eq!(2, lights.lights.len());
+        assert_eq!(0.5, lights.lights[0].area);
+
+        let mut rng_state = 0x12345678u32;
+        let mut above = 0;
+        for _ in 0..1000 {
+            let sample = lights.sample(&Vec3::zero(), 0.0, &mut rng_state).unwrap();
+            if sample.direction.y() > 0.0 {
+                above += 1;
+                assert_eq!(Vec3::fill(1.0), sample.radiance);
+            }
+            let cos_light = sample.direction.y().abs();
+            let expected =
+                sample.distance * sample.distance / cos_light * sample.radiance.luminance() / 2.0;
+            assert!((sample.pdf - expected).abs() < 1e-3 * expected);
+        }
+        // the light below is three times as bright
+        assert!(above > 200 && above < 300);
+    }
+}
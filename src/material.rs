@@ -0,0 +1,462 @@
+use crate::hit::Hit;
+use crate::random::*;
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::vec3::*;
+use core::f32::consts::PI;
+use std::sync::Arc;
+
+/// Continuation of a path after bouncing on a surface.
+pub struct Scatter {
+    pub ray: Ray,
+    /// Color filter applied to the light coming back along `ray`: the BSDF times the cosine,
+    /// divided by `pdf`.
+    pub attenuation: Vec3,
+    /// Probability density of the direction of `ray`, per unit solid angle, or `None` for a
+    /// specular bounce.
+    pub pdf: Option<f32>,
+}
+
+pub trait Material: Send + Sync {
+    /// Sample the direction the path continues in, or `None` when the light is absorbed.
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng_state: &mut u32) -> Option<Scatter>;
+
+    /// BSDF times the cosine with the normal, for the light coming from `light_dir` and
+    /// reflected back along `ray`. Null for the specular surfaces.
+    fn eval(&self, _ray: &Ray, _hit: &Hit, _light_dir: &Vec3) -> Vec3 {
+        Vec3::zero()
+    }
+
+    /// Probability density of `scatter` sampling `dir`, per unit solid angle.
+    fn pdf(&self, _ray: &Ray, _hit: &Hit, _dir: &Vec3) -> f32 {
+        0.0
+    }
+
+    /// Light reflected back along `ray` when the surface is lit by a directional light of color
+    /// `light_color` coming from `light_dir`. The color of these lights is their irradiance
+    /// divided by pi, so that a white light on a white lambertian surface facing it is white.
+    fn shade(&self, ray: &Ray, hit: &Hit, light_dir: &Vec3, light_color: &Vec3) -> Vec3 {
+        self.eval(ray, hit, light_dir) * *light_color * PI
+    }
+
+    /// Whether `eval` and `pdf` describe how the surface scatters light, in which case the
+    /// lights are sampled explicitly. False for the specular surfaces, and the ones that do not
+    /// scatter.
+    fn receives_lights(&self) -> bool {
+        false
+    }
+
+    /// Light emitted by the surface toward the origin of `ray`.
+    fn emitted(&self, _ray: &Ray, _hit: &Hit) -> Vec3 {
+        self.emission()
+    }
+
+    /// Radiance emitted uniformly by the surface, which is sampled as an area light when not
+    /// null.
+    fn emission(&self) -> Vec3 {
+        Vec3::zero()
+    }
+}
+
+/// Two unit vectors forming an orthonormal basis with the unit vector `normal`, following
+/// Duff et al. "Building an Orthonormal Basis, Revisited".
+fn orthonormal_basis(normal: &Vec3) -> (Vec3, Vec3) {
+    let sign = 1f32.copysign(normal.z());
+    let a = -1.0 / (sign + normal.z());
+    let b = normal.x() * normal.y() * a;
+    (
+        Vec3::new(
+            1.0 + sign * normal.x() * normal.x() * a,
+            sign * b,
+            -sign * normal.x(),
+        ),
+        Vec3::new(b, sign + normal.y() * normal.y() * a, -normal.y()),
+    )
+}
+
+/// Direction around `axis` making an angle whose cosine is `cos_theta`, at a random azimuth.
+pub(crate) fn around(axis: &Vec3, cos_theta: f32, rng_state: &mut u32) -> Vec3 {
+    let (tangent, bitangent) = orthonormal_basis(axis);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * random_float01(rng_state);
+    (tangent * phi.cos() + bitangent * phi.sin()) * sin_theta + *axis * cos_theta
+}
+
+/// Exponent of the Phong lobe matching `roughness`, as for the Beckmann distribution.
+fn phong_exponent(roughness: f32) -> f32 {
+    let roughness = roughness.max(0.01);
+    2.0 / (roughness * roughness) - 2.0
+}
+
+/// Density of the directions making an angle of cosine `cos_alpha` with the axis of a Phong
+/// lobe of exponent `exponent`.
+fn phong_pdf(cos_alpha: f32, exponent: f32) -> f32 {
+    if cos_alpha <= 0.0 {
+        return 0.0;
+    }
+    (exponent + 1.0) / (2.0 * PI) * cos_alpha.powf(exponent)
+}
+
+fn reflect(dir: &Vec3, normal: &Vec3) -> Vec3 {
+    *dir - *normal * (2.0 * Vec3::dot(dir, normal))
+}
+
+/// Refract `dir` through a surface of normal `normal` facing `dir`, `eta` being the ratio of the
+/// indices of refraction. Returns `None` on total internal reflection.
+fn refract(dir: &Vec3, normal: &Vec3, eta: f32) -> Option<Vec3> {
+    let cos_i = -Vec3::dot(dir, normal);
+    let sin2_t = eta * eta * (1.0 - cos_i * cos_i);
+    if sin2_t > 1.0 {
+        return None;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    return Some(*dir * eta + *normal * (eta * cos_i - cos_t));
+}
+
+fn schlick(cos_theta: f32, ior: f32) -> f32 {
+    let r0 = (1.0 - ior) / (1.0 + ior);
+    let r0 = r0 * r0;
+    r0 + (1.0 - r0) * (1.0 - cos_theta).powi(5)
+}
+
+/// Shading normal of the hit, on the same side of the surface as the origin of the ray.
+fn facing_normal(ray: &Ray, hit: &Hit) -> Vec3 {
+    let geometric = hit.normal * -Vec3::dot(&hit.normal, &ray.dir()).signum();
+    hit.shading_normal * Vec3::dot(&hit.shading_normal, &geometric).signum()
+}
+
+/// `value` modulated by `map` sampled at the texture coordinates of the hit.
+fn textured(value: &Vec3, map: &Option<Arc<Texture>>, hit: &Hit) -> Vec3 {
+    match map {
+        Some(texture) => *value * texture.sample(hit.uv),
+        None => *value,
+    }
+}
+
+/// Hit with its shading normal bent by a tangent-space normal map, the red, green and blue
+/// channels going along the tangent, bitangent and normal.
+fn normal_mapped(hit: &Hit, normal_map: &Option<Arc<Texture>>) -> Hit {
+    let texture = match normal_map {
+        Some(texture) => texture,
+        None => return *hit,
+    };
+    let normal = hit.shading_normal;
+    let tangent = hit.tangent - normal * Vec3::dot(&hit.tangent, &normal);
+    if tangent.length_sq() < 1e-12 {
+        return *hit;
+    }
+    let tangent = tangent.normalize();
+    let bitangent = Vec3::cross(&normal, &tangent);
+    let offset = texture.sample(hit.uv) * 2.0 - Vec3::fill(1.0);
+    let mapped = tangent * offset.x() + bitangent * offset.y() + normal * offset.z();
+    if mapped.length_sq() < 1e-12 {
+        return *hit;
+    }
+    Hit {
+        shading_normal: mapped.normalize(),
+        ..*hit
+    }
+}
+
+pub struct Lambertian {
+    pub albedo: Vec3,
+    /// Multiplies `albedo`, in linear space.
+    pub albedo_map: Option<Arc<Texture>>,
+    pub normal_map: Option<Arc<Texture>>,
+}
+
+impl Lambertian {
+    pub fn new(albedo: &Vec3) -> Lambertian {
+        Lambertian {
+            albedo: *albedo,
+            albedo_map: None,
+            normal_map: None,
+        }
+    }
+}
+
+impl Material for Lambertian {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng_state: &mut u32) -> Option<Scatter> {
+        let hit = normal_mapped(hit, &self.normal_map);
+        let normal = facing_normal(ray, &hit);
+        // cosine distribution, by projecting a point of the unit disk on the hemisphere
+        let disk = Vec3::rand_unit_2d(rng_state);
+        let cos_theta = (1.0 - disk.length_sq()).max(0.0).sqrt();
+        let (tangent, bitangent) = orthonormal_basis(&normal);
+        let dir = (tangent * disk.x() + bitangent * disk.y() + normal * cos_theta).normalize();
+        Some(Scatter {
+            ray: Ray::new(&hit.pos, &dir).with_time(ray.time()),
+            attenuation: textured(&self.albedo, &self.albedo_map, &hit),
+            pdf: Some(cos_theta / PI),
+        })
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, light_dir: &Vec3) -> Vec3 {
+        let hit = normal_mapped(hit, &self.normal_map);
+        let cos_theta = Vec3::dot(light_dir, &facing_normal(ray, &hit));
+        textured(&self.albedo, &self.albedo_map, &hit) * (cos_theta.max(0.0) / PI)
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> f32 {
+        let hit = normal_mapped(hit, &self.normal_map);
+        Vec3::dot(dir, &facing_normal(ray, &hit)).max(0.0) / PI
+    }
+
+    fn receives_lights(&self) -> bool {
+        true
+    }
+}
+
+/// Conductor, `roughness` going from a perfect mirror (0) to a very blurry reflection (1).
+pub struct Metal {
+    pub albedo: Vec3,
+    pub roughness: f32,
+    /// Multiplies `albedo`, in linear space.
+    pub albedo_map: Option<Arc<Texture>>,
+    /// Multiplies `roughness`, read from the red channel.
+    pub roughness_map: Option<Arc<Texture>>,
+    pub normal_map: Option<Arc<Texture>>,
+}
+
+impl Metal {
+    pub fn new(albedo: &Vec3, roughness: f32) -> Metal {
+        Metal {
+            albedo: *albedo,
+            roughness,
+            albedo_map: None,
+            roughness_map: None,
+            normal_map: None,
+        }
+    }
+}
+
+impl Metal {
+    /// Shading normal, mirrored direction and Phong exponent of the lobe at `hit`.
+    fn lobe(&self, ray: &Ray, hit: &Hit) -> (Vec3, Vec3, f32) {
+        let hit = normal_mapped(hit, &self.normal_map);
+        let normal = facing_normal(ray, &hit);
+        let reflected = reflect(&ray.dir(), &normal);
+        let roughness = match &self.roughness_map {
+            Some(texture) => self.roughness * texture.sample(hit.uv).x(),
+            None => self.roughness,
+        };
+        (normal, reflected, phong_exponent(roughness))
+    }
+}
+
+/// The rough metals reflect light in a Phong lobe around the mirrored direction, sampled
+/// exactly. The directions of the lobe going under the surface are absorbed.
+impl Material for Metal {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng_state: &mut u32) -> Option<Scatter> {
+        let (normal, reflected, exponent) = self.lobe(ray, hit);
+        let attenuation = textured(&self.albedo, &self.albedo_map, hit);
+        if !self.receives_lights() {
+            return Some(Scatter {
+                ray: Ray::new(&hit.pos, &reflected.normalize()).with_time(ray.time()),
+                attenuation,
+                pdf: None,
+            });
+        }
+        let cos_alpha = random_float01(rng_state).powf(1.0 / (exponent + 1.0));
+        let dir = around(&reflected, cos_alpha, rng_state).normalize();
+        if Vec3::dot(&dir, &normal) <= 0.0 {
+            return None;
+        }
+        Some(Scatter {
+            ray: Ray::new(&hit.pos, &dir).with_time(ray.time()),
+            attenuation,
+            pdf: Some(phong_pdf(cos_alpha, exponent)),
+        })
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, light_dir: &Vec3) -> Vec3 {
+        let pdf = self.pdf(ray, hit, light_dir);
+        textured(&self.albedo, &self.albedo_map, hit) * pdf
+    }
+
+    fn pdf(&self, ray: &Ray, hit: &Hit, dir: &Vec3) -> f32 {
+        if !self.receives_lights() {
+            return 0.0;
+        }
+        let (normal, reflected, exponent) = self.lobe(ray, hit);
+        if Vec3::dot(dir, &normal) <= 0.0 {
+            return 0.0;
+        }
+        phong_pdf(Vec3::dot(dir, &reflected), exponent)
+    }
+
+    /// Perfect mirrors are specular.
+    fn receives_lights(&self) -> bool {
+        self.roughness > 0.0
+    }
+}
+
+/// Clear glass-like material.
+pub struct Dielectric {
+    pub ior: f32,
+}
+
+impl Material for Dielectric {
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng_state: &mut u32) -> Option<Scatter> {
+        let entering = Vec3::dot(&hit.normal, &ray.dir()) < 0.0;
+        let normal = facing_normal(ray, hit);
+        let eta = if entering { 1.0 / self.ior } else { self.ior };
+        let cos_i = -Vec3::dot(&ray.dir(), &normal);
+        let dir = match refract(&ray.dir(), &normal, eta) {
+            Some(refracted) if random_float01(rng_state) >= schlick(cos_i, self.ior) => refracted,
+            _ => reflect(&ray.dir(), &normal),
+        };
+        Some(Scatter {
+            ray: Ray::new(&hit.pos, &dir.normalize()).with_time(ray.time()),
+            attenuation: Vec3::fill(1.0),
+            pdf: None,
+        })
+    }
+}
+
+/// Surface emitting light on both of its sides.
+pub struct Emissive {
+    pub color: Vec3,
+    pub intensity: f32,
+}
+
+impl Material for Emissive {
+    fn scatter(&self, _ray: &Ray, _hit: &Hit, _rng_state: &mut u32) -> Option<Scatter> {
+        None
+    }
+
+    fn emission(&self) -> Vec3 {
+        self.color * self.intensity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit_floor() -> Hit {
+        Hit {
+            pos: Vec3::zero(),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            shading_normal: Vec3::new(0.0, 1.0, 0.0),
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            barycentric: [1.0, 0.0, 0.0],
+            uv: [0.25, 0.25],
+            t: 1.0,
+            material: 0,
+        }
+    }
+
+    #[test]
+    fn lambertian_scatter_above_surface() {
+        let material = Lambertian::new(&Vec3::fill(0.5));
+        let ray = Ray::new(&Vec3::new(0.0, 1.0, 0.0), &Vec3::new(0.0, -1.0, 0.0));
+        let mut rng_state = 0x12345678u32;
+        for _ in 0..100 {
+            let scatter = material
+                .scatter(&ray, &hit_floor(), &mut rng_state)
+                .unwrap();
+            assert!(scatter.ray.dir().y() >= 0.0);
+            assert_eq!(Vec3::fill(0.5), scatter.attenuation);
+        }
+    }
+
+    #[test]
+    fn mirror() {
+        let material = Metal::new(&Vec3::fill(1.0), 0.0);
+        let dir = Vec3::new(1.0, -1.0, 0.0).normalize();
+        let ray = Ray::new(&Vec3::new(-1.0, 1.0, 0.0), &dir);
+        let mut rng_state = 0x12345678u32;
+        let scatter = material
+            .scatter(&ray, &hit_floor(), &mut rng_state)
+            .unwrap();
+        let expected = Vec3::new(1.0, 1.0, 0.0).normalize();
+        assert!((scatter.ray.dir() - expected).length() < 0.0001);
+    }
+
+    #[test]
+    fn dielectric_straight_through() {
+        let material = Dielectric { ior: 1.5 };
+        let ray = Ray::new(&Vec3::new(0.0, 1.0, 0.0), &Vec3::new(0.0, -1.0, 0.0));
+        let mut rng_state = 0x12345678u32;
+        let mut refracted = 0;
+        for _ in 0..100 {
+            let scatter = material
+                .scatter(&ray, &hit_floor(), &mut rng_state)
+                .unwrap();
+            if scatter.ray.dir().y() < 0.0 {
+                assert!((scatter.ray.dir() - ray.dir()).length() < 0.0001);
+                refracted += 1;
+            }
+        }
+        // about 4% of the light is reflected at normal incidence
+        assert!(refracted > 85);
+    }
+
+    #[test]
+    fn texture_maps() {
+        let mut material = Lambertian::new(&Vec3::fill(0.5));
+        let red = Vec3::new(1.0, 0.0, 0.0);
+        material.albedo_map = Some(Arc::new(Texture::new(1, 1, vec![red])));
+        // normal tilted toward the tangent
+        let tilted = Vec3::new(1.0, 0.0, 1.0).normalize();
+        let encoded = (tilted + Vec3::fill(1.0)) * 0.5;
+        material.normal_map = Some(Arc::new(Texture::new(1, 1, vec![encoded])));
+
+        let ray = Ray::new(&Vec3::new(0.0, 1.0, 0.0), &Vec3::new(0.0, -1.0, 0.0));
+        let light_dir = Vec3::new(1.0, 1.0, 0.0).normalize();
+        let color = material.shade(&ray, &hit_floor(), &light_dir, &Vec3::fill(1.0));
+        assert!((color.x() - 0.5).abs() < 0.001);
+        assert_eq!(0.0, color.y());
+
+        let mapped = normal_mapped(&hit_floor(), &material.normal_map);
+        assert!((mapped.shading_normal - Vec3::new(1.0, 1.0, 0.0).normalize()).length() < 0.001);
+    }
+
+    #[test]
+    fn pdf_matches_scatter() {
+        let ray = Ray::new(
+            &Vec3::new(-1.0, 1.0, 0.0),
+            &Vec3::new(1.0, -1.0, 0.0).normalize(),
+        );
+        let materials: [Box<dyn Material>; 2] = [
+            Box::new(Lambertian::new(&Vec3::fill(0.5))),
+            Box::new(Metal::new(&Vec3::fill(0.5), 0.5)),
+        ];
+        let mut rng_state = 0x12345678u32;
+        for material in materials.iter() {
+            assert!(material.receives_lights());
+            let mut scattered = 0;
+            for _ in 0..1000 {
+                let scatter = match material.scatter(&ray, &hit_floor(), &mut rng_state) {
+                    Some(scatter) => scatter,
+                    None => continue,
+                };
+                scattered += 1;
+                let dir = scatter.ray.dir();
+                let pdf = material.pdf(&ray, &hit_floor(), &dir);
+                assert!((scatter.pdf.unwrap() - pdf).abs() < 1e-3 * pdf);
+                let eval = material.eval(&ray, &hit_floor(), &dir);
+                assert!((eval * (1.0 / pdf) - scatter.attenuation).length() < 1e-3);
+            }
+            // the density integrates to the fraction of the directions not absorbed
+            let mut integral = 0.0;
+            for _ in 0..20000 {
+                // uniform on the sphere
+                let z = 1.0 - 2.0 * random_float01(&mut rng_state);
+                let dir = around(&Vec3::new(0.0, 0.0, 1.0), z, &mut rng_state);
+                integral += material.pdf(&ray, &hit_floor(), &dir);
+            }
+            integral *= 4.0 * core::f32::consts::PI / 20000.0;
+            assert!((integral - scattered as f32 / 1000.0).abs() < 0.1);
+        }
+        assert!(!Metal::new(&Vec3::fill(0.5), 0.0).receives_lights());
+    }
+
+    #[test]
+    fn total_internal_reflection() {
+        let dir = Vec3::new(1.0, -0.2, 0.0).normalize();
+        assert!(refract(&dir, &Vec3::new(0.0, 1.0, 0.0), 1.5).is_none());
+        assert!(refract(&dir, &Vec3::new(0.0, 1.0, 0.0), 1.0 / 1.5).is_some());
+    }
+}
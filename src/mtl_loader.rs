@@ -0,0 +1,374 @@
+use crate::material::*;
+use crate::texture::{TextureCache, WrapMode};
+use crate::vec3::Vec3;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+/// Material as described in a Wavefront MTL library.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MtlMaterial {
+    pub name: String,
+    /// Kd
+    pub diffuse: Vec3,
+    /// Ks
+    pub specular: Vec3,
+    /// Ns
+    pub shininess: f32,
+    /// Ke
+    pub emission: Vec3,
+    /// d, or 1 - Tr
+    pub dissolve: f32,
+    /// Ni
+    pub ior: f32,
+    pub illum: u32,
+    /// map_Kd, joined to the directory of the library like the other maps
+    pub diffuse_map: Option<PathBuf>,
+    /// map_Pr
+    pub roughness_map: Option<PathBuf>,
+    /// norm, a tangent-space normal map
+    pub normal_map: Option<PathBuf>,
+    /// map_Bump or bump, a height map, which is not rendered
+    pub bump_map: Option<PathBuf>,
+}
+
+impl MtlMaterial {
+    pub fn new(name: &str) -> MtlMaterial {
+        MtlMaterial {
+            name: name.to_string(),
+            diffuse: Vec3::fill(0.7),
+            specular: Vec3::zero(),
+            shininess: 0.0,
+            emission: Vec3::zero(),
+            dissolve: 1.0,
+            ior: 1.5,
+            illum: 2,
+            diffuse_map: None,
+            roughness_map: None,
+            normal_map: None,
+            bump_map: None,
+        }
+    }
+
+    /// Closest renderer material: emissive when Ke is set, glass for transparent or refracting
+    /// illumination models, metal for the reflective ones and lambertian otherwise. The texture
+    /// maps are loaded through `textures`, those that cannot be read being left out and
+    /// reported in `warnings`.
+    pub fn to_material(
+        &self,
+        textures: &mut TextureCache,
+        warnings: &mut Vec<String>,
+    ) -> Box<dyn Material> {
+        if self.emission.hmax() > 0.0 {
+            return Box::new(Emissive {
+                color: self.emission,
+                intensity: 1.0,
+            });
+        }
+        let refracting = matches!(self.illum, 4 | 6 | 7 | 9);
+        if self.dissolve < 1.0 || refracting {
+            return Box::new(Dielectric { ior: self.ior });
+        }
+        let mut load = |map: &Option<PathBuf>, srgb: bool| {
+            let path = map.as_ref()?;
+            match textures.load(path, srgb, WrapMode::Repeat) {
+                Ok(texture) => Some(texture),
+                Err(err) => {
+                    warnings.push(format!("material {} left untextured: {}", self.name, err));
+                    None
+                }
+            }
+        };
+        let reflecting = self.illum == 3 || self.illum == 5;
+        if reflecting && self.specular.hmax() > 0.0 {
+            // Blinn-Phong exponent to roughness, as in Walter et al. 2007
+            let roughness = (2.0 / (self.shininess + 2.0)).sqrt();
+            let mut metal = Metal::new(&self.specular, roughness);
+            metal.roughness_map = load(&self.roughness_map, false);
+            metal.normal_map = load(&self.normal_map, false);
+            return Box::new(metal);
+        }
+        let mut lambertian = Lambertian::new(&self.diffuse);
+        lambertian.albedo_map = load(&self.diffuse_map, true);
+        lambertian.normal_map = load(&self.normal_map, false);
+        return Box::new(lambertian);
+    }
+}
+
+/// Keywords of the texture maps, which exporters write with any case.
+const MAP_KEYWORDS: [&str; 5] = ["map_Kd", "map_Pr", "norm", "map_Bump", "bump"];
+
+fn parse_floats(words: &[&str], count: usize, error: &str) -> std::io::Result<Vec<f32>> {
+    if words.len() < count {
+        return Err(Error::other(error.to_string()));
+    }
+    let mut values = Vec::with_capacity(count);
+    for w in words.iter().take(count) {
+        match w.parse::<f32>() {
+            Ok(v) => values.push(v),
+            _ => return Err(Error::other(error.to_string())),
+        }
+    }
+    return Ok(values);
+}
+
+fn parse_color(words: &[&str], error: &str) -> std::io::Result<Vec3> {
+    // a single value is a gray
+    if words.len() == 1 {
+        let v = parse_floats(words, 1, error)?;
+        return Ok(Vec3::fill(v[0]));
+    }
+    let v = parse_floats(words, 3, error)?;
+    return Ok(Vec3::new(v[0], v[1], v[2]));
+}
+
+/// Materials of the library `filename`. Texture paths are resolved relatively to it.
+pub fn load_library(filename: &Path) -> std::io::Result<Vec<MtlMaterial>> {
+    let contents = std::fs::read_to_string(filename)?;
+    let base_dir = filename.parent().unwrap_or_else(|| Path::new(""));
+    parse_library(&contents, base_dir)
+}
+
+pub fn parse_library(contents: &str, base_dir: &Path) -> std::io::Result<Vec<MtlMaterial>> {
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    // Tr is only used when d is missing, exporters disagree on its meaning otherwise
+    let mut has_dissolve = false;
+    for line in contents.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() || words[0].starts_with('#') {
+            continue;
+        }
+        if words[0] == "newmtl" {
+            let name = words[1..].join(" ");
+            materials.push(MtlMaterial::new(&name));
+            has_dissolve = false;
+            continue;
+        }
+        let material = match materials.last_mut() {
+            Some(material) => material,
+            None => continue,
+        };
+        let args = &words[1..];
+        match words[0] {
+            "Kd" => material.diffuse = parse_color(args, "bad Kd format")?,
+            "Ks" => material.specular = parse_color(args, "bad Ks format")?,
+            "Ke" => material.emission = parse_color(args, "bad Ke format")?,
+            "Ns" => material.shininess = parse_floats(args, 1, "bad Ns format")?[0],
+            "Ni" => material.ior = parse_floats(args, 1, "bad Ni format")?[0],
+            "d" => {
+                material.dissolve = parse_floats(args, 1, "bad d format")?[0];
+                has_dissolve = true;
+            }
+            "Tr" => {
+                let transparency = parse_floats(args, 1, "bad Tr format")?[0];
+                if !has_dissolve {
+                    material.dissolve = 1.0 - transparency;
+                }
+            }
+            "illum" => {
+                material.illum = match args.first().map(|w| w.parse::<u32>()) {
+                    Some(Ok(illum)) => illum,
+                    _ => return Err(Error::other("bad illum format")),
+                }
+            }
+            keyword
+                if MAP_KEYWORDS
+                    .iter()
+                    .any(|map| keyword.eq_ignore_ascii_case(map)) =>
+            {
+                let map = if keyword.eq_ignore_ascii_case("map_Kd") {
+                    &mut material.diffuse_map
+                } else if keyword.eq_ignore_ascii_case("map_Pr") {
+                    &mut material.roughness_map
+                } else if keyword.eq_ignore_ascii_case("norm") {
+                    &mut material.normal_map
+                } else {
+                    &mut material.bump_map
+                };
+                // options come before the file name, which is the last word, written with
+                // backslashes by Windows exporters
+                match args.last() {
+                    Some(path) => *map = Some(base_dir.join(path.replace('\\', "/"))),
+                    None => return Err(Error::other(format!("bad {} format", keyword))),
+                }
+            }
+            _ => {}
+        }
+    }
+    return Ok(materials);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hit::Hit;
+    use crate::ray::Ray;
+
+    fn hit_floor() -> Hit {
+        Hit {
+            pos: Vec3::zero(),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            shading_normal: Vec3::new(0.0, 1.0, 0.0),
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            barycentric: [1.0, 0.0, 0.0],
+            uv: [0.25, 0.25],
+            t: 1.0,
+            material: 0,
+        }
+    }
+
+    /// Attenuation of a path scattered by `material`, coming straight down on a floor.
+    fn attenuation(material: &dyn Material) -> Vec3 {
+        let ray = Ray::new(&Vec3::new(0.0, 1.0, 0.0), &Vec3::new(0.0, -1.0, 0.0));
+        let scatter = material
+            .scatter(&ray, &hit_floor(), &mut 0x12345678u32)
+            .unwrap();
+        return scatter.attenuation;
+    }
+
+    #[test]
+    fn library() {
+        let materials = parse_library(
+            "# test library
+newmtl red
+Kd 0.8 0.1 0.1
+Ks 0.0
+map_Kd -bm 1.0 textures/red.ppm
+map_Bump textures/red_bump.png
+norm textures/red_normal.png
+
+newmtl glass
+Ni 1.33
+d 0.5
+Tr 0.0
+
+newmtl lamp
+Ke 4 4 4
+
+newmtl chrome
+illum 3
+Ks 0.9 0.9 0.9
+Ns 98
+",
+            Path::new("assets"),
+        )
+        .unwrap();
+
+        assert_eq!(4, materials.len());
+        assert_eq!("red", materials[0].name);
+        assert_eq!(Vec3::new(0.8, 0.1, 0.1), materials[0].diffuse);
+        assert_eq!(
+            Some(Path::new("assets").join("textures/red.ppm")),
+            materials[0].diffuse_map
+        );
+        assert_eq!(
+            Some(Path::new("assets").join("textures/red_normal.png")),
+            materials[0].normal_map
+        );
+        assert_eq!(
+            Some(Path::new("assets").join("textures/red_bump.png")),
+            materials[0].bump_map
+        );
+        assert_eq!(None, materials[0].roughness_map);
+        assert_eq!(1.33, materials[1].ior);
+        assert_eq!(0.5, materials[1].dissolve);
+        assert_eq!(Vec3::fill(4.0), materials[2].emission);
+        assert_eq!(3, materials[3].illum);
+        assert_eq!(98.0, materials[3].shininess);
+    }
+
+    #[test]
+    fn bad_format() {
+        let result = parse_library("newmtl red\nKd 0.8 abc 0.1\n", Path::new(""));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unreadable_maps() {
+        let materials = parse_library(
+            "newmtl red\nKd 0.8 0.1 0.1\nMAP_KD textures\\red.tga\nNORM missing.png\n",
+            Path::new("assets"),
+        )
+        .unwrap();
+        assert_eq!(
+            Some(Path::new("assets").join("textures/red.tga")),
+            materials[0].diffuse_map
+        );
+        assert_eq!(
+            Some(Path::new("assets").join("missing.png")),
+            materials[0].normal_map
+        );
+        // left untextured
+        let mut warnings = Vec::new();
+        let material = materials[0].to_material(&mut TextureCache::new(), &mut warnings);
+        assert_eq!(Vec3::new(0.8, 0.1, 0.1), attenuation(material.as_ref()));
+        assert_eq!(2, warnings.len());
+    }
+
+    #[test]
+    fn to_material() {
+        let materials = parse_library(
+            "newmtl plastic
+Kd 0.8 0.1 0.1
+Ks 0.5 0.5 0.5
+
+newmtl chrome
+Kd 0.8 0.1 0.1
+Ks 0.9 0.9 0.9
+Ns 98
+illum 3
+
+newmtl lamp
+Kd 0.8 0.1 0.1
+Ke 4 4 4
+
+newmtl glass
+Kd 0.8 0.1 0.1
+d 0.5
+",
+            Path::new(""),
+        )
+        .unwrap();
+        let mut textures = TextureCache::new();
+        let materials: Vec<Box<dyn Material>> = materials
+            .iter()
+            .map(|material| material.to_material(&mut textures, &mut Vec::new()))
+            .collect();
+        // lambertian with Kd, as Ks only reflects with the illum 3 and 5
+        assert!(materials[0].receives_lights());
+        assert_eq!(Vec3::new(0.8, 0.1, 0.1), attenuation(materials[0].as_ref()));
+        // rough metal with Ks
+        assert!(materials[1].receives_lights());
+        assert_eq!(Vec3::fill(0.9), attenuation(materials[1].as_ref()));
+        // emissive with Ke
+        assert_eq!(Vec3::fill(4.0), materials[2].emission());
+        assert_eq!(Vec3::zero(), materials[0].emission());
+        // dielectric, transparent and colorless
+        assert!(!materials[3].receives_lights());
+        assert_eq!(Vec3::fill(1.0), attenuation(materials[3].as_ref()));
+    }
+
+    #[test]
+    fn height_bump() {
+        // a uniform dark gray, which would bend the normal below the surface as a normal map
+        let dir = std::env::temp_dir();
+        let filename = dir.join("toy_mesh_path_tracer_mtl_bump.ppm");
+        let mut data = b"P6\n1 1\n255\n".to_vec();
+        data.extend_from_slice(&[64, 64, 64]);
+        std::fs::write(&filename, data).unwrap();
+        let materials = parse_library(
+            "newmtl clay\nKd 0.5 0.5 0.5\nbump toy_mesh_path_tracer_mtl_bump.ppm\n",
+            &dir,
+        )
+        .unwrap();
+        let mut warnings = Vec::new();
+        let material = materials[0].to_material(&mut TextureCache::new(), &mut warnings);
+        std::fs::remove_file(&filename).unwrap();
+
+        let ray = Ray::new(&Vec3::new(0.0, 1.0, 0.0), &Vec3::new(0.0, -1.0, 0.0));
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let expected = Lambertian::new(&Vec3::fill(0.5)).eval(&ray, &hit_floor(), &up);
+        assert_eq!(expected, material.eval(&ray, &hit_floor(), &up));
+        assert_eq!(Some(filename), materials[0].bump_map);
+        assert!(warnings.is_empty());
+    }
+}
@@ -1,83 +1,560 @@
+use crate::mtl_loader::{self, MtlMaterial};
 use crate::triangle::Triangle;
 use crate::vec3::Vec3;
-use std::io::{Error, ErrorKind};
-
-pub fn load_scene(filename: &str) -> std::io::Result<Vec<Triangle>> {
-    let mut vertices: Vec<f32> = Vec::new();
-    let mut indexes: Vec<usize> = Vec::new();
-    {
-        let mut idx_buffer: Vec<usize> = Vec::new();
-        let contents = std::fs::read_to_string(filename)?;
-        for line in contents.lines() {
-            if line.starts_with("v ") {
-                let error = Error::new(ErrorKind::Other, "bad vertex format");
-                let mut word_iter = line.split_whitespace();
-                assert_eq!(Some("v"), word_iter.next());
-                for w in word_iter {
-                    let val = match w.parse::<f32>() {
-                        Ok(v) => v,
-                        _ => return Err(error),
-                    };
-                    vertices.push(val);
+use std::fmt;
+use std::path::Path;
+
+pub struct ObjMesh {
+    /// `Triangle::material` indexes `materials`.
+    pub triangles: Vec<Triangle>,
+    /// Materials used by the faces, in order of first use. Faces read before any `usemtl`, or
+    /// using a material missing from the libraries, get a default `MtlMaterial` named after it
+    /// (with an empty name for the former).
+    pub materials: Vec<MtlMaterial>,
+    /// Problems which did not stop the loading, such as material libraries that could not be
+    /// read, for the caller to report.
+    pub warnings: Vec<String>,
+}
+
+/// Why an OBJ file could not be loaded. Line numbers start at 1.
+#[derive(Debug)]
+pub enum ObjError {
+    /// The OBJ file itself could not be read.
+    Io(std::io::Error),
+    /// Malformed statement.
+    Syntax { line: usize, message: String },
+    /// Face corner referring to an element which is not defined, `index` being as written in
+    /// the file.
+    IndexOutOfRange {
+        line: usize,
+        element: &'static str,
+        index: i64,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(err) => write!(f, "{}", err),
+            ObjError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+            ObjError::IndexOutOfRange {
+                line,
+                element,
+                index,
+            } => write!(
+                f,
+                "line {}: {} index {} is out of range",
+                line, element, index
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+pub fn load_scene(filename: &str) -> Result<ObjMesh, ObjError> {
+    let contents = std::fs::read_to_string(filename).map_err(ObjError::Io)?;
+    let base_dir = Path::new(filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    parse_scene(&contents, base_dir)
+}
+
+fn syntax_error(line: usize, message: &str) -> ObjError {
+    ObjError::Syntax {
+        line,
+        message: message.to_string(),
+    }
+}
+
+/// Parse the `count` first values of a statement, the following ones defaulting to 0.
+fn parse_floats<const N: usize>(
+    args: &[&str],
+    count: usize,
+    line: usize,
+    what: &str,
+) -> Result<[f32; N], ObjError> {
+    let mut values = [0f32; N];
+    if args.len() < count {
+        return Err(syntax_error(
+            line,
+            &format!("{} needs {} values", what, count),
+        ));
+    }
+    for (value, w) in values.iter_mut().zip(args.iter()) {
+        *value = w
+            .parse::<f32>()
+            .map_err(|_| syntax_error(line, &format!("{} has an invalid value '{}'", what, w)))?;
+    }
+    return Ok(values);
+}
+
+/// Resolve a 1-based index, or a negative one relative to the `count` elements defined so far,
+/// to a 0-based index.
+fn resolve_index(
+    index_str: &str,
+    count: usize,
+    line: usize,
+    element: &'static str,
+) -> Result<usize, ObjError> {
+    let index = index_str
+        .parse::<i64>()
+        .map_err(|_| syntax_error(line, &format!("invalid {} index '{}'", element, index_str)))?;
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(ObjError::IndexOutOfRange {
+            line,
+            element,
+            index,
+        });
+    }
+    return Ok(resolved as usize);
+}
+
+/// Vertex of a face, as indexes in the vertex, texture coordinates and normal lists.
+#[derive(Clone, Copy)]
+struct Corner {
+    vertex: usize,
+    texcoord: Option<usize>,
+    normal: Option<usize>,
+}
+
+/// Split a polygon into triangles by ear clipping, after projecting it on the axis plane where
+/// it is the largest. Returns indexes in `points`. What is left when no ear can be found, for
+/// degenerate or self-intersecting polygons, is split as a fan.
+fn triangulate(points: &[Vec3]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+    // Newell's method, robust to concave polygons
+    let mut normal = [0f32; 3];
+    for (i, a) in points.iter().enumerate() {
+        let b = points[(i + 1) % n];
+        normal[0] += (a.y() - b.y()) * (a.z() + b.z());
+        normal[1] += (a.z() - b.z()) * (a.x() + b.x());
+        normal[2] += (a.x() - b.x()) * (a.y() + b.y());
+    }
+    let mut axis = 0;
+    for i in 1..3 {
+        if normal[i].abs() > normal[axis].abs() {
+            axis = i;
+        }
+    }
+    // with the two other axes in cyclic order, the projection keeps the winding when the
+    // normal points along `axis`
+    let orientation = normal[axis].signum();
+    let projected: Vec<[f32; 2]> = points
+        .iter()
+        .map(|p| [p.get((axis + 1) % 3), p.get((axis + 2) % 3)])
+        .collect();
+    let cross = |a: usize, b: usize, c: usize| {
+        let (a, b, c) = (projected[a], projected[b], projected[c]);
+        ((b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])) * orientation
+    };
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n - 2);
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        // starting from the second vertex, so that convex polygons end up as a fan
+        let ear = (1..=m).map(|i| i % m).find(|&i| {
+            let a = remaining[(i + m - 1) % m];
+            let b = remaining[i];
+            let c = remaining[(i + 1) % m];
+            if cross(a, b, c) <= 0.0 {
+                return false;
+            }
+            remaining.iter().all(|&p| {
+                p == a
+                    || p == b
+                    || p == c
+                    || cross(a, b, p) < 0.0
+                    || cross(b, c, p) < 0.0
+                    || cross(c, a, p) < 0.0
+            })
+        });
+        match ear {
+            Some(i) => {
+                triangles.push([
+                    remaining[(i + m - 1) % m],
+                    remaining[i],
+                    remaining[(i + 1) % m],
+                ]);
+                remaining.remove(i);
+            }
+            None => break,
+        }
+    }
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+    return triangles;
+}
+
+/// Parse the OBJ `contents`, loading its material libraries relatively to `base_dir`. Libraries
+/// that cannot be loaded are skipped, and listed in the warnings of the mesh.
+pub fn parse_scene(contents: &str, base_dir: &Path) -> Result<ObjMesh, ObjError> {
+    let mut vertices: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut texcoords: Vec<[f32; 2]> = Vec::new();
+    let mut triangles_list: Vec<Triangle> = Vec::new();
+    let mut has_faces = false;
+    let mut material_names: Vec<String> = Vec::new();
+    let mut library: Vec<MtlMaterial> = Vec::new();
+    let mut current_material: Option<usize> = None;
+    let mut corners: Vec<Corner> = Vec::new();
+    let mut polygon: Vec<Vec3> = Vec::new();
+    for (line_idx, line) in contents.lines().enumerate() {
+        let line_number = line_idx + 1;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() || words[0].starts_with('#') {
+            continue;
+        }
+        let args = &words[1..];
+        match words[0] {
+            "mtllib" => {
+                // the faces keep the default materials without it
+                for w in args {
+                    let path = base_dir.join(w);
+                    match mtl_loader::load_library(&path) {
+                        Ok(materials) => library.extend(materials),
+                        Err(err) => warnings.push(format!(
+                            "line {}: failed to load material library '{}': {}",
+                            line_number,
+                            path.display(),
+                            err
+                        )),
+                    }
                 }
-            } else if line.starts_with("f ") {
-                idx_buffer.clear();
-                let error = Error::new(ErrorKind::Other, "bad face form");
-                let mut word_iter = line.split_whitespace();
-                assert_eq!(Some("f"), word_iter.next());
-                for w in word_iter {
-                    let index = match w.split('/').next() {
-                        Some(index_str) => index_str.parse::<usize>().unwrap(),
-                        _ => return Err(error),
-                    };
-                    idx_buffer.push(index - 1usize);
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                current_material = Some(
+                    match material_names.iter().position(|known| *known == name) {
+                        Some(idx) => idx,
+                        None => {
+                            material_names.push(name);
+                            material_names.len() - 1
+                        }
+                    },
+                );
+            }
+            // the optional w, or vertex colors, are ignored
+            "v" => vertices.push(Vec3::from(parse_floats::<3>(
+                args,
+                3,
+                line_number,
+                "vertex",
+            )?)),
+            "vn" => normals.push(Vec3::from(parse_floats::<3>(
+                args,
+                3,
+                line_number,
+                "normal",
+            )?)),
+            // v defaults to 0 and the optional w is ignored
+            "vt" => texcoords.push(parse_floats::<2>(
+                args,
+                1,
+                line_number,
+                "texture coordinates",
+            )?),
+            "f" => {
+                has_faces = true;
+                corners.clear();
+                for w in args {
+                    // v, v/vt, v//vn or v/vt/vn
+                    let parts: Vec<&str> = w.split('/').collect();
+                    if parts.len() > 3 || parts[0].is_empty() {
+                        return Err(syntax_error(
+                            line_number,
+                            &format!("invalid face vertex '{}'", w),
+                        ));
+                    }
+                    let optional =
+                        |idx: usize, count: usize, element: &'static str| match parts.get(idx) {
+                            Some(index_str) if !index_str.is_empty() => {
+                                resolve_index(index_str, count, line_number, element).map(Some)
+                            }
+                            _ => Ok(None),
+                        };
+                    corners.push(Corner {
+                        vertex: resolve_index(parts[0], vertices.len(), line_number, "vertex")?,
+                        texcoord: optional(1, texcoords.len(), "texture coordinates")?,
+                        normal: optional(2, normals.len(), "normal")?,
+                    });
+                }
+                if corners.len() < 3 {
+                    return Err(syntax_error(line_number, "face with fewer than 3 vertices"));
                 }
-                if idx_buffer.len() == 3 {
-                    for idx in idx_buffer.iter() {
-                        indexes.push(*idx);
+                let material = match current_material {
+                    Some(material) => material,
+                    None => {
+                        material_names.push(String::new());
+                        current_material = Some(material_names.len() - 1);
+                        material_names.len() - 1
+                    }
+                };
+                polygon.clear();
+                polygon.extend(corners.iter().map(|corner| vertices[corner.vertex]));
+                for [c0, c1, c2] in triangulate(&polygon) {
+                    let (c0, c1, c2) = (corners[c0], corners[c1], corners[c2]);
+                    let mut triangle = Triangle::new(
+                        vertices[c0.vertex],
+                        vertices[c1.vertex],
+                        vertices[c2.vertex],
+                    );
+                    triangle.material = material;
+                    // only used when given for every corner, and none of them is null, the
+                    // geometric normal being used otherwise
+                    if let (Some(n0), Some(n1), Some(n2)) = (c0.normal, c1.normal, c2.normal) {
+                        let corner_normals = [normals[n0], normals[n1], normals[n2]];
+                        if corner_normals.iter().all(|normal| normal.length_sq() > 0.0) {
+                            triangle.normals =
+                                Some(corner_normals.map(|normal| normal.normalize()));
+                        }
+                    }
+                    if let (Some(t0), Some(t1), Some(t2)) = (c0.texcoord, c1.texcoord, c2.texcoord)
+                    {
+                        triangle.uvs = Some([texcoords[t0], texcoords[t1], texcoords[t2]]);
                     }
-                } else if idx_buffer.len() == 4 {
-                    indexes.push(idx_buffer[0]);
-                    indexes.push(idx_buffer[1]);
-                    indexes.push(idx_buffer[2]);
-                    indexes.push(idx_buffer[0]);
-                    indexes.push(idx_buffer[2]);
-                    indexes.push(idx_buffer[3]);
-                } else {
-                    return Err(error);
+                    triangles_list.push(triangle);
                 }
             }
+            _ => {}
         }
     }
-    let mut triangles_list: Vec<Triangle> = Vec::new();
-    if indexes.is_empty() {
-        for tr_chunk in vertices.chunks(9) {
-            let mut v1 = [0f32; 3];
-            v1.copy_from_slice(&tr_chunk[0..3]);
-            let mut v2 = [0f32; 3];
-            v2.copy_from_slice(&tr_chunk[3..6]);
-            let mut v3 = [0f32; 3];
-            v3.copy_from_slice(&tr_chunk[6..9]);
-            triangles_list.push(Triangle {
-                vertices: [Vec3::from(v1), Vec3::from(v2), Vec3::from(v3)],
-            });
-        }
-    } else {
-        for tr_chunk in indexes.chunks(3) {
-            let mut v1 = [0f32; 3];
-            let v1_idx = tr_chunk[0] * 3;
-            v1.copy_from_slice(&vertices[v1_idx..v1_idx + 3]);
-            let mut v2 = [0f32; 3];
-            let v2_idx = tr_chunk[1] * 3;
-            v2.copy_from_slice(&vertices[v2_idx..v2_idx + 3]);
-            let mut v3 = [0f32; 3];
-            let v3_idx = tr_chunk[2] * 3;
-            v3.copy_from_slice(&vertices[v3_idx..v3_idx + 3]);
-            triangles_list.push(Triangle {
-                vertices: [Vec3::from(v1), Vec3::from(v2), Vec3::from(v3)],
-            });
+    // without faces, consecutive vertices make up triangles
+    if !has_faces {
+        for tr_chunk in vertices.chunks_exact(3) {
+            triangles_list.push(Triangle::new(tr_chunk[0], tr_chunk[1], tr_chunk[2]));
         }
     }
 
-    return Ok(triangles_list);
+    if material_names.is_empty() {
+        material_names.push(String::new());
+    }
+    let materials = material_names
+        .iter()
+        .map(
+            |name| match library.iter().find(|material| &material.name == name) {
+                Some(material) => material.clone(),
+                None => MtlMaterial::new(name),
+            },
+        )
+        .collect();
+
+    return Ok(ObjMesh {
+        triangles: triangles_list,
+        materials,
+        warnings,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usemtl() {
+        let mesh = parse_scene(
+            "v 0 0 0
+v 1 0 0
+v 0 1 0
+v 1 1 0
+f 1 2 3
+usemtl red
+f 2 4 3
+usemtl blue
+f 1 2 3 4
+usemtl red
+f 1 2 4
+",
+            Path::new(""),
+        )
+        .unwrap();
+        let names: Vec<&str> = mesh.materials.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(vec!["", "red", "blue"], names);
+        let materials: Vec<usize> = mesh.triangles.iter().map(|t| t.material).collect();
+        assert_eq!(vec![0, 1, 2, 2, 1], materials);
+
+        // a missing library leaves the default materials
+        let mesh = parse_scene(
+            "mtllib missing.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\n",
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(vec![MtlMaterial::new("red")], mesh.materials);
+        assert_eq!(1, mesh.warnings.len());
+        assert!(mesh.warnings[0].starts_with("line 1: "));
+    }
+
+    #[test]
+    fn vertex_normals() {
+        let mesh = parse_scene(
+            "v 0 0 0
+v 1 0 0
+v 0 1 0
+vn 0 0 2
+vn 0 1 1
+vn 0 0 0
+vt 0 0
+f 1//1 2//1 3//2
+f 1/1/1 2/1/2 3/1/1
+f 1 2 3
+f 1//1 2 3
+f 1//1 2//3 3//1
+",
+            Path::new(""),
+        )
+        .unwrap();
+        let n0 = Vec3::new(0.0, 0.0, 1.0);
+        let n1 = Vec3::new(0.0, 1.0, 1.0).normalize();
+        assert_eq!(Some([n0, n0, n1]), mesh.triangles[0].normals);
+        assert_eq!(Some([n0, n1, n0]), mesh.triangles[1].normals);
+        assert_eq!(None, mesh.triangles[2].normals);
+        // normals are only used when given for every corner
+        assert_eq!(None, mesh.triangles[3].normals);
+        // nor when one of them is null
+        assert_eq!(None, mesh.triangles[4].normals);
+        assert!(parse_scene("v 0 0 0\nf 1//1 1//1 1//1\n", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn texture_coordinates() {
+        let mesh = parse_scene(
+            "v 0 0 0
+v 1 0 0
+v 0 1 0
+vt 0 0
+vt 1 0 0
+vt 0.5 1
+vn 0 0 1
+f 1/1 2/2 3/3
+f 1/3/1 2/2/1 3/1/1
+f 1//1 2//1 3//1
+",
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(
+            Some([[0.0, 0.0], [1.0, 0.0], [0.5, 1.0]]),
+            mesh.triangles[0].uvs
+        );
+        assert_eq!(
+            Some([[0.5, 1.0], [1.0, 0.0], [0.0, 0.0]]),
+            mesh.triangles[1].uvs
+        );
+        assert!(mesh.triangles[1].normals.is_some());
+        assert_eq!(None, mesh.triangles[2].uvs);
+        assert!(parse_scene("v 0 0 0\nf 1/1 1/1 1/1\n", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn relative_indices() {
+        let mesh = parse_scene(
+            "v 0 0 0
+v 1 0 0
+v 0 1 0
+f -3 -2 -1
+v 5 5 5
+f 1 -3 -2
+",
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(2, mesh.triangles.len());
+        assert_eq!(mesh.triangles[0].vertices, mesh.triangles[1].vertices);
+    }
+
+    #[test]
+    fn polygons() {
+        // concave L shape, a fan from the first vertex would cover the notch
+        let mesh = parse_scene(
+            "v 0 0 0
+v 2 0 0
+v 2 1 0
+v 1 1 0
+v 1 2 0
+v 0 2 0
+f 4 5 6 1 2 3
+",
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!(4, mesh.triangles.len());
+        let area: f32 = mesh
+            .triangles
+            .iter()
+            .map(|t| {
+                let v = &t.vertices;
+                let cross = Vec3::cross(&(v[1] - v[0]), &(v[2] - v[0]));
+                // every triangle keeps the winding of the polygon
+                assert!(cross.z() > 0.0);
+                cross.length() * 0.5
+            })
+            .sum();
+        assert!((area - 3.0).abs() < 0.0001);
+
+        // convex polygons are split as a fan
+        let mesh = parse_scene(
+            "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5\n",
+            Path::new(""),
+        )
+        .unwrap();
+        let p = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(2.0, 1.0, 0.0),
+            Vec3::new(1.0, 2.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        ];
+        let fan = vec![[p[0], p[1], p[2]], [p[0], p[2], p[3]], [p[0], p[3], p[4]]];
+        let triangles: Vec<[Vec3; 3]> = mesh.triangles.iter().map(|t| t.vertices).collect();
+        assert_eq!(fan, triangles);
+    }
+
+    #[test]
+    fn errors() {
+        let parse_err = |contents: &str| parse_scene(contents, Path::new("")).err().unwrap();
+        match parse_err("v 0 0 0\nv 1 0 0\n\nf 1 2 x\n") {
+            ObjError::Syntax { line: 4, .. } => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err("v 0 0 0\nv 1 0 abc\n") {
+            ObjError::Syntax { line: 2, .. } => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err("v 0 0 0\nv 1 0 0\nf 1 2\n") {
+            ObjError::Syntax { line: 3, .. } => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n") {
+            ObjError::IndexOutOfRange {
+                line: 4,
+                element: "vertex",
+                index: 4,
+            } => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -4 1 2\n") {
+            ObjError::IndexOutOfRange { index: -4, .. } => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n") {
+            ObjError::IndexOutOfRange { index: 0, .. } => {}
+            err => panic!("unexpected error {}", err),
+        }
+        let err = parse_err("v 0 0 0\nf 1/1 1/1 1/1\n");
+        assert_eq!(
+            "line 2: texture coordinates index 1 is out of range",
+            err.to_string()
+        );
+    }
 }
@@ -26,7 +26,7 @@ mod tests {
     #[test]
     #[ignore]
     fn random_float01_full_range() {
-        for mut state in u32::min_value()..=u32::max_value() {
+        for mut state in u32::MIN..=u32::MAX {
             let rand_float = super::random_float01(&mut state);
             assert!(0.0 <= rand_float);
             assert!(1.0 >= rand_float);
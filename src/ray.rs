@@ -4,11 +4,44 @@ pub struct Ray {
     origin: Vec3,
     dir: Vec3,
     dir_inv: Vec3,
+    /// Rows of the transform of the direction into the unit z axis, see `shear`.
+    shear: [Vec3; 3],
+    /// Instant the ray is cast at, at which the moving triangles are intersected.
+    time: f32,
 }
 
 impl Ray {
     pub fn new(origin: &Vec3, direction: &Vec3) -> Ray {
         assert!((direction.length() - 1.0).abs() < 0.001);
+        let abs_dir = [
+            direction.x().abs(),
+            direction.y().abs(),
+            direction.z().abs(),
+        ];
+        let kz = if abs_dir[0] > abs_dir[1] {
+            if abs_dir[0] > abs_dir[2] {
+                0
+            } else {
+                2
+            }
+        } else if abs_dir[1] > abs_dir[2] {
+            1
+        } else {
+            2
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if direction.get(kz) < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        // the largest component becomes z, and the others x and y, keeping the handedness
+        let dir_z = direction.get(kz);
+        let mut shear = [[0f32; 3]; 3];
+        shear[0][kx] = 1.0;
+        shear[0][kz] = -direction.get(kx) / dir_z;
+        shear[1][ky] = 1.0;
+        shear[1][kz] = -direction.get(ky) / dir_z;
+        shear[2][kz] = 1.0 / dir_z;
         Ray {
             origin: *origin,
             dir: *direction,
@@ -17,9 +50,21 @@ impl Ray {
                 1.0 / direction.y(),
                 1.0 / direction.z(),
             ),
+            shear: [
+                Vec3::from(shear[0]),
+                Vec3::from(shear[1]),
+                Vec3::from(shear[2]),
+            ],
+            time: 0.0,
         }
     }
 
+    /// Same ray, cast at `time`.
+    pub fn with_time(mut self, time: f32) -> Ray {
+        self.time = time;
+        self
+    }
+
     pub fn origin(&self) -> Vec3 {
         self.origin
     }
@@ -32,6 +77,17 @@ impl Ray {
         self.dir_inv
     }
 
+    /// Rows of the permutation and shear transforming the ray direction into the unit z axis,
+    /// used by the watertight triangle test. The largest component of the direction is the one
+    /// moved to z, which keeps the transform well conditioned.
+    pub fn shear(&self) -> &[Vec3; 3] {
+        &self.shear
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
     pub fn point_at(&self, t: f32) -> Vec3 {
         self.origin + self.dir * t
     }
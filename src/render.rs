@@ -0,0 +1,863 @@
+use crate::bvh::TraversalCounts;
+use crate::camera::Camera;
+use crate::ppm_writer;
+use crate::random;
+use crate::scene::{self, Scene};
+use crate::vec3::Vec3;
+
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// What the pixels show.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RenderMode {
+    /// Path traced image.
+    Shaded,
+    /// Number of BVH nodes and triangles visited by the camera rays, from blue for none to red
+    /// for `HEATMAP_MAX_VISITS` or more.
+    Heatmap,
+}
+
+/// Visits shown in red by the heatmap. The scale is fixed so that images of different trees
+/// can be compared.
+pub const HEATMAP_MAX_VISITS: usize = 200;
+
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub spp: usize,
+    pub max_depth: usize,
+    pub tile_size: usize,
+    pub mode: RenderMode,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            width: 640,
+            height: 360,
+            spp: 4,
+            max_depth: 10,
+            tile_size: 8,
+            mode: RenderMode::Shaded,
+        }
+    }
+}
+
+impl RenderSettings {
+    pub fn aspect(&self) -> f32 {
+        (self.width as f32) / (self.height as f32)
+    }
+}
+
+pub struct RenderStats {
+    /// Samples per pixel rendered, which progressive renders may stop short of, and the most
+    /// samples taken in a pixel by adaptive renders.
+    pub spp: usize,
+    /// Samples taken in all of the pixels.
+    pub sample_count: usize,
+    pub ray_count: usize,
+    /// BVH nodes and triangles visited by all rays, only counted by the heatmap mode.
+    pub traversal: TraversalCounts,
+}
+
+/// Linear radiance, stored row by row starting from the top left pixel.
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![Vec3::zero(); width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[x + y * self.width]
+    }
+
+    /// Gamma corrected and saturated 8 bits RGB data.
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let mut img_data = vec![0u8; 3 * self.width * self.height];
+        img_data
+            .chunks_mut(3)
+            .zip(self.pixels.iter())
+            .for_each(|(rgb, pixel)| {
+                let color = gamma_correction(*pixel);
+                // saturate
+                let color_0 = Vec3::fill(0.0);
+                let color_1 = Vec3::fill(1.0);
+                let color = Vec3::max(&color_0, &Vec3::min(&color_1, &color));
+                for (idx, channel) in rgb.iter_mut().enumerate() {
+                    *channel = (255.0 * color.get(idx)) as u8;
+                }
+            });
+        return img_data;
+    }
+
+    pub fn write_ppm(&self, filename: &str) {
+        ppm_writer::write(filename, self.width, self.height, &self.to_rgb8());
+    }
+}
+
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+/// Linear color of the heatmap for `visits`.
+fn heatmap_color(visits: f32) -> Vec3 {
+    ramp_color(visits / HEATMAP_MAX_VISITS as f32)
+}
+
+/// Linear color going through blue, cyan, green, yellow and red as `x` goes from 0 to 1.
+fn ramp_color(x: f32) -> Vec3 {
+    let ramp = [
+        Vec3::new(0.0, 0.0, 1.0),
+        Vec3::new(0.0, 1.0, 1.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        Vec3::new(1.0, 1.0, 0.0),
+        Vec3::new(1.0, 0.0, 0.0),
+    ];
+    let x = x.clamp(0.0, 1.0) * (ramp.len() - 1) as f32;
+    let idx = (x as usize).min(ramp.len() - 2);
+    let fraction = x - idx as f32;
+    let color = ramp[idx] * (1.0 - fraction) + ramp[idx + 1] * fraction;
+    // undo the gamma correction of the output
+    return color * color;
+}
+
+fn gamma_correction(color: Vec3) -> Vec3 {
+    let mut result = [0f32; 3];
+    for (idx, channel) in result.iter_mut().enumerate() {
+        *channel = color.get(idx).sqrt();
+    }
+    return Vec3::from(result);
+}
+
+/// Split the image in tiles of `tile_length` pixels of side. Tiles on the right and bottom
+/// borders are clamped to the image when its size is not a multiple of `tile_length`.
+fn make_tiles(width: usize, height: usize, tile_length: usize) -> Vec<Tile> {
+    let mut tiles = Vec::new();
+    for y in (0..height).step_by(tile_length) {
+        for x in (0..width).step_by(tile_length) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: tile_length.min(width - x),
+                height: tile_length.min(height - y),
+            });
+        }
+    }
+    return tiles;
+}
+
+/// Rays cast, and BVH nodes and triangles visited, shared by the tasks of a render.
+#[derive(Default)]
+struct Counters {
+    samples: AtomicUsize,
+    rays: AtomicUsize,
+    nodes: AtomicUsize,
+    triangles: AtomicUsize,
+}
+
+impl Counters {
+    fn stats(&self, spp: usize) -> RenderStats {
+        RenderStats {
+            spp,
+            sample_count: self.samples.load(Ordering::SeqCst),
+            ray_count: self.rays.load(Ordering::SeqCst),
+            traversal: TraversalCounts {
+                nodes: self.nodes.load(Ordering::SeqCst),
+                triangles: self.triangles.load(Ordering::SeqCst),
+            },
+        }
+    }
+}
+
+/// Seed of the random numbers of the tile `tile_idx` during the pass `pass`.
+fn tile_seed(tile_idx: usize, pass: usize) -> u32 {
+    let seed = (tile_idx as u32).wrapping_mul(9781).wrapping_add(1);
+    // xorshift never leaves 0
+    seed.wrapping_add((pass as u32).wrapping_mul(0x9E3779B9))
+        .max(1)
+}
+
+/// Samples taken in a pixel: their sum, and the running mean and variance of their luminance,
+/// updated with Welford's method.
+#[derive(Clone, Copy)]
+struct PixelEstimate {
+    sum: Vec3,
+    count: usize,
+    mean: f32,
+    /// Sum of the squared differences to the mean.
+    m2: f32,
+}
+
+/// Luminance under which the error of the pixels is measured relative to this value instead,
+/// so that the dark pixels do not need endless samples.
+const MIN_ERROR_LUMINANCE: f32 = 0.01;
+
+impl Default for PixelEstimate {
+    fn default() -> PixelEstimate {
+        PixelEstimate {
+            sum: Vec3::zero(),
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+}
+
+impl PixelEstimate {
+    fn add(&mut self, sample: &Vec3) {
+        self.sum = self.sum + *sample;
+        self.count += 1;
+        let luminance = sample.luminance();
+        let delta = luminance - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    /// Standard error of the mean luminance, relative to the luminance.
+    fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let count = self.count as f32;
+        let variance = self.m2.max(0.0) / (count - 1.0);
+        (variance / count).sqrt() / self.mean.max(MIN_ERROR_LUMINANCE)
+    }
+}
+
+/// Add `spp` samples to each pixel of `tile`, whose estimates are stored row by row in
+/// `block`.
+#[allow(clippy::too_many_arguments)]
+fn render_tile(
+    tile: &Tile,
+    rng_state: &mut u32,
+    spp: usize,
+    scene: &Scene,
+    camera: &Camera,
+    settings: &RenderSettings,
+    counters: &Counters,
+    block: &mut [PixelEstimate],
+) {
+    let inv_width = 1.0f32 / (settings.width as f32);
+    let inv_height = 1.0f32 / (settings.height as f32);
+    block.iter_mut().enumerate().for_each(|(idx, pixel)| {
+        let y = tile.y + idx / tile.width;
+        let x = tile.x + idx % tile.width;
+        for _s in 0..spp {
+            let u = (x as f32 + random::random_float01(rng_state)) * inv_width;
+            let v = 1.0 - (y as f32 + random::random_float01(rng_state)) * inv_height;
+            let sample = match camera.get_ray(u, v, rng_state) {
+                // black outside of the image of the camera
+                None => Vec3::zero(),
+                Some(ray) if settings.mode == RenderMode::Heatmap => {
+                    let counts = scene::traversal_counts(&ray, scene);
+                    counters.rays.fetch_add(1, Ordering::SeqCst);
+                    counters.nodes.fetch_add(counts.nodes, Ordering::SeqCst);
+                    counters
+                        .triangles
+                        .fetch_add(counts.triangles, Ordering::SeqCst);
+                    heatmap_color((counts.nodes + counts.triangles) as f32)
+                }
+                Some(ray) => {
+                    let (ray_color, ray_count) =
+                        scene::trace(&ray, settings.max_depth, rng_state, scene);
+                    counters.rays.fetch_add(ray_count, Ordering::SeqCst);
+                    ray_color
+                }
+            };
+            pixel.add(&sample);
+        }
+    });
+    counters
+        .samples
+        .fetch_add(spp * block.len(), Ordering::SeqCst);
+}
+
+/// Copy the pixels of each tile into the `width` pixels wide rows of `pixels`, applying `op`.
+fn scatter_tiles<T, P, F: Fn(&mut P, &T)>(
+    tiles: &[Tile],
+    tile_data: &[Vec<T>],
+    width: usize,
+    pixels: &mut [P],
+    op: F,
+) {
+    tiles
+        .iter()
+        .zip(tile_data.iter())
+        .for_each(|(tile, block)| {
+            block.iter().enumerate().for_each(|(idx, pixel)| {
+                let y = tile.y + idx / tile.width;
+                let x = tile.x + idx % tile.width;
+                op(&mut pixels[x + y * width], pixel);
+            });
+        });
+}
+
+pub fn render(scene: &Scene, camera: &Camera, settings: &RenderSettings) -> Image {
+    render_with_stats(scene, camera, settings).0
+}
+
+pub fn render_with_stats(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &RenderSettings,
+) -> (Image, RenderStats) {
+    let spp = settings.spp;
+    let tiles = make_tiles(settings.width, settings.height, settings.tile_size);
+    let counters = Counters::default();
+    let tile_data: Vec<Vec<PixelEstimate>> = tiles
+        .par_iter()
+        .enumerate()
+        .map(|(tile_idx, tile)| {
+            let mut rng_state = tile_seed(tile_idx, 0);
+            let mut block = vec![PixelEstimate::default(); tile.width * tile.height];
+            render_tile(
+                tile,
+                &mut rng_state,
+                spp,
+                scene,
+                camera,
+                settings,
+                &counters,
+                &mut block,
+            );
+            block
+        })
+        .collect();
+
+    let mut image = Image::new(settings.width, settings.height);
+    let spp_inv = 1.0 / (spp as f32);
+    scatter_tiles(
+        &tiles,
+        &tile_data,
+        settings.width,
+        &mut image.pixels,
+        |pixel, estimate| *pixel = estimate.sum * spp_inv,
+    );
+    return (image, counters.stats(spp));
+}
+
+/// Running sums of the samples of each pixel, to which progressive rendering adds one sample
+/// per pixel at each pass over the whole image.
+pub struct Accumulator {
+    width: usize,
+    height: usize,
+    sums: Vec<Vec3>,
+    passes: usize,
+    counters: Counters,
+}
+
+impl Accumulator {
+    pub fn new(width: usize, height: usize) -> Accumulator {
+        Accumulator {
+            width,
+            height,
+            sums: vec![Vec3::zero(); width * height],
+            passes: 0,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Samples accumulated in each pixel.
+    pub fn passes(&self) -> usize {
+        self.passes
+    }
+
+    /// Add a sample to each pixel. `settings` must keep the size the accumulator was created
+    /// with.
+    pub fn add_pass(&mut self, scene: &Scene, camera: &Camera, settings: &RenderSettings) {
+        assert_eq!((self.width, self.height), (settings.width, settings.height));
+        let tiles = make_tiles(self.width, self.height, settings.tile_size);
+        let pass = self.passes;
+        let counters = &self.counters;
+        let tile_data: Vec<Vec<PixelEstimate>> = tiles
+            .par_iter()
+            .enumerate()
+            .map(|(tile_idx, tile)| {
+                let mut rng_state = tile_seed(tile_idx, pass);
+                let mut block = vec![PixelEstimate::default(); tile.width * tile.height];
+                render_tile(
+                    tile,
+                    &mut rng_state,
+                    1,
+                    scene,
+                    camera,
+                    settings,
+                    counters,
+                    &mut block,
+                );
+                block
+            })
+            .collect();
+        scatter_tiles(
+            &tiles,
+            &tile_data,
+            self.width,
+            &mut self.sums,
+            |sum, sample| *sum = *sum + sample.sum,
+        );
+        self.passes += 1;
+    }
+
+    /// Average of the samples accumulated so far.
+    pub fn image(&self) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        let passes_inv = 1.0 / (self.passes.max(1) as f32);
+        for (pixel, sum) in image.pixels.iter_mut().zip(self.sums.iter()) {
+            *pixel = *sum * passes_inv;
+        }
+        return image;
+    }
+
+    pub fn stats(&self) -> RenderStats {
+        self.counters.stats(self.passes)
+    }
+}
+
+/// When a progressive render stops, and when it shows its progress.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ProgressiveSettings {
+    /// Stop after the pass which exceeds this duration, even before reaching the samples per
+    /// pixel of the render settings.
+    pub time_limit: Option<Duration>,
+    /// Show the image after the first pass ending this long after the previous preview.
+    pub preview_interval: Option<Duration>,
+    /// Show the image every this many passes.
+    pub preview_passes: Option<usize>,
+}
+
+/// Render one sample per pixel at a time, until reaching the samples per pixel of `settings`
+/// or the time limit of `progressive`. `preview` is called with the image rendered so far and
+/// its number of samples per pixel as often as `progressive` asks, but not after the last pass.
+pub fn render_progressive<F: FnMut(&Image, usize)>(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &RenderSettings,
+    progressive: &ProgressiveSettings,
+    mut preview: F,
+) -> (Image, RenderStats) {
+    let begin = Instant::now();
+    let mut last_preview = begin;
+    let mut accumulator = Accumulator::new(settings.width, settings.height);
+    loop {
+        accumulator.add_pass(scene, camera, settings);
+        let passes = accumulator.passes();
+        let now = Instant::now();
+        let elapsed = now.duration_since(begin);
+        let out_of_time = progressive.time_limit.is_some_and(|limit| elapsed >= limit);
+        if passes >= settings.spp || out_of_time {
+            break;
+        }
+        let since_preview = now.duration_since(last_preview);
+        let preview_due = progressive
+            .preview_passes
+            .is_some_and(|every| passes.is_multiple_of(every))
+            || progressive
+                .preview_interval
+                .is_some_and(|interval| since_preview >= interval);
+        if preview_due {
+            preview(&accumulator.image(), passes);
+            last_preview = Instant::now();
+        }
+    }
+    return (accumulator.image(), accumulator.stats());
+}
+
+/// Sample counts of an adaptive render.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveSettings {
+    /// Samples taken in every pixel, before measuring their error.
+    pub min_spp: usize,
+    /// Samples after which a pixel is left alone, whatever its error.
+    pub max_spp: usize,
+    /// Standard error of the mean luminance of the pixels, relative to their luminance, under
+    /// which a tile is done.
+    pub error_threshold: f32,
+}
+
+impl Default for AdaptiveSettings {
+    fn default() -> AdaptiveSettings {
+        AdaptiveSettings {
+            min_spp: 4,
+            max_spp: 256,
+            error_threshold: 0.02,
+        }
+    }
+}
+
+/// Number of samples taken in each pixel, stored row by row starting from the top left pixel.
+pub struct SampleMap {
+    pub width: usize,
+    pub height: usize,
+    pub counts: Vec<usize>,
+}
+
+impl SampleMap {
+    pub fn get(&self, x: usize, y: usize) -> usize {
+        self.counts[x + y * self.width]
+    }
+
+    /// Counts colored from blue for none to red for `max_spp`, through cyan, green and yellow.
+    pub fn to_image(&self, max_spp: usize) -> Image {
+        let mut image = Image::new(self.width, self.height);
+        for (pixel, count) in image.pixels.iter_mut().zip(self.counts.iter()) {
+            *pixel = ramp_color(*count as f32 / max_spp.max(1) as f32);
+        }
+        return image;
+    }
+}
+
+/// Tile of an adaptive render, refined until its error is low enough.
+struct AdaptiveTile {
+    rng_state: u32,
+    spp: usize,
+    estimates: Vec<PixelEstimate>,
+    done: bool,
+}
+
+impl AdaptiveTile {
+    /// Mean relative error of the pixels.
+    fn error(&self) -> f32 {
+        let sum: f32 = self
+            .estimates
+            .iter()
+            .map(|estimate| estimate.relative_error())
+            .sum();
+        sum / self.estimates.len() as f32
+    }
+}
+
+/// Render `adaptive.min_spp` samples in every pixel, then keep adding samples to the tiles
+/// whose error is above `adaptive.error_threshold`, until they are below it or reach
+/// `adaptive.max_spp`. Each refinement takes half as many samples as the tile already has, so
+/// that a tile is measured a few times as its error halves. The samples per pixel of
+/// `settings` are not used.
+pub fn render_adaptive(
+    scene: &Scene,
+    camera: &Camera,
+    settings: &RenderSettings,
+    adaptive: &AdaptiveSettings,
+) -> (Image, RenderStats, SampleMap) {
+    let min_spp = adaptive.min_spp.max(1);
+    let max_spp = adaptive.max_spp.max(min_spp);
+    let tiles = make_tiles(settings.width, settings.height, settings.tile_size);
+    let counters = Counters::default();
+    let mut states: Vec<AdaptiveTile> = tiles
+        .iter()
+        .enumerate()
+        .map(|(tile_idx, tile)| AdaptiveTile {
+            rng_state: tile_seed(tile_idx, 0),
+            spp: 0,
+            estimates: vec![PixelEstimate::default(); tile.width * tile.height],
+            done: false,
+        })
+        .collect();
+    while states.iter().any(|state| !state.done) {
+        states
+            .par_iter_mut()
+            .zip(tiles.par_iter())
+            .filter(|(state, _)| !state.done)
+            .for_each(|(state, tile)| {
+                let batch = if state.spp == 0 {
+                    min_spp
+                } else {
+                    (state.spp / 2).clamp(1, max_spp - state.spp)
+                };
+                render_tile(
+                    tile,
+                    &mut state.rng_state,
+                    batch,
+                    scene,
+                    camera,
+                    settings,
+                    &counters,
+                    &mut state.estimates,
+                );
+                state.spp += batch;
+                state.done = state.spp >= max_spp || state.error() <= adaptive.error_threshold;
+            });
+    }
+
+    let tile_data: Vec<Vec<PixelEstimate>> =
+        states.into_iter().map(|state| state.estimates).collect();
+    let mut image = Image::new(settings.width, settings.height);
+    scatter_tiles(
+        &tiles,
+        &tile_data,
+        settings.width,
+        &mut image.pixels,
+        |pixel, estimate| *pixel = estimate.sum * (1.0 / estimate.count as f32),
+    );
+    let mut samples = SampleMap {
+        width: settings.width,
+        height: settings.height,
+        counts: vec![0; settings.width * settings.height],
+    };
+    scatter_tiles(
+        &tiles,
+        &tile_data,
+        settings.width,
+        &mut samples.counts,
+        |count, estimate| *count = estimate.count,
+    );
+    let max_tile_spp = samples.counts.iter().copied().max().unwrap_or(0);
+    return (image, counters.stats(max_tile_spp), samples);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::light::Light;
+    use crate::triangle::Triangle;
+
+    #[test]
+    fn tiles_cover_image() {
+        let (width, height) = (21, 10);
+        let mut covered = vec![0; width * height];
+        for tile in make_tiles(width, height, 8) {
+            for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    covered[x + y * width] += 1;
+                }
+            }
+        }
+        assert!(covered.iter().all(|&count| count == 1));
+    }
+
+    #[test]
+    fn render_triangle() {
+        let scene = Scene::new(vec![Triangle::new(
+            Vec3::new(-1.0, -1.0, 0.0),
+            Vec3::new(1.0, -1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        )]);
+        let settings = RenderSettings {
+            width: 13,
+            height: 7,
+            spp: 2,
+            max_depth: 1,
+            tile_size: 4,
+            mode: RenderMode::Shaded,
+        };
+        let camera = Camera::look_at(
+            &Vec3::new(0.0, 0.0, 3.0),
+            &Vec3::zero(),
+            &Vec3::new(0.0, 1.0, 0.0),
+            60.0,
+            settings.aspect(),
+            0.0,
+            3.0,
+        );
+        let image = render(&scene, &camera, &settings);
+        assert_eq!(13 * 7, image.pixels.len());
+        assert_eq!(3 * 13 * 7, image.to_rgb8().len());
+        assert!(image.pixels.iter().all(|pixel| pixel.length().is_finite()));
+        // the gray triangle reflects light from the sky as well as the sun
+        assert!(image.get(6, 3).length() > 0.0);
+        assert_ne!(image.get(0, 0), image.get(6, 3));
+
+        // under a black sky, only the sun lights the triangle
+        let sun_only = Scene::from_parts(
+            scene.triangle_list.clone(),
+            vec![scene::default_material()],
+            vec![scene::default_sun()],
+            scene::Background::Constant(Vec3::zero()),
+        );
+        let image = render(&sun_only, &camera, &settings);
+        assert_eq!(Vec3::zero(), image.get(0, 0));
+        let sun = scene::default_sun()
+            .illuminate(&Vec3::zero(), &mut 1)
+            .unwrap();
+        let expected = 0.7 * 0.7 * sun.direction.z();
+        assert!((image.get(6, 3) - Vec3::fill(expected)).length() < 1e-3);
+
+        let settings = RenderSettings {
+            mode: RenderMode::Heatmap,
+            ..settings
+        };
+        let (image, stats) = render_with_stats(&scene, &camera, &settings);
+        assert_eq!(13 * 7 * 2, stats.ray_count);
+        // a lone triangle is a single leaf of the root, which every ray enters
+        assert_eq!(stats.ray_count, stats.traversal.nodes);
+        assert!(stats.traversal.triangles > 0);
+        assert_eq!(heatmap_color(1.0), image.get(0, 0));
+        assert_ne!(image.get(0, 0), image.get(6, 3));
+    }
+
+    #[test]
+    fn progressive() {
+        let triangle = Triangle::new(
+            Vec3::new(-1.0, -1.0, 0.0),
+            Vec3::new(1.0, -1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        let scene = Scene::from_parts(
+            vec![triangle],
+            vec![scene::default_material()],
+            vec![scene::default_sun()],
+            scene::Background::Constant(Vec3::fill(0.5)),
+        );
+        let settings = RenderSettings {
+            width: 9,
+            height: 5,
+            spp: 4,
+            max_depth: 1,
+            tile_size: 4,
+            mode: RenderMode::Shaded,
+        };
+        let camera = Camera::look_at(
+            &Vec3::new(0.0, 0.0, 3.0),
+            &Vec3::zero(),
+            &Vec3::new(0.0, 1.0, 0.0),
+            60.0,
+            settings.aspect(),
+            0.0,
+            3.0,
+        );
+        let mut previews = Vec::new();
+        let progressive = ProgressiveSettings {
+            preview_passes: Some(1),
+            ..ProgressiveSettings::default()
+        };
+        let (image, stats) =
+            render_progressive(&scene, &camera, &settings, &progressive, |image, spp| {
+                previews.push((image.get(0, 0), spp))
+            });
+        // previews of the passes before the last one
+        assert_eq!(
+            vec![1, 2, 3],
+            previews.iter().map(|(_, spp)| *spp).collect::<Vec<_>>()
+        );
+        assert!(previews.iter().all(|(sky, _)| *sky == Vec3::fill(0.5)));
+        assert_eq!(4, stats.spp);
+        assert!(stats.ray_count >= 9 * 5 * 4);
+        assert_eq!(Vec3::fill(0.5), image.get(0, 0));
+        // the accumulated passes converge like a single render
+        let settings = RenderSettings {
+            spp: 64,
+            ..settings
+        };
+        let reference = render(&scene, &camera, &settings);
+        let (image, _) = render_progressive(
+            &scene,
+            &camera,
+            &settings,
+            &ProgressiveSettings::default(),
+            |_, _| panic!("no preview asked"),
+        );
+        assert!((image.get(4, 2) - reference.get(4, 2)).length() < 0.05);
+
+        let out_of_time = ProgressiveSettings {
+            time_limit: Some(Duration::from_secs(0)),
+            ..ProgressiveSettings::default()
+        };
+        let (_, stats) = render_progressive(&scene, &camera, &settings, &out_of_time, |_, _| {});
+        assert_eq!(1, stats.spp);
+    }
+
+    #[test]
+    fn welford() {
+        let mut estimate = PixelEstimate::default();
+        assert_eq!(f32::INFINITY, estimate.relative_error());
+        let samples = [1.0, 3.0, 2.0, 6.0];
+        for sample in samples.iter() {
+            estimate.add(&Vec3::fill(*sample));
+        }
+        assert_eq!(Vec3::fill(12.0), estimate.sum);
+        assert!((estimate.mean - 3.0).abs() < 1e-5);
+        // sample variance of 14 / 3, over 4 samples
+        let expected = (14.0f32 / 3.0 / 4.0).sqrt() / 3.0;
+        assert!((estimate.relative_error() - expected).abs() < 1e-5);
+    }
+
+    #[test]
+    fn adaptive() {
+        let triangle = Triangle::new(
+            Vec3::new(-1.0, -1.0, 0.0),
+            Vec3::new(1.0, -1.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        // the flat sky converges at once, while the triangle is noisy under a wide light grazing
+        // it
+        let light = Light::directional(&Vec3::new(1.0, 0.0, 0.2), &Vec3::fill(1.0), 0.5);
+        let scene = Scene::from_parts(
+            vec![triangle],
+            vec![scene::default_material()],
+            vec![light],
+            scene::Background::Constant(Vec3::fill(0.5)),
+        );
+        let settings = RenderSettings {
+            width: 16,
+            height: 8,
+            spp: 1,
+            max_depth: 2,
+            tile_size: 4,
+            mode: RenderMode::Shaded,
+        };
+        let camera = Camera::look_at(
+            &Vec3::new(0.0, 0.0, 2.0),
+            &Vec3::zero(),
+            &Vec3::new(0.0, 1.0, 0.0),
+            90.0,
+            settings.aspect(),
+            0.0,
+            2.0,
+        );
+        // only the pixels without any noise stop at the minimum without threshold
+        let adaptive = AdaptiveSettings {
+            min_spp: 4,
+            max_spp: 64,
+            error_threshold: 0.0,
+        };
+        let (image, stats, samples) = render_adaptive(&scene, &camera, &settings, &adaptive);
+        assert_eq!(4, samples.get(0, 0));
+        assert_eq!(Vec3::fill(0.5), image.get(0, 0));
+        assert_eq!(64, samples.get(8, 4));
+        assert_eq!(64, stats.spp);
+        assert_eq!(samples.counts.iter().sum::<usize>(), stats.sample_count);
+        let settings_64 = RenderSettings {
+            spp: 64,
+            ..settings
+        };
+        let reference = render(&scene, &camera, &settings_64);
+        // converged like a render taking as many samples, around the center
+        let mut difference = Vec3::zero();
+        for y in 3..5 {
+            for x in 6..10 {
+                difference = difference + image.get(x, y) - reference.get(x, y);
+            }
+        }
+        assert!(difference.length() / 8.0 < 0.03);
+        let map = samples.to_image(adaptive.max_spp);
+        assert_eq!(ramp_color(1.0), map.get(8, 4));
+        assert_eq!(ramp_color(4.0 / 64.0), map.get(0, 0));
+
+        let adaptive = AdaptiveSettings {
+            error_threshold: 0.01,
+            ..adaptive
+        };
+        let (_, stats, samples) = render_adaptive(&scene, &camera, &settings, &adaptive);
+        assert!(samples.get(8, 4) > 4 && samples.get(8, 4) < 64);
+        assert!(stats.sample_count < 64 * 16 * 8 / 2);
+    }
+
+    #[test]
+    fn heatmap_ramp() {
+        assert_eq!(Vec3::new(0.0, 0.0, 1.0), heatmap_color(0.0));
+        assert_eq!(Vec3::new(0.0, 1.0, 0.0), heatmap_color(100.0));
+        assert_eq!(Vec3::new(1.0, 0.0, 0.0), heatmap_color(1000.0));
+    }
+}
@@ -1,12 +1,211 @@
 use crate::bvh::*;
+use crate::environment::EnvironmentMap;
 use crate::hit::*;
+use crate::light::*;
+use crate::material::*;
+use crate::obj_loader::ObjMesh;
+use crate::random::random_float01;
 use crate::ray::*;
+use crate::scene_file;
 use crate::triangle::*;
 use crate::vec3::*;
+use crate::wide_bvh::*;
+use core::f32::consts::PI;
+
+/// Color returned by the rays escaping the scene.
+pub enum Background {
+    Constant(Vec3),
+    /// Vertical gradient, blended on the y component of the ray direction.
+    Gradient {
+        bottom: Vec3,
+        top: Vec3,
+    },
+    /// HDR image, typically a captured environment.
+    Map(EnvironmentMap),
+}
+
+impl Background {
+    pub fn color(&self, dir: &Vec3) -> Vec3 {
+        match self {
+            Background::Constant(color) => *color,
+            Background::Gradient { bottom, top } => {
+                let t = 0.5 * (dir.y() + 1.0);
+                *bottom * (1.0 - t) + *top * t
+            }
+            Background::Map(map) => map.radiance(dir),
+        }
+    }
+
+    /// Direction sampled to light the scene with the background, with its probability density
+    /// per unit solid angle. Environment maps are importance sampled, the other backgrounds
+    /// uniformly.
+    pub fn sample(&self, rng_state: &mut u32) -> (Vec3, f32) {
+        if let Background::Map(map) = self {
+            return map.sample(rng_state);
+        }
+        let z = 1.0 - 2.0 * random_float01(rng_state);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * random_float01(rng_state);
+        (Vec3::new(r * phi.cos(), r * phi.sin(), z), 1.0 / (4.0 * PI))
+    }
+
+    /// Probability density of `sample` returning `dir`.
+    pub fn pdf(&self, dir: &Vec3) -> f32 {
+        match self {
+            Background::Map(map) => map.pdf(dir),
+            _ => 1.0 / (4.0 * PI),
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Background {
+        Background::Gradient {
+            bottom: Vec3::new(1.0, 1.0, 1.0),
+            top: Vec3::new(0.25, 0.35, 0.5),
+        }
+    }
+}
 
 pub struct Scene {
     pub triangle_list: Vec<Triangle>,
     pub bvh: Option<Bvh>,
+    /// Collapsed from `bvh`, and used instead of it for tracing.
+    pub wide_bvh: Option<WideBvh>,
+    /// Indexed by `Triangle::material`.
+    pub materials: Vec<Box<dyn Material>>,
+    pub lights: Vec<Light>,
+    /// Triangles of emissive materials, built along with the BVH.
+    pub area_lights: AreaLights,
+    pub background: Background,
+}
+
+/// Gray clay used when nothing else is specified.
+pub fn default_material() -> Box<dyn Material> {
+    Box::new(Lambertian::new(&Vec3::fill(0.7)))
+}
+
+pub fn default_sun() -> Light {
+    Light::directional(&Vec3::from(LIGHT_DIR), &Vec3::fill(0.7), 0.0)
+}
+
+impl Scene {
+    /// Scene made of the default material, lit by the default sun and sky. The triangles all use
+    /// the default material, see `from_mesh` to keep the materials of a loaded mesh.
+    pub fn new(mut triangle_list: Vec<Triangle>) -> Scene {
+        for triangle in triangle_list.iter_mut() {
+            triangle.material = 0;
+        }
+        Scene::from_parts(
+            triangle_list,
+            vec![default_material()],
+            vec![default_sun()],
+            Background::default(),
+        )
+    }
+
+    /// Scene of a loaded mesh with the materials of its MTL libraries, lit by the default sun
+    /// and sky. Faces without material use the default one. Texture maps that cannot be loaded
+    /// are left out silently, `SceneDescription::from_mesh` reports them.
+    pub fn from_mesh(mesh: &ObjMesh) -> Scene {
+        let (triangle_list, materials) = scene_file::mesh_parts(mesh, &mut Vec::new());
+        Scene::from_parts(
+            triangle_list,
+            materials,
+            vec![default_sun()],
+            Background::default(),
+        )
+    }
+
+    /// # Panics
+    /// When a triangle uses a material out of `materials`.
+    pub fn from_parts(
+        mut triangle_list: Vec<Triangle>,
+        materials: Vec<Box<dyn Material>>,
+        lights: Vec<Light>,
+        background: Background,
+    ) -> Scene {
+        let bvh = Bvh::create(&mut triangle_list[..]);
+        Scene::with_bvh(triangle_list, bvh, materials, lights, background)
+    }
+
+    /// Same as `from_parts`, with a tree already built for `triangle_list`, as loaded from a
+    /// `bvh_cache`.
+    ///
+    /// # Panics
+    /// When a triangle uses a material out of `materials`.
+    pub fn with_bvh(
+        triangle_list: Vec<Triangle>,
+        bvh: Bvh,
+        materials: Vec<Box<dyn Material>>,
+        lights: Vec<Light>,
+        background: Background,
+    ) -> Scene {
+        assert!(
+            triangle_list
+                .iter()
+                .all(|triangle| triangle.material < materials.len()),
+            "triangle material out of the scene materials"
+        );
+        let wide_bvh = WideBvh::from_bvh(&bvh);
+        let area_lights = AreaLights::from_triangles(&triangle_list, &materials);
+        Scene {
+            triangle_list,
+            bvh: Some(bvh),
+            wide_bvh: Some(wide_bvh),
+            materials,
+            lights,
+            area_lights,
+            background,
+        }
+    }
+
+    /// Axis aligned bounds of the scene, as a (min, max) pair.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        compute_boundary(&self.triangle_list)
+    }
+}
+
+/// Two triangles right "under the scene" and covering a larger area than the scene itself, to
+/// serve as a "floor".
+pub fn make_floor(scene_min: &Vec3, scene_max: &Vec3) -> [Triangle; 2] {
+    let floor_size = (*scene_max - *scene_min) * 0.7;
+    let v0 = Vec3::new(
+        scene_min.x() - floor_size.x(),
+        scene_min.y(),
+        scene_min.z() - floor_size.z(),
+    );
+    let v1 = Vec3::new(
+        scene_min.x() - floor_size.x(),
+        scene_min.y(),
+        scene_max.z() + floor_size.z(),
+    );
+    let v2 = Vec3::new(
+        scene_max.x() + floor_size.x(),
+        scene_min.y(),
+        scene_min.z() - floor_size.z(),
+    );
+    let v3 = Vec3::new(
+        scene_max.x() + floor_size.x(),
+        scene_min.y(),
+        scene_max.z() + floor_size.z(),
+    );
+    [Triangle::new(v0, v1, v2), Triangle::new(v1, v3, v2)]
+}
+
+pub fn compute_boundary(triangle_list: &[Triangle]) -> (Vec3, Vec3) {
+    let compute_triangle_boundary = |(min_b, max_b), triangle: &Triangle| {
+        triangle
+            .vertices
+            .iter()
+            .fold((min_b, max_b), |(min_tr, max_tr), vertice| {
+                (Vec3::min(&min_tr, vertice), Vec3::max(&max_tr, vertice))
+            })
+    };
+    triangle_list.iter().fold(
+        (Vec3::max_value(), Vec3::min_value()),
+        compute_triangle_boundary,
+    )
 }
 
 const RAY_MIN: f32 = 0.01;
@@ -14,65 +213,319 @@ const RAY_MAX: f32 = 100.0;
 const LIGHT_DIR: [f32; 3] = [-0.5301519, 0.758786, 0.378395];
 
 fn hit_scene(ray: &Ray, min_t: f32, max_t: f32, hit_type: HitType, scene: &Scene) -> Option<Hit> {
-    if scene.bvh.is_some() {
-        return scene
-            .bvh
-            .as_ref()
-            .unwrap()
-            .intersect(ray, min_t, max_t, hit_type, &scene.triangle_list[..]);
+    if let Some(wide_bvh) = scene.wide_bvh.as_ref() {
+        return wide_bvh.intersect(ray, min_t, max_t, hit_type, &scene.triangle_list[..]);
+    }
+    if let Some(bvh) = scene.bvh.as_ref() {
+        return bvh.intersect(ray, min_t, max_t, hit_type, &scene.triangle_list[..]);
     }
     let mut min_distance = max_t;
-    let mut best_hit: Option<Hit> = None;
+    let mut best_hit = None;
     for triangle in scene.triangle_list.iter() {
-        let hit = triangle.intersect(ray, min_t, max_t);
-        if hit.is_none() {
-            continue;
-        }
-        let hit = hit.unwrap();
-        if hit.t < min_distance {
-            min_distance = hit.t;
-            best_hit = Some(hit);
+        if let Some(hit) = triangle.intersect_distance(ray, min_t, min_distance) {
             if let HitType::Any = hit_type {
-                return best_hit;
+                return Some(triangle.hit(ray, &hit));
             }
+            min_distance = hit.t;
+            best_hit = Some((triangle, hit));
         }
     }
-    return best_hit;
+    return best_hit.map(|(triangle, hit)| triangle.hit(ray, &hit));
+}
+
+/// Closest hit of `ray` in the range of distances covered by the paths.
+pub fn intersect(ray: &Ray, scene: &Scene) -> Option<Hit> {
+    hit_scene(ray, RAY_MIN, RAY_MAX, HitType::Closest, scene)
+}
+
+/// Work done to find the closest hit of `ray`, for the heatmap render mode. Counted in the tree
+/// used to render, the 4-wide one when it is built.
+pub fn traversal_counts(ray: &Ray, scene: &Scene) -> TraversalCounts {
+    let mut counts = TraversalCounts::default();
+    let triangles = &scene.triangle_list[..];
+    if let Some(wide_bvh) = scene.wide_bvh.as_ref() {
+        wide_bvh.intersect_counted(
+            ray,
+            RAY_MIN,
+            RAY_MAX,
+            HitType::Closest,
+            triangles,
+            &mut counts,
+        );
+    } else if let Some(bvh) = scene.bvh.as_ref() {
+        bvh.intersect_counted(
+            ray,
+            RAY_MIN,
+            RAY_MAX,
+            HitType::Closest,
+            triangles,
+            &mut counts,
+        );
+    }
+    return counts;
 }
 
-fn scatter(ray: &Ray, hit: &Hit, rng_state: &mut u32, scene: &Scene) -> (Ray, Vec3) {
+/// Weight of a sample of probability density `pdf` combined with another sampling strategy of
+/// density `other_pdf` for the same direction, with the power heuristic of Veach.
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf_sq = pdf * pdf;
+    let sum = pdf_sq + other_pdf * other_pdf;
+    if sum > 0.0 {
+        pdf_sq / sum
+    } else {
+        0.0
+    }
+}
+
+/// Light received from the scene lights and reflected back along `ray`, along with the number
+/// of shadow rays cast. Each point, spot and directional light is sampled, while a single area
+/// light, picked by power, and a direction of the background are sampled and weighted against
+/// the BSDF sampling done by `trace_path`.
+fn direct_lighting(
+    ray: &Ray,
+    hit: &Hit,
+    material: &dyn Material,
+    rng_state: &mut u32,
+    scene: &Scene,
+) -> (Vec3, usize) {
     let mut light_ray = Vec3::zero();
-    let target = hit.normal + Vec3::rand_unit(rng_state);
-    let scattered = Ray::new(&hit.pos, &target.normalize());
-
-    if hit_scene(
-        &Ray::new(&hit.pos, &Vec3::from(LIGHT_DIR)),
-        RAY_MIN,
-        RAY_MAX,
-        HitType::Any,
-        scene,
-    )
-    .is_none()
-    {
-        let nl = hit.normal * Vec3::dot(&hit.normal, &ray.dir()).signum() * -1.0;
-        light_ray = Vec3::fill(0.7) * 0.0f32.max(Vec3::dot(&Vec3::from(LIGHT_DIR), &nl));
+    let mut shadow_ray_count = 0;
+    if !material.receives_lights() {
+        return (light_ray, shadow_ray_count);
+    }
+    let visible = |dir: &Vec3, max_t: f32, shadow_ray_count: &mut usize| {
+        *shadow_ray_count += 1;
+        hit_scene(
+            &Ray::new(&hit.pos, dir).with_time(ray.time()),
+            RAY_MIN,
+            max_t,
+            HitType::Any,
+            scene,
+        )
+        .is_none()
+    };
+
+    // lights which the BSDF sampling never finds
+    for light in scene.lights.iter() {
+        let lit = match light.illuminate(&hit.pos, rng_state) {
+            Some(lit) => lit,
+            None => continue,
+        };
+        let shaded = material.shade(ray, hit, &lit.direction, &lit.color);
+        let max_t = (lit.distance - RAY_MIN).min(RAY_MAX);
+        if shaded != Vec3::zero() && visible(&lit.direction, max_t, &mut shadow_ray_count) {
+            light_ray = light_ray + shaded;
+        }
+    }
+
+    if let Some(sample) = scene.area_lights.sample(&hit.pos, ray.time(), rng_state) {
+        let shaded = material.eval(ray, hit, &sample.direction) * sample.radiance;
+        if shaded != Vec3::zero()
+            && visible(
+                &sample.direction,
+                sample.distance - RAY_MIN,
+                &mut shadow_ray_count,
+            )
+        {
+            let bsdf_pdf = material.pdf(ray, hit, &sample.direction);
+            let weight = power_heuristic(sample.pdf, bsdf_pdf);
+            light_ray = light_ray + shaded * (weight / sample.pdf);
+        }
+    }
+
+    let (dir, env_pdf) = scene.background.sample(rng_state);
+    let shaded = material.eval(ray, hit, &dir) * scene.background.color(&dir);
+    if env_pdf > 0.0 && shaded != Vec3::zero() && visible(&dir, RAY_MAX, &mut shadow_ray_count) {
+        let weight = power_heuristic(env_pdf, material.pdf(ray, hit, &dir));
+        light_ray = light_ray + shaded * (weight / env_pdf);
     }
 
-    return (scattered, light_ray);
+    return (light_ray, shadow_ray_count);
 }
 
+/// Light coming back along `ray`, estimated with next event estimation and BSDF sampling,
+/// combined with multiple importance sampling. The path bounces at most `depth` times, the last
+/// ray only gathering the light it finds. Also returns the number of rays cast.
 pub fn trace(ray: &Ray, depth: usize, rng_state: &mut u32, scene: &Scene) -> (Vec3, usize) {
+    trace_path(ray, depth, None, rng_state, scene)
+}
+
+/// `bsdf_pdf` is the density the previous bounce sampled `ray` with, or `None` for the camera
+/// rays and the specular bounces, for which the lights hit are not sampled explicitly.
+fn trace_path(
+    ray: &Ray,
+    depth: usize,
+    bsdf_pdf: Option<f32>,
+    rng_state: &mut u32,
+    scene: &Scene,
+) -> (Vec3, usize) {
+    let hit = match hit_scene(ray, RAY_MIN, RAY_MAX, HitType::Closest, scene) {
+        Some(hit) => hit,
+        None => {
+            let background = scene.background.color(&ray.dir());
+            let weight = match bsdf_pdf {
+                Some(pdf) => power_heuristic(pdf, scene.background.pdf(&ray.dir())),
+                None => 1.0,
+            };
+            return (background * weight, 1);
+        }
+    };
+
+    let material = scene.materials[hit.material].as_ref();
+    let mut emitted = material.emitted(ray, &hit);
+    if let Some(pdf) = bsdf_pdf {
+        if emitted != Vec3::zero() && !scene.area_lights.is_empty() {
+            // density of the light sampling reaching the same point, in solid angle
+            let cos_light = Vec3::dot(&hit.normal, &ray.dir()).abs();
+            let light_pdf =
+                scene.area_lights.pdf_area(&material.emission()) * hit.t * hit.t / cos_light;
+            emitted = emitted * power_heuristic(pdf, light_pdf);
+        }
+    }
     if 0 == depth {
-        return (Vec3::zero(), 1);
+        return (emitted, 1);
     }
-    let hit = hit_scene(ray, RAY_MIN, RAY_MAX, HitType::Closest, scene);
-    if hit.is_some() {
-        let (ray_scatter, light_ray) = scatter(ray, &hit.unwrap(), rng_state, scene);
-        let (color, ray_count) = trace(&ray_scatter, depth - 1, rng_state, scene);
-        return (light_ray + color * 0.7, ray_count + 2);
-    } else {
-        let t = 0.5 * (ray.dir().y() + 1.0);
-        let color = Vec3::new(1.0, 1.0, 1.0) * (1.0 - t) + Vec3::new(0.5, 0.7, 1.0) * t * 0.5;
-        return (color, 1);
+
+    let (light_ray, shadow_ray_count) = direct_lighting(ray, &hit, material, rng_state, scene);
+    match material.scatter(ray, &hit, rng_state) {
+        Some(scatter) => {
+            let (color, ray_count) =
+                trace_path(&scatter.ray, depth - 1, scatter.pdf, rng_state, scene);
+            return (
+                emitted + light_ray + scatter.attenuation * color,
+                ray_count + 1 + shadow_ray_count,
+            );
+        }
+        None => return (emitted + light_ray, 1 + shadow_ray_count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Texture;
+
+    /// Reference estimator only following the BSDF samples, and finding the lights by chance.
+    fn trace_bsdf(ray: &Ray, depth: usize, rng_state: &mut u32, scene: &Scene) -> Vec3 {
+        let hit = match hit_scene(ray, RAY_MIN, RAY_MAX, HitType::Closest, scene) {
+            Some(hit) => hit,
+            None => return scene.background.color(&ray.dir()),
+        };
+        let material = scene.materials[hit.material].as_ref();
+        let emitted = material.emitted(ray, &hit);
+        if 0 == depth {
+            return emitted;
+        }
+        match material.scatter(ray, &hit, rng_state) {
+            Some(scatter) => {
+                emitted
+                    + scatter.attenuation * trace_bsdf(&scatter.ray, depth - 1, rng_state, scene)
+            }
+            None => emitted,
+        }
+    }
+
+    fn quad(center: &Vec3, half_size: f32, material: usize) -> [Triangle; 2] {
+        let corner = |dx: f32, dz: f32| *center + Vec3::new(dx, 0.0, dz) * half_size;
+        let mut triangles = [
+            Triangle::new(corner(-1.0, -1.0), corner(-1.0, 1.0), corner(1.0, -1.0)),
+            Triangle::new(corner(-1.0, 1.0), corner(1.0, 1.0), corner(1.0, -1.0)),
+        ];
+        for triangle in triangles.iter_mut() {
+            triangle.material = material;
+        }
+        triangles
+    }
+
+    /// Floor made of a lambertian and a rough metal half, under a small light.
+    fn test_scene(background: Background) -> Scene {
+        let materials: Vec<Box<dyn Material>> = vec![
+            Box::new(Lambertian::new(&Vec3::new(0.8, 0.5, 0.3))),
+            Box::new(Metal::new(&Vec3::fill(0.9), 0.3)),
+            Box::new(Emissive {
+                color: Vec3::new(1.0, 0.9, 0.8),
+                intensity: 8.0,
+            }),
+        ];
+        let mut triangles = Vec::new();
+        triangles.extend_from_slice(&quad(&Vec3::new(-1.0, 0.0, 0.0), 1.0, 0));
+        triangles.extend_from_slice(&quad(&Vec3::new(1.0, 0.0, 0.0), 1.0, 1));
+        triangles.extend_from_slice(&quad(&Vec3::new(0.0, 1.0, 0.0), 0.3, 2));
+        Scene::from_parts(triangles, materials, Vec::new(), background)
+    }
+
+    /// Compare the average of both estimators on both halves of the floor.
+    fn assert_matches_bsdf_sampling(scene: &Scene) {
+        let sample_count = 40000;
+        for target in [Vec3::new(-0.5, 0.0, 0.2), Vec3::new(0.5, 0.0, -0.2)] {
+            let origin = Vec3::new(0.0, 1.5, 1.5);
+            let ray = Ray::new(&origin, &(target - origin).normalize());
+            let mut mis = Vec3::zero();
+            let mut reference = Vec3::zero();
+            let mut rng_state = 0x12345678u32;
+            for _ in 0..sample_count {
+                mis = mis + trace(&ray, 3, &mut rng_state, scene).0;
+                reference = reference + trace_bsdf(&ray, 3, &mut rng_state, scene);
+            }
+            for idx in 0..3 {
+                let (mis, reference) = (mis.get(idx), reference.get(idx));
+                assert!(
+                    (mis - reference).abs() < 0.03 * reference,
+                    "MIS {} against {} with BSDF sampling",
+                    mis / sample_count as f32,
+                    reference / sample_count as f32
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mis_matches_bsdf_sampling() {
+        let scene = test_scene(Background::Constant(Vec3::fill(0.3)));
+        assert_eq!(2, scene.area_lights.lights.len());
+        assert_matches_bsdf_sampling(&scene);
+    }
+
+    #[test]
+    fn environment_map_matches_bsdf_sampling() {
+        // bright band above the horizon, on one side
+        let (width, height) = (8, 4);
+        let texels = (0..width * height)
+            .map(|idx| match (idx % width, idx / width) {
+                (2..=4, 1) => Vec3::new(4.0, 3.0, 2.0),
+                _ => Vec3::fill(0.1),
+            })
+            .collect();
+        let map = EnvironmentMap::new(
+            Texture::new(width, height, texels),
+            &Vec3::new(0.0, 30.0, 0.0),
+            1.5,
+        );
+        assert_matches_bsdf_sampling(&test_scene(Background::Map(map)));
+    }
+
+    #[test]
+    fn power_heuristic_weights() {
+        assert_eq!(0.5, power_heuristic(1.0, 1.0));
+        assert_eq!(0.8, power_heuristic(2.0, 1.0));
+        assert_eq!(1.0, power_heuristic(1.0, 0.0));
+        assert_eq!(0.0, power_heuristic(0.0, 0.0));
+    }
+
+    #[test]
+    fn from_mesh() {
+        let mesh = crate::obj_loader::parse_scene(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\nusemtl blue\nf 1 3 2\n",
+            std::path::Path::new(""),
+        )
+        .unwrap();
+        let scene = Scene::from_mesh(&mesh);
+        assert_eq!(3, scene.materials.len());
+        let mut materials: Vec<usize> = scene.triangle_list.iter().map(|t| t.material).collect();
+        materials.sort_unstable();
+        assert_eq!(vec![1, 2], materials);
+        // without the materials, everything uses the default one
+        let scene = Scene::new(mesh.triangles);
+        assert!(scene.triangle_list.iter().all(|t| t.material == 0));
     }
 }
@@ -0,0 +1,1314 @@
+//! Scene description files.
+//!
+//! A scene is described in TOML, next to the assets it references:
+//!
+//! ```toml
+//! floor = true
+//!
+//! [render]
+//! width = 640
+//! height = 360
+//! spp = 4
+//!
+//! [camera]
+//! look_from = [0.0, 1.0, 3.0]
+//! look_at = [0.0, 0.0, 0.0]
+//! vfov = 60.0
+//!
+//! [background]
+//! type = "gradient"
+//! bottom = [1.0, 1.0, 1.0]
+//! top = [0.25, 0.35, 0.5]
+//!
+//! [[lights]]
+//! type = "directional"
+//! direction = [-0.53, 0.76, 0.38]
+//! color = [0.7, 0.7, 0.7]
+//!
+//! [[materials]]
+//! name = "gold"
+//! type = "metal"
+//! albedo = [1.0, 0.78, 0.34]
+//! roughness = 0.2
+//!
+//! [[meshes]]
+//! file = "../data/suzanne.obj"
+//! material = "gold"
+//! translate = [0.0, 0.5, 0.0]
+//! rotate = [0.0, 45.0, 0.0]
+//! scale = 1.5
+//! ```
+//!
+//! Lights are `directional` (`direction` toward the light, `color`, and `angular_radius` in
+//! degrees to soften the shadows), `point` (`position`, `color`, `intensity`) or `spot`
+//! (`position`, `direction` of the cone, `color`, `intensity`, and the `inner_angle` and
+//! `outer_angle` half angles in degrees between which it fades out). A white light lights a
+//! white lambertian surface facing it in white, at a distance of 1 for the point and spot
+//! lights, whose light decreases with the square of the distance.
+//!
+//! The camera is a thin lens of diameter `aperture`, 0 by default for a pinhole camera, sharp at
+//! `focus_distance` from `look_from`, which defaults to the distance to `look_at`. `autofocus`
+//! focuses on the surface seen at the center of the image instead. The aperture is circular, or
+//! a polygon of `blades` sides rotated by `blade_rotation` degrees, and `squeeze` times as tall
+//! as wide, to mimic the oval out of focus highlights of anamorphic lenses.
+//!
+//! `projection` is `perspective` by default, `orthographic` (with `ortho_height` units of the
+//! scene from the bottom to the top of the image, the height of the perspective view at the
+//! focus distance by default), `fisheye` (equidistant, inscribed in the image, of `fisheye_fov`
+//! degrees, 180 by default) or `equirectangular` (a 360 degree panorama, at an aspect of 2).
+//! The aperture only applies to the perspective projection.
+//!
+//! Meshes can move during the exposure, for motion blur: their `motion` table gives the
+//! `translate`, `rotate` and `scale` they end up with at the time 1, each defaulting to the
+//! one of the mesh, at the time 0. Each of them is interpolated linearly between the two, so
+//! that a turning mesh keeps its shape. The camera shutter is open from `shutter_open` to
+//! `shutter_close`, within [0, 1] and both 0 by default, which leaves the scene still:
+//!
+//! ```toml
+//! [camera]
+//! look_from = [0.0, 1.0, 3.0]
+//! look_at = [0.0, 0.0, 0.0]
+//! shutter_close = 0.5
+//!
+//! [[meshes]]
+//! file = "../data/suzanne.obj"
+//!
+//! [meshes.motion]
+//! rotate = [0.0, 30.0, 0.0]
+//! ```
+//!
+//! Mesh paths are relative to the scene file. Meshes use the materials of their MTL libraries
+//! unless a `material` is given. Materials are `lambertian` (`albedo`), `metal`
+//! (`albedo`, `roughness`), `dielectric` (`ior`) or `emissive` (`color`, `intensity`). Meshes
+//! without material, and the floor, use the material named `default`, a gray lambertian unless
+//! redefined.
+//!
+//! The triangles of emissive materials are area lights: besides being seen by the paths bouncing
+//! on them, they are sampled explicitly from the lambertian and rough metal surfaces, the
+//! brightest ones more often.
+//!
+//! Lambertian and metal materials also take PNG or PPM texture maps, relative to the scene file:
+//! `albedo_map` multiplying the albedo, `normal_map` in tangent space and, for metals,
+//! `roughness_map` multiplying the roughness. `wrap` sets how they repeat outside of the
+//! texture coordinates [0, 1]: `repeat` (the default), `clamp` or `mirror`.
+//!
+//! The background is a `constant` `color`, a vertical `gradient` from `bottom` to `top`, or an
+//! `environment` map: an equirectangular Radiance HDR or OpenEXR `file`, relative to the scene
+//! file, which can be rotated with Euler angles in degrees (`rotate`) and scaled (`intensity`).
+//! Its center faces -z and its top +y before the rotation.
+
+use crate::bvh::Bvh;
+use crate::bvh_cache::{BvhCache, CacheStatus};
+use crate::camera::{ApertureShape, Camera, Projection};
+use crate::environment::EnvironmentMap;
+use crate::light::*;
+use crate::material::*;
+use crate::obj_loader::{self, ObjError, ObjMesh};
+use crate::ray::Ray;
+use crate::render::RenderSettings;
+use crate::scene::{self, *};
+use crate::texture::{Texture, TextureCache, WrapMode};
+use crate::transform::Placement;
+use crate::triangle::{Triangle, TriangleMotion};
+use crate::vec3::*;
+
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Mesh(String, ObjError),
+    Invalid(String),
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneFileError::Io(filename, err) => write!(f, "{}: {}", filename, err),
+            SceneFileError::Parse(filename, err) => write!(f, "{}: {}", filename, err),
+            SceneFileError::Mesh(filename, err) => write!(f, "{}: {}", filename, err),
+            SceneFileError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SceneFileError {}
+
+#[derive(Clone, Copy)]
+pub struct CameraParams {
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub vfov: f32,
+    pub aperture: f32,
+    pub aperture_shape: ApertureShape,
+    pub focus_distance: f32,
+    /// Focus on the surface seen at the center of the image instead of at `focus_distance`,
+    /// which is kept when nothing is seen there.
+    pub autofocus: bool,
+    pub projection: Projection,
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+}
+
+impl CameraParams {
+    /// Pinhole camera of 60 degrees of vertical field of view, focused on `look_at`.
+    pub fn new(look_from: &Vec3, look_at: &Vec3) -> CameraParams {
+        CameraParams {
+            look_from: *look_from,
+            look_at: *look_at,
+            up: Vec3::new(0.0, 1.0, 0.0),
+            vfov: 60.0,
+            aperture: 0.0,
+            aperture_shape: ApertureShape::default(),
+            focus_distance: (*look_from - *look_at).length(),
+            autofocus: false,
+            projection: Projection::Perspective,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+        }
+    }
+
+    pub fn build(&self, aspect: f32) -> Camera {
+        let mut camera = Camera::look_at(
+            &self.look_from,
+            &self.look_at,
+            &self.up,
+            self.vfov,
+            aspect,
+            self.aperture,
+            self.focus_distance,
+        );
+        camera.aperture_shape = self.aperture_shape;
+        camera.projection = self.projection;
+        camera.shutter_open = self.shutter_open;
+        camera.shutter_close = self.shutter_close;
+        camera
+    }
+
+    /// Distance along the view direction to the surface of `scene` seen at the center of the
+    /// image.
+    pub fn center_distance(&self, scene: &Scene) -> Option<f32> {
+        let view = (self.look_at - self.look_from).normalize();
+        scene::intersect(&Ray::new(&self.look_from, &view), scene).map(|hit| hit.t)
+    }
+}
+
+/// Everything needed to render an image.
+pub struct SceneDescription {
+    pub scene: Scene,
+    pub camera: CameraParams,
+    pub settings: RenderSettings,
+    /// Problems which did not stop the loading, for the caller to report.
+    pub warnings: Vec<String>,
+}
+
+impl SceneDescription {
+    /// Default setup to preview a lone mesh: a floor under it, the default sun and sky, and a
+    /// camera looking at it from above.
+    pub fn from_mesh(mesh: &ObjMesh) -> SceneDescription {
+        SceneDescription::preview_mesh(mesh, |triangles, _| Bvh::create(&mut triangles[..]))
+    }
+
+    /// Same as `from_mesh`, loading the tree from `cache` when it is valid, and building and
+    /// saving it otherwise.
+    pub fn from_mesh_cached(mesh: &ObjMesh, cache: &BvhCache) -> (SceneDescription, CacheStatus) {
+        let mut status = None;
+        let desc = SceneDescription::preview_mesh(mesh, |triangles, material_count| {
+            let (bvh, cache_status) = cache.load_or_build(triangles, material_count);
+            status = Some(cache_status);
+            bvh
+        });
+        (desc, status.unwrap())
+    }
+
+    fn preview_mesh<F: FnOnce(&mut Vec<Triangle>, usize) -> Bvh>(
+        mesh: &ObjMesh,
+        build_bvh: F,
+    ) -> SceneDescription {
+        let mut warnings = mesh.warnings.clone();
+        let (mut triangles, materials) = mesh_parts(mesh, &mut warnings);
+        let (scene_min, scene_max) = compute_boundary(&triangles);
+        triangles.extend_from_slice(&make_floor(&scene_min, &scene_max));
+
+        let scene_size = scene_max - scene_min;
+        let scene_center = (scene_min + scene_max) * 0.5;
+        let look_from = scene_center + scene_size * Vec3::new(0.3, 0.6, 1.2);
+        let look_at = scene_center + scene_size * Vec3::new(0.0, -0.1, 0.0);
+        let bvh = build_bvh(&mut triangles, materials.len());
+        SceneDescription {
+            scene: Scene::with_bvh(
+                triangles,
+                bvh,
+                materials,
+                vec![default_sun()],
+                Background::default(),
+            ),
+            camera: CameraParams::new(&look_from, &look_at),
+            settings: RenderSettings::default(),
+            warnings,
+        }
+    }
+
+    pub fn camera(&self) -> Camera {
+        let mut params = self.camera;
+        if params.autofocus {
+            if let Some(distance) = params.center_distance(&self.scene) {
+                params.focus_distance = distance;
+            }
+        }
+        params.build(self.settings.aspect())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFile {
+    #[serde(default)]
+    floor: bool,
+    #[serde(default)]
+    render: RenderSection,
+    camera: CameraSection,
+    background: Option<BackgroundSection>,
+    #[serde(default)]
+    lights: Vec<LightSection>,
+    #[serde(default)]
+    materials: Vec<MaterialSection>,
+    #[serde(default)]
+    meshes: Vec<MeshSection>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RenderSection {
+    width: Option<usize>,
+    height: Option<usize>,
+    spp: Option<usize>,
+    max_depth: Option<usize>,
+    tile_size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraSection {
+    look_from: [f32; 3],
+    look_at: [f32; 3],
+    #[serde(default = "default_up")]
+    up: [f32; 3],
+    #[serde(default = "default_vfov")]
+    vfov: f32,
+    #[serde(default)]
+    aperture: f32,
+    focus_distance: Option<f32>,
+    #[serde(default)]
+    autofocus: bool,
+    #[serde(default)]
+    blades: usize,
+    /// In degrees.
+    #[serde(default)]
+    blade_rotation: f32,
+    #[serde(default = "default_squeeze")]
+    squeeze: f32,
+    #[serde(default)]
+    projection: ProjectionKind,
+    ortho_height: Option<f32>,
+    /// In degrees.
+    #[serde(default = "default_fisheye_fov")]
+    fisheye_fov: f32,
+    #[serde(default)]
+    shutter_open: f32,
+    #[serde(default)]
+    shutter_close: f32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum ProjectionKind {
+    #[default]
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum BackgroundSection {
+    Constant {
+        color: [f32; 3],
+    },
+    Gradient {
+        bottom: [f32; 3],
+        top: [f32; 3],
+    },
+    Environment {
+        file: String,
+        /// Euler angles in degrees.
+        #[serde(default)]
+        rotate: [f32; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum LightSection {
+    Directional {
+        direction: [f32; 3],
+        #[serde(default = "default_color")]
+        color: [f32; 3],
+        /// In degrees.
+        #[serde(default)]
+        angular_radius: f32,
+    },
+    Point {
+        position: [f32; 3],
+        #[serde(default = "default_color")]
+        color: [f32; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        #[serde(default = "default_color")]
+        color: [f32; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+        /// Half angles of the cone, in degrees. The light fades out over the last 5 degrees by
+        /// default.
+        inner_angle: Option<f32>,
+        #[serde(default = "default_outer_angle")]
+        outer_angle: f32,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+enum MaterialSection {
+    Lambertian {
+        name: String,
+        albedo: [f32; 3],
+        albedo_map: Option<String>,
+        normal_map: Option<String>,
+        #[serde(default = "default_wrap")]
+        wrap: WrapMode,
+    },
+    Metal {
+        name: String,
+        albedo: [f32; 3],
+        #[serde(default)]
+        roughness: f32,
+        albedo_map: Option<String>,
+        roughness_map: Option<String>,
+        normal_map: Option<String>,
+        #[serde(default = "default_wrap")]
+        wrap: WrapMode,
+    },
+    Dielectric {
+        name: String,
+        ior: f32,
+    },
+    Emissive {
+        name: String,
+        #[serde(default = "default_color")]
+        color: [f32; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(untagged)]
+enum Scale {
+    Uniform(f32),
+    PerAxis([f32; 3]),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshSection {
+    file: String,
+    material: Option<String>,
+    #[serde(default)]
+    translate: [f32; 3],
+    /// Euler angles in degrees.
+    #[serde(default)]
+    rotate: [f32; 3],
+    scale: Option<Scale>,
+    motion: Option<MotionSection>,
+}
+
+/// Placement of a moving mesh at the time 1, defaulting to its placement at the time 0.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MotionSection {
+    translate: Option<[f32; 3]>,
+    /// Euler angles in degrees.
+    rotate: Option<[f32; 3]>,
+    scale: Option<Scale>,
+}
+
+fn default_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_vfov() -> f32 {
+    60.0
+}
+
+fn default_squeeze() -> f32 {
+    1.0
+}
+
+fn default_fisheye_fov() -> f32 {
+    180.0
+}
+
+fn default_color() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_outer_angle() -> f32 {
+    30.0
+}
+
+fn default_wrap() -> WrapMode {
+    WrapMode::Repeat
+}
+
+impl MaterialSection {
+    fn name(&self) -> &str {
+        match self {
+            MaterialSection::Lambertian { name, .. } => name,
+            MaterialSection::Metal { name, .. } => name,
+            MaterialSection::Dielectric { name, .. } => name,
+            MaterialSection::Emissive { name, .. } => name,
+        }
+    }
+
+    /// Texture maps are loaded relatively to `base_dir`, through `textures`.
+    fn build(
+        &self,
+        base_dir: &Path,
+        textures: &mut TextureCache,
+    ) -> Result<Box<dyn Material>, SceneFileError> {
+        let name = self.name();
+        let mut load = |map: &Option<String>,
+                        srgb: bool,
+                        wrap: WrapMode|
+         -> Result<Option<Arc<Texture>>, SceneFileError> {
+            match map {
+                Some(file) => {
+                    let path = base_dir.join(file);
+                    let texture = textures.load(&path, srgb, wrap).map_err(|err| {
+                        SceneFileError::Invalid(format!("material '{}': {}", name, err))
+                    })?;
+                    Ok(Some(texture))
+                }
+                None => Ok(None),
+            }
+        };
+        let material: Box<dyn Material> = match self {
+            MaterialSection::Lambertian {
+                albedo,
+                albedo_map,
+                normal_map,
+                wrap,
+                ..
+            } => {
+                let mut lambertian = Lambertian::new(&Vec3::from(*albedo));
+                lambertian.albedo_map = load(albedo_map, true, *wrap)?;
+                lambertian.normal_map = load(normal_map, false, *wrap)?;
+                Box::new(lambertian)
+            }
+            MaterialSection::Metal {
+                albedo,
+                roughness,
+                albedo_map,
+                roughness_map,
+                normal_map,
+                wrap,
+                ..
+            } => {
+                if !(0.0..=1.0).contains(roughness) {
+                    return Err(SceneFileError::Invalid(format!(
+                        "material '{}': roughness must be in [0, 1]",
+                        name
+                    )));
+                }
+                let mut metal = Metal::new(&Vec3::from(*albedo), *roughness);
+                metal.albedo_map = load(albedo_map, true, *wrap)?;
+                metal.roughness_map = load(roughness_map, false, *wrap)?;
+                metal.normal_map = load(normal_map, false, *wrap)?;
+                Box::new(metal)
+            }
+            MaterialSection::Dielectric { ior, .. } => {
+                if ior.is_nan() || *ior <= 0.0 {
+                    return Err(SceneFileError::Invalid(format!(
+                        "material '{}': ior must be positive",
+                        name
+                    )));
+                }
+                Box::new(Dielectric { ior: *ior })
+            }
+            MaterialSection::Emissive {
+                color, intensity, ..
+            } => Box::new(Emissive {
+                color: Vec3::from(*color),
+                intensity: check_intensity(&format!("material '{}': intensity", name), *intensity)?,
+            }),
+        };
+        Ok(material)
+    }
+}
+
+fn placement(translate: [f32; 3], rotate: [f32; 3], scale: Option<Scale>) -> Placement {
+    Placement {
+        translate: Vec3::from(translate),
+        rotate: Vec3::from(rotate),
+        scale: match scale {
+            Some(Scale::Uniform(factor)) => Vec3::fill(factor),
+            Some(Scale::PerAxis(factors)) => Vec3::from(factors),
+            None => Vec3::fill(1.0),
+        },
+    }
+}
+
+impl MeshSection {
+    fn placement(&self) -> Placement {
+        placement(self.translate, self.rotate, self.scale)
+    }
+
+    /// Placement at the time 1 of the moving meshes.
+    fn end_placement(&self) -> Option<Placement> {
+        self.motion.as_ref().map(|motion| {
+            placement(
+                motion.translate.unwrap_or(self.translate),
+                motion.rotate.unwrap_or(self.rotate),
+                motion.scale.or(self.scale),
+            )
+        })
+    }
+}
+
+/// Triangles of `mesh`, in place, and the materials they use, the default material first.
+/// Texture maps that cannot be loaded are reported in `warnings`.
+pub(crate) fn mesh_parts(
+    mesh: &ObjMesh,
+    warnings: &mut Vec<String>,
+) -> (Vec<Triangle>, Vec<Box<dyn Material>>) {
+    let mut triangles = Vec::new();
+    let mut materials = vec![default_material()];
+    append_mesh(
+        mesh,
+        &Placement::default(),
+        None,
+        None,
+        &mut triangles,
+        &mut materials,
+        &mut TextureCache::new(),
+        warnings,
+    );
+    (triangles, materials)
+}
+
+/// Append the triangles of `mesh`, placed, to `triangles`, moving to `end_placement` at the
+/// time 1 when given. They all use `material_override` when set. Otherwise the mesh
+/// materials are converted and appended to `materials`, except for the unnamed one which maps
+/// to the scene default material, the texture maps that cannot be loaded being reported in
+/// `warnings`.
+#[allow(clippy::too_many_arguments)]
+fn append_mesh(
+    mesh: &ObjMesh,
+    placement: &Placement,
+    end_placement: Option<&Placement>,
+    material_override: Option<usize>,
+    triangles: &mut Vec<Triangle>,
+    materials: &mut Vec<Box<dyn Material>>,
+    textures: &mut TextureCache,
+    warnings: &mut Vec<String>,
+) {
+    let mut mesh_materials: Vec<usize> = Vec::with_capacity(mesh.materials.len());
+    for material in mesh.materials.iter() {
+        mesh_materials.push(match material_override {
+            Some(idx) => idx,
+            None if material.name.is_empty() => 0,
+            None => {
+                materials.push(material.to_material(textures, warnings));
+                materials.len() - 1
+            }
+        });
+    }
+    let transform = placement.transform();
+    triangles.extend(mesh.triangles.iter().map(|triangle| {
+        let v = &triangle.vertices;
+        let mut transformed = Triangle::new(
+            transform.transform_point(&v[0]),
+            transform.transform_point(&v[1]),
+            transform.transform_point(&v[2]),
+        );
+        transformed.normals = triangle.normals.map(|normals| {
+            [
+                transform.transform_normal(&normals[0]),
+                transform.transform_normal(&normals[1]),
+                transform.transform_normal(&normals[2]),
+            ]
+        });
+        transformed.uvs = triangle.uvs;
+        transformed.material = mesh_materials[triangle.material];
+        transformed.motion = end_placement.map(|end| {
+            Box::new(TriangleMotion {
+                vertices: triangle.vertices,
+                normals: triangle.normals,
+                start: *placement,
+                end: *end,
+            })
+        });
+        transformed
+    }));
+}
+
+fn check_direction(name: &str, direction: [f32; 3]) -> Result<Vec3, SceneFileError> {
+    let direction = Vec3::from(direction);
+    if direction.length() > 0.0 {
+        Ok(direction)
+    } else {
+        Err(SceneFileError::Invalid(format!(
+            "{} must not be a null vector",
+            name
+        )))
+    }
+}
+
+/// `intensity`, which must be a non negative number.
+fn check_intensity(name: &str, intensity: f32) -> Result<f32, SceneFileError> {
+    if intensity >= 0.0 {
+        Ok(intensity)
+    } else {
+        Err(SceneFileError::Invalid(format!(
+            "{} must not be negative",
+            name
+        )))
+    }
+}
+
+pub fn load(filename: &str) -> Result<SceneDescription, SceneFileError> {
+    let contents = std::fs::read_to_string(filename)
+        .map_err(|err| SceneFileError::Io(filename.to_string(), err))?;
+    let base_dir = Path::new(filename)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    parse(&contents, base_dir).map_err(|err| match err {
+        SceneFileError::Parse(_, err) => SceneFileError::Parse(filename.to_string(), err),
+        err => err,
+    })
+}
+
+/// Parse a scene description, loading the meshes relatively to `base_dir`.
+pub fn parse(contents: &str, base_dir: &Path) -> Result<SceneDescription, SceneFileError> {
+    let file: SceneFile =
+        toml::from_str(contents).map_err(|err| SceneFileError::Parse(String::new(), err))?;
+
+    // the default material always comes first, so that the floor can use it
+    let mut material_names = vec![String::from("default")];
+    let mut materials = vec![default_material()];
+    let mut textures = TextureCache::new();
+    let mut default_redefined = false;
+    for material in file.materials.iter() {
+        let name = material.name();
+        let defined = if name == "default" {
+            std::mem::replace(&mut default_redefined, true)
+        } else {
+            material_names.iter().any(|known| known == name)
+        };
+        if defined {
+            return Err(SceneFileError::Invalid(format!(
+                "material '{}' is defined twice",
+                name
+            )));
+        }
+        let built = material.build(base_dir, &mut textures)?;
+        if name == "default" {
+            materials[0] = built;
+        } else {
+            material_names.push(name.to_string());
+            materials.push(built);
+        }
+    }
+
+    let mut triangles: Vec<Triangle> = Vec::new();
+    let mut warnings = Vec::new();
+    for mesh in file.meshes.iter() {
+        let material = match &mesh.material {
+            Some(name) => match material_names.iter().position(|known| known == name) {
+                Some(idx) => Some(idx),
+                None => {
+                    return Err(SceneFileError::Invalid(format!(
+                        "mesh '{}' uses the unknown material '{}'",
+                        mesh.file, name
+                    )))
+                }
+            },
+            None => None,
+        };
+        let path = base_dir.join(&mesh.file);
+        let path = path.to_string_lossy();
+        let obj_mesh = obj_loader::load_scene(&path).map_err(|err| match err {
+            ObjError::Io(err) => SceneFileError::Io(path.to_string(), err),
+            err => SceneFileError::Mesh(path.to_string(), err),
+        })?;
+        warnings.extend(
+            obj_mesh
+                .warnings
+                .iter()
+                .map(|warning| format!("{}: {}", path, warning)),
+        );
+        append_mesh(
+            &obj_mesh,
+            &mesh.placement(),
+            mesh.end_placement().as_ref(),
+            material,
+            &mut triangles,
+            &mut materials,
+            &mut textures,
+            &mut warnings,
+        );
+    }
+    if file.floor && !triangles.is_empty() {
+        let (scene_min, scene_max) = compute_boundary(&triangles);
+        triangles.extend_from_slice(&make_floor(&scene_min, &scene_max));
+    }
+
+    let mut lights = Vec::new();
+    for light in file.lights.iter() {
+        match light {
+            LightSection::Directional {
+                direction,
+                color,
+                angular_radius,
+            } => {
+                let direction = check_direction("light direction", *direction)?;
+                if !(0.0..90.0).contains(angular_radius) {
+                    return Err(SceneFileError::Invalid(String::from(
+                        "light angular_radius must be in [0, 90[",
+                    )));
+                }
+                lights.push(Light::directional(
+                    &direction,
+                    &Vec3::from(*color),
+                    angular_radius.to_radians(),
+                ));
+            }
+            LightSection::Point {
+                position,
+                color,
+                intensity,
+            } => {
+                let intensity = check_intensity("light intensity", *intensity)?;
+                lights.push(Light::point(
+                    &Vec3::from(*position),
+                    &(Vec3::from(*color) * intensity),
+                ));
+            }
+            LightSection::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                inner_angle,
+                outer_angle,
+            } => {
+                let direction = check_direction("light direction", *direction)?;
+                let intensity = check_intensity("light intensity", *intensity)?;
+                let inner_angle = inner_angle.unwrap_or((outer_angle - 5.0).max(0.0));
+                if !(0.0..=180.0).contains(outer_angle)
+                    || !(0.0..=*outer_angle).contains(&inner_angle)
+                {
+                    return Err(SceneFileError::Invalid(String::from(
+                        "spot light angles must verify 0 <= inner_angle <= outer_angle <= 180",
+                    )));
+                }
+                lights.push(Light::spot(
+                    &Vec3::from(*position),
+                    &direction,
+                    &(Vec3::from(*color) * intensity),
+                    inner_angle.to_radians(),
+                    outer_angle.to_radians(),
+                ));
+            }
+        }
+    }
+
+    let background = match file.background {
+        Some(BackgroundSection::Constant { color }) => Background::Constant(Vec3::from(color)),
+        Some(BackgroundSection::Gradient { bottom, top }) => Background::Gradient {
+            bottom: Vec3::from(bottom),
+            top: Vec3::from(top),
+        },
+        Some(BackgroundSection::Environment {
+            file,
+            rotate,
+            intensity,
+        }) => {
+            let intensity = check_intensity("background intensity", intensity)?;
+            let path = base_dir.join(&file);
+            let map = EnvironmentMap::load(&path, &Vec3::from(rotate), intensity)
+                .map_err(|err| SceneFileError::Io(path.to_string_lossy().to_string(), err))?;
+            Background::Map(map)
+        }
+        None => Background::default(),
+    };
+
+    let camera = &file.camera;
+    let look_from = Vec3::from(camera.look_from);
+    let look_at = Vec3::from(camera.look_at);
+    check_direction("camera view direction", (look_at - look_from).to_array())?;
+    check_direction("camera up vector", camera.up)?;
+    if camera.aperture.is_nan() || camera.aperture < 0.0 {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera aperture must not be negative",
+        )));
+    }
+    if camera
+        .focus_distance
+        .is_some_and(|distance| distance.is_nan() || distance <= 0.0)
+    {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera focus_distance must be positive",
+        )));
+    }
+    if camera.blades == 1 || camera.blades == 2 {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera blades must be 0, for a circular aperture, or at least 3",
+        )));
+    }
+    if camera.squeeze.is_nan() || camera.squeeze <= 0.0 {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera squeeze must be positive",
+        )));
+    }
+    if camera
+        .ortho_height
+        .is_some_and(|height| height.is_nan() || height <= 0.0)
+    {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera ortho_height must be positive",
+        )));
+    }
+    if !(camera.fisheye_fov > 0.0 && camera.fisheye_fov <= 360.0) {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera fisheye_fov must be in (0, 360] degrees",
+        )));
+    }
+    let in_motion = 0.0..=1.0;
+    if !in_motion.contains(&camera.shutter_open) || !in_motion.contains(&camera.shutter_close) {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera shutter_open and shutter_close must be in [0, 1], the time of the motion",
+        )));
+    }
+    if camera.shutter_close < camera.shutter_open {
+        return Err(SceneFileError::Invalid(String::from(
+            "camera shutter_close must not be before shutter_open",
+        )));
+    }
+    let focus_distance = camera
+        .focus_distance
+        .unwrap_or_else(|| (look_from - look_at).length());
+    let projection = match camera.projection {
+        ProjectionKind::Perspective => Projection::Perspective,
+        ProjectionKind::Orthographic => Projection::Orthographic {
+            height: camera
+                .ortho_height
+                .unwrap_or_else(|| 2.0 * focus_distance * (camera.vfov.to_radians() / 2.0).tan()),
+        },
+        ProjectionKind::Fisheye => Projection::Fisheye {
+            fov: camera.fisheye_fov,
+        },
+        ProjectionKind::Equirectangular => Projection::Equirectangular,
+    };
+    let camera = CameraParams {
+        up: Vec3::from(camera.up),
+        vfov: camera.vfov,
+        aperture: camera.aperture,
+        aperture_shape: ApertureShape {
+            blades: camera.blades,
+            rotation: camera.blade_rotation,
+            squeeze: camera.squeeze,
+        },
+        focus_distance,
+        autofocus: camera.autofocus,
+        projection,
+        shutter_open: camera.shutter_open,
+        shutter_close: camera.shutter_close,
+        ..CameraParams::new(&look_from, &look_at)
+    };
+
+    let mut settings = RenderSettings::default();
+    let render = &file.render;
+    let counts = [
+        (render.width, &mut settings.width, "width"),
+        (render.height, &mut settings.height, "height"),
+        (render.spp, &mut settings.spp, "spp"),
+        (render.max_depth, &mut settings.max_depth, "max_depth"),
+        (render.tile_size, &mut settings.tile_size, "tile_size"),
+    ];
+    for (value, setting, name) in counts {
+        if let Some(value) = value {
+            if value == 0 {
+                return Err(SceneFileError::Invalid(format!(
+                    "render.{} must be positive",
+                    name
+                )));
+            }
+            *setting = value;
+        }
+    }
+
+    Ok(SceneDescription {
+        scene: Scene::from_parts(triangles, materials, lights, background),
+        camera,
+        settings,
+        warnings,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal() {
+        let desc = parse(
+            "[camera]\nlook_from = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\n",
+            Path::new(""),
+        )
+        .unwrap();
+        assert!(desc.scene.triangle_list.is_empty());
+        assert!(desc.scene.lights.is_empty());
+        assert_eq!(1, desc.scene.materials.len());
+        assert_eq!(640, desc.settings.width);
+        assert_eq!(5.0, desc.camera.focus_distance);
+        assert_eq!(60.0, desc.camera.vfov);
+        assert_eq!(Projection::Perspective, desc.camera.projection);
+    }
+
+    #[test]
+    fn projections() {
+        let camera =
+            "[camera]\nlook_from = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\nvfov = 90.0\n";
+        let projection = |options: &str| {
+            let contents = format!("{}{}", camera, options);
+            parse(&contents, Path::new("")).unwrap().camera.projection
+        };
+        match projection("projection = \"orthographic\"\n") {
+            Projection::Orthographic { height } => assert!((height - 10.0).abs() < 1e-4),
+            other => panic!("unexpected projection {:?}", other),
+        }
+        assert_eq!(
+            Projection::Orthographic { height: 3.0 },
+            projection("projection = \"orthographic\"\northo_height = 3.0\n")
+        );
+        assert_eq!(
+            Projection::Fisheye { fov: 180.0 },
+            projection("projection = \"fisheye\"\n")
+        );
+        assert_eq!(
+            Projection::Equirectangular,
+            projection("projection = \"equirectangular\"\n")
+        );
+    }
+
+    #[test]
+    fn full() {
+        let desc = parse(
+            r#"
+            [render]
+            width = 320
+            height = 200
+            spp = 16
+            max_depth = 3
+
+            [camera]
+            look_from = [0.0, 1.0, 5.0]
+            look_at = [0.0, 1.0, 0.0]
+            vfov = 45.0
+            aperture = 0.1
+            focus_distance = 4.0
+
+            [background]
+            type = "constant"
+            color = [0.1, 0.2, 0.3]
+
+            [[lights]]
+            type = "directional"
+            direction = [0.0, 2.0, 0.0]
+            color = [2.0, 2.0, 2.0]
+            angular_radius = 0.5
+
+            [[lights]]
+            type = "point"
+            position = [0.0, 3.0, 0.0]
+            intensity = 10.0
+
+            [[lights]]
+            type = "spot"
+            position = [0.0, 3.0, 2.0]
+            direction = [0.0, -1.0, 0.0]
+            color = [1.0, 0.5, 0.5]
+            outer_angle = 20.0
+
+            [[materials]]
+            name = "glass"
+            type = "dielectric"
+            ior = 1.5
+
+            [[materials]]
+            name = "default"
+            type = "metal"
+            albedo = [0.9, 0.9, 0.9]
+            roughness = 0.1
+
+            [[materials]]
+            name = "lamp"
+            type = "emissive"
+            intensity = 4.0
+            "#,
+            Path::new(""),
+        )
+        .unwrap();
+        assert_eq!((320, 200), (desc.settings.width, desc.settings.height));
+        assert_eq!(16, desc.settings.spp);
+        assert_eq!(3, desc.settings.max_depth);
+        assert_eq!(8, desc.settings.tile_size);
+        assert_eq!(45.0, desc.camera.vfov);
+        assert_eq!(4.0, desc.camera.focus_distance);
+        assert_eq!(3, desc.scene.lights.len());
+        assert_eq!(3, desc.scene.materials.len());
+        match &desc.scene.lights[0] {
+            Light::Directional { direction, .. } => {
+                assert_eq!(Vec3::new(0.0, 1.0, 0.0), *direction)
+            }
+            _ => panic!("expected a directional light"),
+        }
+        assert_eq!(
+            Vec3::new(0.1, 0.2, 0.3),
+            desc.scene.background.color(&Vec3::new(0.0, 1.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let camera = "[camera]\nlook_from = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\n";
+        let parse_err = |contents: &str| parse(contents, Path::new("")).err().unwrap();
+        match parse_err("[camera]\nlook_from = [0.0, 0.0, 5.0]\n") {
+            SceneFileError::Parse(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err(&format!("{}[render]\nsamples = 4\n", camera)) {
+            SceneFileError::Parse(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err(&format!("{}[render]\nspp = 0\n", camera)) {
+            SceneFileError::Invalid(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err(&format!("{}projection = \"cylindrical\"\n", camera)) {
+            SceneFileError::Parse(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err(&format!(
+            "{}shutter_open = 3.0\nshutter_close = 3.0\n",
+            camera
+        )) {
+            SceneFileError::Invalid(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        for name in ["default", "red"] {
+            let material = format!(
+                "[[materials]]\nname = \"{}\"\ntype = \"lambertian\"\nalbedo = [0.5, 0.5, 0.5]\n",
+                name
+            );
+            assert!(parse(&format!("{}{}", camera, material), Path::new("")).is_ok());
+            match parse_err(&format!("{}{}{}", camera, material, material)) {
+                SceneFileError::Invalid(..) => {}
+                err => panic!("unexpected error {}", err),
+            }
+        }
+        for distance in ["0.0", "-2.0"] {
+            match parse_err(&format!("{}focus_distance = {}\n", camera, distance)) {
+                SceneFileError::Invalid(..) => {}
+                err => panic!("unexpected error {}", err),
+            }
+        }
+        match parse_err(&format!("{}fisheye_fov = 400.0\n", camera)) {
+            SceneFileError::Invalid(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        for field in ["aperture", "squeeze", "ortho_height", "fisheye_fov"] {
+            match parse_err(&format!("{}{} = nan\n", camera, field)) {
+                SceneFileError::Invalid(..) => {}
+                err => panic!("unexpected error {}", err),
+            }
+        }
+        for intensity in ["-1.0", "nan"] {
+            for section in [
+                "[[lights]]\ntype = \"point\"\nposition = [0.0, 1.0, 0.0]\n",
+                "[[lights]]\ntype = \"spot\"\nposition = [0.0, 1.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\n",
+                "[[materials]]\nname = \"a\"\ntype = \"emissive\"\n",
+                "[background]\ntype = \"environment\"\nfile = \"missing.hdr\"\n",
+            ] {
+                match parse_err(&format!("{}{}intensity = {}\n", camera, section, intensity)) {
+                    SceneFileError::Invalid(..) => {}
+                    err => panic!("unexpected error {}", err),
+                }
+            }
+        }
+        match parse_err(&format!(
+            "{}[[lights]]\ntype = \"spot\"\nposition = [0.0, 1.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\ninner_angle = 40.0\nouter_angle = 30.0\n",
+            camera
+        )) {
+            SceneFileError::Invalid(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        for ior in ["0.0", "nan"] {
+            match parse_err(&format!(
+                "{}[[materials]]\nname = \"a\"\ntype = \"dielectric\"\nior = {}\n",
+                camera, ior
+            )) {
+                SceneFileError::Invalid(..) => {}
+                err => panic!("unexpected error {}", err),
+            }
+        }
+        match parse_err(&format!(
+            "{}[[meshes]]\nfile = \"a.obj\"\nmaterial = \"unknown\"\n",
+            camera
+        )) {
+            SceneFileError::Invalid(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err(&format!(
+            "{}[[materials]]\nname = \"a\"\ntype = \"lambertian\"\nalbedo = [1.0, 1.0, 1.0]\nalbedo_map = \"missing.png\"\n",
+            camera
+        )) {
+            SceneFileError::Invalid(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err(&format!("{}[[meshes]]\nfile = \"missing.obj\"\n", camera)) {
+            SceneFileError::Io(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+        match parse_err(&format!(
+            "{}[background]\ntype = \"environment\"\nfile = \"missing.hdr\"\n",
+            camera
+        )) {
+            SceneFileError::Io(..) => {}
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn environment() {
+        let dir = std::env::temp_dir();
+        let filename = dir.join("toy_mesh_path_tracer_scene_environment.hdr");
+        let mut data = b"#?RADIANCE\n\n-Y 1 +X 2\n".to_vec();
+        data.extend_from_slice(&[128, 128, 128, 129, 1, 1, 1, 1]);
+        std::fs::write(&filename, data).unwrap();
+        let desc = parse(
+            r#"
+            [camera]
+            look_from = [0.0, 0.0, 5.0]
+            look_at = [0.0, 0.0, 0.0]
+
+            [background]
+            type = "environment"
+            file = "toy_mesh_path_tracer_scene_environment.hdr"
+            rotate = [0.0, 90.0, 0.0]
+            intensity = 2.0
+            "#,
+            &dir,
+        );
+        std::fs::remove_file(&filename).unwrap();
+        let color = desc
+            .unwrap()
+            .scene
+            .background
+            .color(&Vec3::new(0.0, 1.0, 0.0));
+        assert!((color - Vec3::fill(2.0 * 128.5 / 128.0)).length() < 1e-4);
+    }
+
+    #[test]
+    fn autofocus() {
+        let dir = std::env::temp_dir();
+        let filename = dir.join("toy_mesh_path_tracer_scene_autofocus.obj");
+        std::fs::write(&filename, "v -1 -1 1\nv 1 -1 1\nv 0 1 1\nf 1 2 3\n").unwrap();
+        let desc = parse(
+            r#"
+            [camera]
+            look_from = [0.0, 0.0, 5.0]
+            look_at = [0.0, 0.0, -5.0]
+            aperture = 0.2
+            autofocus = true
+            blades = 6
+            squeeze = 1.5
+
+            [[meshes]]
+            file = "toy_mesh_path_tracer_scene_autofocus.obj"
+            "#,
+            &dir,
+        );
+        std::fs::remove_file(&filename).unwrap();
+        let desc = desc.unwrap();
+        assert!(desc.camera.autofocus);
+        assert_eq!(10.0, desc.camera.focus_distance);
+        assert_eq!(6, desc.camera.aperture_shape.blades);
+        let distance = desc.camera.center_distance(&desc.scene).unwrap();
+        assert!((distance - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn motion() {
+        let dir = std::env::temp_dir();
+        let filename = dir.join("toy_mesh_path_tracer_scene_motion.obj");
+        std::fs::write(&filename, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let desc = parse(
+            r#"
+            [camera]
+            look_from = [0.0, 0.0, 5.0]
+            look_at = [0.0, 0.0, 0.0]
+            shutter_open = 0.2
+            shutter_close = 0.6
+
+            [[meshes]]
+            file = "toy_mesh_path_tracer_scene_motion.obj"
+            translate = [1.0, 0.0, 0.0]
+            scale = 2.0
+
+            [meshes.motion]
+            translate = [3.0, 0.0, 0.0]
+            "#,
+            &dir,
+        );
+        std::fs::remove_file(&filename).unwrap();
+        let desc = desc.unwrap();
+        assert_eq!(
+            (0.2, 0.6),
+            (desc.camera.shutter_open, desc.camera.shutter_close)
+        );
+        let triangle = &desc.scene.triangle_list[0];
+        assert_eq!(Vec3::new(3.0, 0.0, 0.0), triangle.vertices[1]);
+        // the scale is kept, only the translation moves
+        let end = triangle.vertices_at(1.0);
+        assert_eq!(Vec3::new(5.0, 0.0, 0.0), end[1]);
+        assert_eq!(Vec3::new(3.0, 2.0, 0.0), end[2]);
+    }
+}
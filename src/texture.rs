@@ -0,0 +1,542 @@
+use crate::vec3::*;
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Error};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// How texture coordinates outside of [0, 1] are handled.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl WrapMode {
+    fn apply(self, idx: i64, size: usize) -> usize {
+        let size = size as i64;
+        let wrapped = match self {
+            WrapMode::Repeat => idx.rem_euclid(size),
+            WrapMode::Clamp => idx.max(0).min(size - 1),
+            WrapMode::Mirror => {
+                let period = idx.rem_euclid(2 * size);
+                if period < size {
+                    period
+                } else {
+                    2 * size - 1 - period
+                }
+            }
+        };
+        wrapped as usize
+    }
+}
+
+/// Image sampled with bilinear filtering. Texels are stored row by row from the top left, in
+/// linear space.
+pub struct Texture {
+    width: usize,
+    height: usize,
+    texels: Vec<Vec3>,
+    pub wrap: WrapMode,
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, texels: Vec<Vec3>) -> Texture {
+        assert_eq!(width * height, texels.len());
+        assert!(width > 0 && height > 0);
+        Texture {
+            width,
+            height,
+            texels,
+            wrap: WrapMode::Repeat,
+        }
+    }
+
+    /// Load a PNG, PPM, Radiance HDR or OpenEXR image. `srgb` tells whether the values have to
+    /// be converted to linear, as for color maps, or are used as is, as for roughness or normal
+    /// maps. HDR and EXR images are always linear.
+    pub fn load(filename: &Path, srgb: bool) -> std::io::Result<Texture> {
+        let mut srgb = srgb;
+        let read = match filename.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => read_png(filename),
+            Some(ext) if ext.eq_ignore_ascii_case("ppm") => read_ppm(filename),
+            Some(ext) if ext.eq_ignore_ascii_case("hdr") => {
+                srgb = false;
+                read_hdr(filename)
+            }
+            Some(ext) if ext.eq_ignore_ascii_case("exr") => {
+                srgb = false;
+                read_exr(filename)
+            }
+            _ => Err(Error::other(
+                "unsupported image format, expected PNG, PPM, HDR or EXR",
+            )),
+        };
+        let (width, height, texels) =
+            read.map_err(|err| Error::new(err.kind(), format!("{}: {}", filename.display(), err)))?;
+        let decode = |value: f32| if srgb { srgb_to_linear(value) } else { value };
+        let texels = texels
+            .iter()
+            .map(|texel| Vec3::new(decode(texel[0]), decode(texel[1]), decode(texel[2])))
+            .collect();
+        Ok(Texture::new(width, height, texels))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub(crate) fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+        self.texels[x + y * self.width]
+    }
+
+    /// Bilinear lookup, `v` going up from the bottom of the image as in OBJ files.
+    pub fn sample(&self, uv: [f32; 2]) -> Vec3 {
+        let x = uv[0] * self.width as f32 - 0.5;
+        let y = (1.0 - uv[1]) * self.height as f32 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let fx = x - x0;
+        let fy = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0) * (1.0 - fx) + self.texel(x0 + 1, y0) * fx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - fx) + self.texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+type Texels = (usize, usize, Vec<[f32; 3]>);
+
+fn read_png(filename: &Path) -> std::io::Result<Texels> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(filename)?));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let to_io_error = |err: png::DecodingError| Error::other(err.to_string());
+    let mut reader = decoder.read_info().map_err(to_io_error)?;
+    let mut buffer = vec![0u8; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(to_io_error)?;
+    let channels = info.color_type.samples();
+    let (width, height) = (info.width as usize, info.height as usize);
+    let mut texels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = &buffer[y * info.line_size..y * info.line_size + width * channels];
+        for pixel in row.chunks(channels) {
+            let value = |idx: usize| pixel[idx] as f32 / 255.0;
+            texels.push(match channels {
+                // gray, with or without alpha
+                1 | 2 => [value(0); 3],
+                _ => [value(0), value(1), value(2)],
+            });
+        }
+    }
+    Ok((width, height, texels))
+}
+
+/// Binary (P6) or ASCII (P3) portable pixmap.
+fn read_ppm(filename: &Path) -> std::io::Result<Texels> {
+    let data = std::fs::read(filename)?;
+    let error = || Error::other("bad PPM format");
+
+    // header: magic, width, height and max value, separated by whitespaces and comments
+    let mut fields: Vec<String> = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if pos < data.len() && data[pos] == b'#' {
+            while pos < data.len() && data[pos] != b'\n' {
+                pos += 1;
+            }
+            continue;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err(error());
+        }
+        fields.push(String::from_utf8_lossy(&data[start..pos]).to_string());
+    }
+    let parse = |field: &str| field.parse::<usize>().map_err(|_| error());
+    let (width, height, max_value) = (parse(&fields[1])?, parse(&fields[2])?, parse(&fields[3])?);
+    if width == 0 || height == 0 || max_value == 0 || max_value > 65535 {
+        return Err(error());
+    }
+    let scale = 1.0 / max_value as f32;
+    let count = width * height * 3;
+
+    let values: Vec<f32> = match fields[0].as_str() {
+        "P6" => {
+            // a single whitespace separates the header from the data
+            let body = &data[(pos + 1).min(data.len())..];
+            let sample_size = if max_value < 256 { 1 } else { 2 };
+            if body.len() < count * sample_size {
+                return Err(error());
+            }
+            body.chunks(sample_size)
+                .take(count)
+                .map(|sample| match sample {
+                    [high, low] => ((*high as u32) << 8 | *low as u32) as f32 * scale,
+                    _ => sample[0] as f32 * scale,
+                })
+                .collect()
+        }
+        "P3" => {
+            let body = String::from_utf8_lossy(&data[pos..]);
+            let values: Vec<f32> = body
+                .split_whitespace()
+                .take(count)
+                .map(|w| w.parse::<f32>().map(|v| v * scale).map_err(|_| error()))
+                .collect::<std::io::Result<_>>()?;
+            if values.len() < count {
+                return Err(error());
+            }
+            values
+        }
+        _ => return Err(error()),
+    };
+    let texels = values
+        .chunks(3)
+        .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+        .collect();
+    Ok((width, height, texels))
+}
+
+/// Radiance RGBE image, with a shared exponent per pixel, flat or run-length encoded. Only the
+/// usual orientation, with rows from the top and pixels from the left, is supported.
+fn read_hdr(filename: &Path) -> std::io::Result<Texels> {
+    let data = std::fs::read(filename)?;
+    let error = |msg: &str| Error::other(format!("bad HDR format: {}", msg));
+
+    let mut pos = 0;
+    let mut next_line = || -> Option<String> {
+        let end = pos + data[pos..].iter().position(|&byte| byte == b'\n')?;
+        let line = String::from_utf8_lossy(&data[pos..end]).to_string();
+        pos = end + 1;
+        Some(line)
+    };
+    match next_line() {
+        Some(magic) if magic.starts_with("#?") => {}
+        _ => return Err(error("missing #? signature")),
+    }
+    loop {
+        let line = next_line().ok_or_else(|| error("truncated header"))?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(error("unsupported pixel format"));
+            }
+        }
+    }
+    let resolution = next_line().ok_or_else(|| error("missing resolution"))?;
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    let (width, height) = match fields[..] {
+        ["-Y", height, "+X", width] => (
+            width.parse::<usize>().map_err(|_| error("bad width"))?,
+            height.parse::<usize>().map_err(|_| error("bad height"))?,
+        ),
+        _ => return Err(error("unsupported orientation")),
+    };
+    if width == 0 || height == 0 {
+        return Err(error("empty image"));
+    }
+    // the longest runs pack 127 pixels of each component in 2 bytes, less than 16 pixels a byte
+    match width.checked_mul(height) {
+        Some(count) if count / 16 <= data.len() - pos => {}
+        _ => return Err(error("image larger than its data")),
+    }
+
+    let mut texels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    let mut bytes = data[pos..].iter().copied();
+    let mut next = || bytes.next().ok_or_else(|| error("truncated pixel data"));
+    for _ in 0..height {
+        let header = [next()?, next()?, next()?, next()?];
+        let run_length = (header[2] as usize) << 8 | header[3] as usize;
+        if header[0] == 2 && header[1] == 2 && header[2] < 128 && (8..32768).contains(&width) {
+            // each of the 4 components in turn, as runs or literals
+            if run_length != width {
+                return Err(error("scanline width mismatch"));
+            }
+            for component in 0..4 {
+                let mut x = 0;
+                while x < width {
+                    let count = next()? as usize;
+                    let (count, run) = if count > 128 {
+                        (count - 128, true)
+                    } else {
+                        (count, false)
+                    };
+                    if count == 0 || x + count > width {
+                        return Err(error("bad run length"));
+                    }
+                    if run {
+                        let value = next()?;
+                        for pixel in scanline[x..x + count].iter_mut() {
+                            pixel[component] = value;
+                        }
+                    } else {
+                        for pixel in scanline[x..x + count].iter_mut() {
+                            pixel[component] = next()?;
+                        }
+                    }
+                    x += count;
+                }
+            }
+        } else {
+            // flat pixels, where (1, 1, 1, n) repeats the previous one
+            let mut x = 0;
+            let mut pixel = header;
+            let mut shift = 0;
+            loop {
+                if pixel[0] == 1 && pixel[1] == 1 && pixel[2] == 1 && x > 0 {
+                    let count = match (pixel[3] as usize).checked_shl(shift) {
+                        Some(count) if x + count <= width => count,
+                        _ => return Err(error("bad run length")),
+                    };
+                    let previous = scanline[x - 1];
+                    scanline[x..x + count].fill(previous);
+                    x += count;
+                    shift += 8;
+                } else {
+                    scanline[x] = pixel;
+                    x += 1;
+                    shift = 0;
+                }
+                if x == width {
+                    break;
+                }
+                pixel = [next()?, next()?, next()?, next()?];
+            }
+        }
+        texels.extend(scanline.iter().map(|rgbe| {
+            if rgbe[3] == 0 {
+                return [0.0; 3];
+            }
+            let scale = 2f32.powi(rgbe[3] as i32 - 136);
+            [
+                (rgbe[0] as f32 + 0.5) * scale,
+                (rgbe[1] as f32 + 0.5) * scale,
+                (rgbe[2] as f32 + 0.5) * scale,
+            ]
+        }));
+    }
+    Ok((width, height, texels))
+}
+
+/// First RGB layer of an OpenEXR image, at its largest resolution.
+fn read_exr(filename: &Path) -> std::io::Result<Texels> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        filename,
+        |resolution, _| {
+            (
+                resolution.width(),
+                vec![[0f32; 3]; resolution.width() * resolution.height()],
+            )
+        },
+        |(width, texels), position, (r, g, b, _): (f32, f32, f32, f32)| {
+            texels[position.x() + position.y() * *width] = [r, g, b];
+        },
+    )
+    .map_err(|err| Error::other(err.to_string()))?;
+    let size = image.layer_data.size;
+    let (_, texels) = image.layer_data.channel_data.pixels;
+    Ok((size.width(), size.height(), texels))
+}
+
+/// Share the textures used by several materials.
+#[derive(Default)]
+pub struct TextureCache {
+    textures: HashMap<(PathBuf, bool, WrapMode), Arc<Texture>>,
+}
+
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache::default()
+    }
+
+    pub fn load(
+        &mut self,
+        filename: &Path,
+        srgb: bool,
+        wrap: WrapMode,
+    ) -> std::io::Result<Arc<Texture>> {
+        let key = (filename.to_path_buf(), srgb, wrap);
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture.clone());
+        }
+        let mut texture = Texture::load(filename, srgb)?;
+        texture.wrap = wrap;
+        let texture = Arc::new(texture);
+        self.textures.insert(key, texture.clone());
+        return Ok(texture);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checker() -> Texture {
+        // black and white 2x2 checker
+        let black = Vec3::zero();
+        let white = Vec3::fill(1.0);
+        Texture::new(2, 2, vec![white, black, black, white])
+    }
+
+    #[test]
+    fn wrap() {
+        assert_eq!(1, WrapMode::Repeat.apply(-1, 2));
+        assert_eq!(0, WrapMode::Repeat.apply(4, 2));
+        assert_eq!(0, WrapMode::Clamp.apply(-3, 2));
+        assert_eq!(1, WrapMode::Clamp.apply(5, 2));
+        assert_eq!(0, WrapMode::Mirror.apply(-1, 2));
+        assert_eq!(1, WrapMode::Mirror.apply(2, 2));
+        assert_eq!(0, WrapMode::Mirror.apply(3, 2));
+    }
+
+    #[test]
+    fn sample() {
+        let mut texture = checker();
+        // texel centers
+        assert_eq!(Vec3::fill(1.0), texture.sample([0.25, 0.75]));
+        assert_eq!(Vec3::zero(), texture.sample([0.75, 0.75]));
+        assert_eq!(Vec3::fill(1.0), texture.sample([0.75, 0.25]));
+        // halfway between two texels
+        assert_eq!(Vec3::fill(0.5), texture.sample([0.5, 0.75]));
+        // the border blends with the opposite side when repeating
+        assert_eq!(Vec3::fill(0.5), texture.sample([0.0, 0.75]));
+        texture.wrap = WrapMode::Clamp;
+        assert_eq!(Vec3::fill(1.0), texture.sample([0.0, 0.75]));
+        assert_eq!(Vec3::fill(1.0), texture.sample([-4.0, 0.75]));
+    }
+
+    #[test]
+    fn ppm() {
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_texture.ppm");
+        std::fs::write(&filename, "P3\n# comment\n2 1\n255\n255 0 0  0 0 255\n").unwrap();
+        let texture = Texture::load(&filename, false);
+        std::fs::remove_file(&filename).unwrap();
+        let texture = texture.unwrap();
+        assert_eq!((2, 1), (texture.width(), texture.height()));
+        assert_eq!(Vec3::new(1.0, 0.0, 0.0), texture.texel(0, 0));
+        assert_eq!(Vec3::new(0.0, 0.0, 1.0), texture.texel(1, 0));
+
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_texture_p6.ppm");
+        let mut data = b"P6 1 1 255\n".to_vec();
+        data.extend_from_slice(&[255, 188, 0]);
+        std::fs::write(&filename, data).unwrap();
+        let texture = Texture::load(&filename, true);
+        std::fs::remove_file(&filename).unwrap();
+        let texel = texture.unwrap().texel(0, 0);
+        assert_eq!(1.0, texel.x());
+        assert!((texel.y() - 0.5).abs() < 0.01);
+        assert_eq!(0.0, texel.z());
+    }
+
+    #[test]
+    fn hdr() {
+        let header = "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n";
+        let mut data = header.as_bytes().to_vec();
+        // run-length encoded scanline: red and exponent as runs, green as literals
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        data.extend_from_slice(&[128 + 8, 128]);
+        data.push(8);
+        data.extend((0..8).map(|x| x * 16));
+        data.extend_from_slice(&[128 + 8, 0]);
+        data.extend_from_slice(&[128 + 8, 129]);
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_texture_rle.hdr");
+        std::fs::write(&filename, data).unwrap();
+        let texture = Texture::load(&filename, true);
+        std::fs::remove_file(&filename).unwrap();
+        let texture = texture.unwrap();
+        assert_eq!((8, 1), (texture.width(), texture.height()));
+        for x in 0..8 {
+            let texel = texture.texel(x, 0);
+            let expected = Vec3::new(128.5, x as f32 * 16.0 + 0.5, 0.5) * (1.0 / 128.0);
+            assert!((texel - expected).length() < 1e-6);
+        }
+
+        // flat pixels, the second one repeating the first
+        let mut data = b"#?RGBE\n\n-Y 1 +X 2\n".to_vec();
+        data.extend_from_slice(&[64, 128, 255, 130, 1, 1, 1, 1]);
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_texture_flat.hdr");
+        std::fs::write(&filename, data).unwrap();
+        let texture = Texture::load(&filename, false);
+        std::fs::remove_file(&filename).unwrap();
+        let texture = texture.unwrap();
+        assert_eq!(texture.texel(0, 0), texture.texel(1, 0));
+        assert!((texture.texel(0, 0).x() - 64.5 / 64.0).abs() < 1e-6);
+
+        // empty repeats shifting the count out of range, and a header larger than the data
+        let mut repeats = b"#?RGBE\n\n-Y 1 +X 2\n".to_vec();
+        repeats.extend_from_slice(&[64, 128, 255, 130]);
+        for _ in 0..9 {
+            repeats.extend_from_slice(&[1, 1, 1, 0]);
+        }
+        let mut huge = b"#?RGBE\n\n-Y 100000 +X 100000\n".to_vec();
+        huge.extend_from_slice(&[64, 128, 255, 130]);
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_texture_bad.hdr");
+        for data in [repeats, huge].iter() {
+            std::fs::write(&filename, data).unwrap();
+            let texture = Texture::load(&filename, false);
+            assert!(texture.is_err());
+        }
+        std::fs::remove_file(&filename).unwrap();
+    }
+
+    #[test]
+    fn exr() {
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_texture.exr");
+        exr::prelude::write_rgb_file(&filename, 2, 1, |x, _| (x as f32 * 4.0, 0.5f32, 10.0f32))
+            .unwrap();
+        let texture = Texture::load(&filename, true);
+        std::fs::remove_file(&filename).unwrap();
+        let texture = texture.unwrap();
+        assert_eq!((2, 1), (texture.width(), texture.height()));
+        assert_eq!(Vec3::new(0.0, 0.5, 10.0), texture.texel(0, 0));
+        assert_eq!(Vec3::new(4.0, 0.5, 10.0), texture.texel(1, 0));
+    }
+
+    #[test]
+    fn png() {
+        let filename = std::env::temp_dir().join("toy_mesh_path_tracer_texture.png");
+        {
+            let file = File::create(&filename).unwrap();
+            let mut encoder = png::Encoder::new(file, 2, 1);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().unwrap();
+            writer
+                .write_image_data(&[0, 255, 0, 255, 51, 102, 153, 0])
+                .unwrap();
+        }
+        let texture = Texture::load(&filename, false);
+        std::fs::remove_file(&filename).unwrap();
+        let texture = texture.unwrap();
+        assert_eq!((2, 1), (texture.width(), texture.height()));
+        assert_eq!(Vec3::new(0.0, 1.0, 0.0), texture.texel(0, 0));
+        assert_eq!(Vec3::new(0.2, 0.4, 0.6), texture.texel(1, 0));
+    }
+}
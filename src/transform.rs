@@ -0,0 +1,228 @@
+use crate::vec3::*;
+
+/// Affine transform: a 3x3 linear part (stored by rows) followed by a translation.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Transform {
+    rows: [Vec3; 3],
+    translation: Vec3,
+}
+
+impl Transform {
+    pub fn identity() -> Transform {
+        Transform {
+            rows: [
+                Vec3::new(1.0, 0.0, 0.0),
+                Vec3::new(0.0, 1.0, 0.0),
+                Vec3::new(0.0, 0.0, 1.0),
+            ],
+            translation: Vec3::zero(),
+        }
+    }
+
+    pub fn translate(offset: &Vec3) -> Transform {
+        Transform {
+            translation: *offset,
+            ..Transform::identity()
+        }
+    }
+
+    pub fn scale(factors: &Vec3) -> Transform {
+        Transform {
+            rows: [
+                Vec3::new(factors.x(), 0.0, 0.0),
+                Vec3::new(0.0, factors.y(), 0.0),
+                Vec3::new(0.0, 0.0, factors.z()),
+            ],
+            translation: Vec3::zero(),
+        }
+    }
+
+    /// Rotation of `degrees` around the axis `axis_idx` (0: x, 1: y, 2: z).
+    pub fn rotate(axis_idx: usize, degrees: f32) -> Transform {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut rows = [[0f32; 3]; 3];
+        let a = (axis_idx + 1) % 3;
+        let b = (axis_idx + 2) % 3;
+        rows[axis_idx][axis_idx] = 1.0;
+        rows[a][a] = cos;
+        rows[a][b] = -sin;
+        rows[b][a] = sin;
+        rows[b][b] = cos;
+        Transform {
+            rows: [
+                Vec3::from(rows[0]),
+                Vec3::from(rows[1]),
+                Vec3::from(rows[2]),
+            ],
+            translation: Vec3::zero(),
+        }
+    }
+
+    /// Euler angles in degrees, applied in the x, y, z order.
+    pub fn rotate_xyz(degrees: &Vec3) -> Transform {
+        Transform::rotate(0, degrees.x())
+            .then(&Transform::rotate(1, degrees.y()))
+            .then(&Transform::rotate(2, degrees.z()))
+    }
+
+    fn column(&self, idx: usize) -> Vec3 {
+        Vec3::new(
+            self.rows[0].get(idx),
+            self.rows[1].get(idx),
+            self.rows[2].get(idx),
+        )
+    }
+
+    /// Transform applying `self` first, then `next`.
+    pub fn then(&self, next: &Transform) -> Transform {
+        let columns = [self.column(0), self.column(1), self.column(2)];
+        let mut rows = [[0f32; 3]; 3];
+        for (row_idx, row) in rows.iter_mut().enumerate() {
+            for (col_idx, value) in row.iter_mut().enumerate() {
+                *value = Vec3::dot(&next.rows[row_idx], &columns[col_idx]);
+            }
+        }
+        Transform {
+            rows: [
+                Vec3::from(rows[0]),
+                Vec3::from(rows[1]),
+                Vec3::from(rows[2]),
+            ],
+            translation: next.transform_point(&self.translation),
+        }
+    }
+
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(
+            Vec3::dot(&self.rows[0], v),
+            Vec3::dot(&self.rows[1], v),
+            Vec3::dot(&self.rows[2], v),
+        )
+    }
+
+    pub fn transform_point(&self, p: &Vec3) -> Vec3 {
+        self.transform_vector(p) + self.translation
+    }
+
+    /// Normals go through the inverse transpose of the linear part, so that they stay
+    /// perpendicular to the transformed surface. The result is normalized.
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        let [a, b, c] = &self.rows;
+        // rows of the inverse transpose, up to the determinant
+        let bc = Vec3::cross(b, c);
+        let ca = Vec3::cross(c, a);
+        let ab = Vec3::cross(a, b);
+        let det = Vec3::dot(a, &bc);
+        let transformed = Vec3::new(Vec3::dot(&bc, n), Vec3::dot(&ca, n), Vec3::dot(&ab, n));
+        transformed.normalize() * det.signum()
+    }
+}
+
+/// Placement of a mesh: scaled, then rotated by Euler angles in degrees applied in the x, y, z
+/// order, then translated.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Placement {
+    pub translate: Vec3,
+    pub rotate: Vec3,
+    pub scale: Vec3,
+}
+
+impl Default for Placement {
+    fn default() -> Placement {
+        Placement {
+            translate: Vec3::zero(),
+            rotate: Vec3::zero(),
+            scale: Vec3::fill(1.0),
+        }
+    }
+}
+
+impl Placement {
+    pub fn transform(&self) -> Transform {
+        Transform::scale(&self.scale)
+            .then(&Transform::rotate_xyz(&self.rotate))
+            .then(&Transform::translate(&self.translate))
+    }
+
+    /// Placement at `t` between `self` at 0 and `end` at 1, interpolating the translation, the
+    /// angles and the scale, so that a turning mesh keeps its shape.
+    pub fn lerp(&self, end: &Placement, t: f32) -> Placement {
+        Placement {
+            translate: Vec3::lerp(&self.translate, &end.translate, t),
+            rotate: Vec3::lerp(&self.rotate, &end.rotate, t),
+            scale: Vec3::lerp(&self.scale, &end.scale, t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 0.0001, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn identity() {
+        let p = Vec3::new(1.0, 2.0, 3.0);
+        assert_eq!(p, Transform::identity().transform_point(&p));
+    }
+
+    #[test]
+    fn rotate() {
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        assert_near(y, Transform::rotate(2, 90.0).transform_vector(&x));
+        assert_near(z, Transform::rotate(0, 90.0).transform_vector(&y));
+        assert_near(x, Transform::rotate(1, 90.0).transform_vector(&z));
+    }
+
+    #[test]
+    fn compose() {
+        let p = Vec3::new(1.0, 0.0, 0.0);
+        let transform = Transform::scale(&Vec3::fill(2.0))
+            .then(&Transform::rotate(2, 90.0))
+            .then(&Transform::translate(&Vec3::new(0.0, 0.0, 5.0)));
+        assert_near(Vec3::new(0.0, 2.0, 5.0), transform.transform_point(&p));
+        assert_near(Vec3::new(0.0, 2.0, 0.0), transform.transform_vector(&p));
+    }
+
+    #[test]
+    fn normal() {
+        // a 45 degrees slope, squashed vertically
+        let transform = Transform::scale(&Vec3::new(1.0, 0.5, 1.0));
+        let tangent = Vec3::new(1.0, 1.0, 0.0);
+        let n = Vec3::new(-1.0, 1.0, 0.0).normalize();
+        let transformed_tangent = transform.transform_vector(&tangent);
+        let transformed_n = transform.transform_normal(&n);
+        assert!(Vec3::dot(&transformed_tangent, &transformed_n).abs() < 0.0001);
+        assert!((transformed_n.length() - 1.0).abs() < 0.0001);
+        // mirroring keeps the normal on the same side of the surface
+        let mirror = Transform::scale(&Vec3::new(-1.0, 1.0, 1.0));
+        assert_near(
+            Vec3::new(1.0, 1.0, 0.0).normalize(),
+            mirror.transform_normal(&n),
+        );
+    }
+
+    #[test]
+    fn placement() {
+        let start = Placement::default();
+        let end = Placement {
+            translate: Vec3::new(2.0, 0.0, 0.0),
+            rotate: Vec3::new(0.0, 180.0, 0.0),
+            scale: Vec3::fill(3.0),
+        };
+        let p = Vec3::new(1.0, 0.0, 0.0);
+        assert_near(p, start.transform().transform_point(&p));
+        assert_near(
+            Vec3::new(-1.0, 0.0, 0.0),
+            end.transform().transform_point(&p),
+        );
+        // halfway through the turn, not through its center
+        let halfway = start.lerp(&end, 0.5).transform();
+        assert_near(Vec3::new(1.0, 0.0, -2.0), halfway.transform_point(&p));
+    }
+}
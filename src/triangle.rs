@@ -1,23 +1,239 @@
 use crate::hit::Hit;
 use crate::ray::*;
+use crate::transform::{Placement, Transform};
 use crate::vec3::*;
 
+/// Motion of a triangle of a moving mesh: the placement of the mesh is interpolated between
+/// its keyframes at the times 0 and 1, and applied to the vertices in the space of the mesh.
+#[derive(Clone)]
+pub struct TriangleMotion {
+    /// Vertices and shading normals in the space of the mesh.
+    pub vertices: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub start: Placement,
+    pub end: Placement,
+}
+
+impl TriangleMotion {
+    /// Transform of the mesh at `time`. The motion stops at the times 0 and 1, which the BVH
+    /// bounds.
+    #[inline]
+    pub fn transform_at(&self, time: f32) -> Transform {
+        return self.start.lerp(&self.end, time.clamp(0.0, 1.0)).transform();
+    }
+
+    pub fn vertices_at(&self, time: f32) -> [Vec3; 3] {
+        let transform = self.transform_at(time);
+        let [v0, v1, v2] = &self.vertices;
+        return [
+            transform.transform_point(v0),
+            transform.transform_point(v1),
+            transform.transform_point(v2),
+        ];
+    }
+}
+
+/// Intersection found by `Triangle::intersect_distance`.
+#[derive(Clone, Copy)]
+pub struct TriangleHit {
+    pub t: f32,
+    /// Weights of the three vertices at the hit point.
+    pub barycentric: [f32; 3],
+}
+
+#[derive(Clone)]
 pub struct Triangle {
     pub vertices: [Vec3; 3],
+    /// Per-vertex normals used for smooth shading, normalized.
+    pub normals: Option<[Vec3; 3]>,
+    /// Per-vertex texture coordinates.
+    pub uvs: Option<[[f32; 2]; 3]>,
+    /// Index of the material in the scene.
+    pub material: usize,
+    /// End of the motion of moving triangles, boxed to keep the static ones small.
+    pub motion: Option<Box<TriangleMotion>>,
 }
 
 impl Triangle {
     pub fn new(v0: Vec3, v1: Vec3, v2: Vec3) -> Triangle {
         Triangle {
             vertices: [v0, v1, v2],
+            normals: None,
+            uvs: None,
+            material: 0,
+            motion: None,
+        }
+    }
+
+    /// Vertices at `time`.
+    #[inline]
+    pub fn vertices_at(&self, time: f32) -> [Vec3; 3] {
+        match &self.motion {
+            None => self.vertices,
+            Some(motion) => motion.vertices_at(time),
+        }
+    }
+
+    /// Shading normals at `time`.
+    fn normals_at(&self, time: f32) -> Option<[Vec3; 3]> {
+        match (&self.normals, &self.motion) {
+            (Some(_), Some(motion)) => motion.normals.map(|[n0, n1, n2]| {
+                let transform = motion.transform_at(time);
+                [
+                    transform.transform_normal(&n0),
+                    transform.transform_normal(&n1),
+                    transform.transform_normal(&n2),
+                ]
+            }),
+            (normals, _) => *normals,
+        }
+    }
+
+    /// Texture coordinates at `barycentric`, and the direction of increasing `u` projected on
+    /// the plane of normal `normal`.
+    fn surface_coords(
+        &self,
+        vertices: &[Vec3; 3],
+        barycentric: &[f32; 3],
+        normal: &Vec3,
+    ) -> ([f32; 2], Vec3) {
+        let edge0 = vertices[1] - vertices[0];
+        let edge1 = vertices[2] - vertices[0];
+        let uvs = match &self.uvs {
+            Some(uvs) => uvs,
+            None => {
+                return ([barycentric[1], barycentric[2]], edge0.normalize());
+            }
+        };
+        let uv = [
+            uvs[0][0] * barycentric[0] + uvs[1][0] * barycentric[1] + uvs[2][0] * barycentric[2],
+            uvs[0][1] * barycentric[0] + uvs[1][1] * barycentric[1] + uvs[2][1] * barycentric[2],
+        ];
+        // solve edge = du * dp/du + dv * dp/dv for dp/du
+        let du0 = uvs[1][0] - uvs[0][0];
+        let dv0 = uvs[1][1] - uvs[0][1];
+        let du1 = uvs[2][0] - uvs[0][0];
+        let dv1 = uvs[2][1] - uvs[0][1];
+        let det = du0 * dv1 - dv0 * du1;
+        let mut tangent = if det.abs() > 1e-12 {
+            (edge0 * dv1 - edge1 * dv0) * (1.0 / det)
+        } else {
+            edge0
+        };
+        tangent = tangent - *normal * Vec3::dot(&tangent, normal);
+        if tangent.length_sq() < 1e-12 {
+            tangent = edge0;
         }
+        return (uv, tangent.normalize());
     }
 
+    /// Distance and barycentric coordinates of the hit of `ray` between `tmin` and `tmax`, with
+    /// the watertight test of Woop, Benthin and Wald: the vertices are moved to a space where the
+    /// ray goes along the unit z axis from the origin, and the signs of the 2D edge functions
+    /// tell whether it passes inside. Rays going through a shared edge or vertex hit at least one
+    /// of the triangles. Moving triangles are intersected where they are at the time of the ray.
+    #[inline]
+    pub fn intersect_distance(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<TriangleHit> {
+        let [shear_x, shear_y, shear_z] = ray.shear();
+        let origin = ray.origin();
+        let vertices = self.vertices_at(ray.time());
+        let a = vertices[0] - origin;
+        let b = vertices[1] - origin;
+        let c = vertices[2] - origin;
+        let ax = Vec3::dot(&a, shear_x);
+        let ay = Vec3::dot(&a, shear_y);
+        let bx = Vec3::dot(&b, shear_x);
+        let by = Vec3::dot(&b, shear_y);
+        let cx = Vec3::dot(&c, shear_x);
+        let cy = Vec3::dot(&c, shear_y);
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            // on an edge in single precision, decide with double precision
+            let (ax, ay, bx, by, cx, cy) = (
+                ax as f64, ay as f64, bx as f64, by as f64, cx as f64, cy as f64,
+            );
+            u = (cx * by - cy * bx) as f32;
+            v = (ax * cy - ay * cx) as f32;
+            w = (bx * ay - by * ax) as f32;
+        }
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+        let det = u + v + w;
+        if det == 0.0 {
+            return None;
+        }
+        let az = Vec3::dot(&a, shear_z);
+        let bz = Vec3::dot(&b, shear_z);
+        let cz = Vec3::dot(&c, shear_z);
+        let t = (u * az + v * bz + w * cz) / det;
+        if !(t >= tmin && t <= tmax) {
+            return None;
+        }
+
+        let inv_det = 1.0 / det;
+        return Some(TriangleHit {
+            t,
+            barycentric: [u * inv_det, v * inv_det, w * inv_det],
+        });
+    }
+
+    /// Shading data of a hit found by `intersect_distance`, only computed for the closest one.
+    pub fn hit(&self, ray: &Ray, triangle_hit: &TriangleHit) -> Hit {
+        let TriangleHit { t, barycentric } = *triangle_hit;
+        let vertices = self.vertices_at(ray.time());
+        let normal =
+            Vec3::cross(&(vertices[1] - vertices[0]), &(vertices[2] - vertices[1])).normalize();
+        let shading_normal = match &self.normals_at(ray.time()) {
+            Some(normals) => {
+                let interpolated = normals[0] * barycentric[0]
+                    + normals[1] * barycentric[1]
+                    + normals[2] * barycentric[2];
+                if interpolated.length_sq() > 0.0 {
+                    interpolated.normalize()
+                } else {
+                    normal
+                }
+            }
+            None => normal,
+        };
+        let (uv, tangent) = self.surface_coords(&vertices, &barycentric, &normal);
+        return Hit {
+            pos: ray.point_at(t),
+            normal,
+            shading_normal,
+            tangent,
+            barycentric,
+            uv,
+            t,
+            material: self.material,
+        };
+    }
+
+    /// Hit of `ray` between `tmin` and `tmax`, with its shading data.
     pub fn intersect(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<Hit> {
-        let edge0 = self.vertices[1] - self.vertices[0];
-        let edge1 = self.vertices[2] - self.vertices[1];
-        let normal = Vec3::cross(&edge0, &edge1).normalize();
-        let plane_offset = Vec3::dot(&self.vertices[0], &normal);
+        self.intersect_distance(ray, tmin, tmax)
+            .map(|triangle_hit| self.hit(ray, &triangle_hit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::random_float01;
+
+    /// Previous implementation, intersecting the plane by interpolating between the points at
+    /// `tmin` and `tmax`.
+    fn reference_intersect(triangle: &Triangle, ray: &Ray, tmin: f32, tmax: f32) -> Option<Hit> {
+        let edge0 = triangle.vertices[1] - triangle.vertices[0];
+        let edge1 = triangle.vertices[2] - triangle.vertices[1];
+        let cross = Vec3::cross(&edge0, &edge1);
+        let double_area = cross.length();
+        let normal = cross * (1.0 / double_area);
+        let plane_offset = Vec3::dot(&triangle.vertices[0], &normal);
 
         let p0 = ray.point_at(tmin);
         let p1 = ray.point_at(tmax);
@@ -29,16 +245,43 @@ impl Triangle {
             let t = tmin + (tmax - tmin) * (plane_offset - offset0) / (offset1 - offset0);
             let p = ray.point_at(t);
 
-            let c0 = Vec3::cross(&edge0, &(p - self.vertices[0]));
-            let c1 = Vec3::cross(&edge1, &(p - self.vertices[1]));
+            let c0 = Vec3::cross(&edge0, &(p - triangle.vertices[0]));
+            let c1 = Vec3::cross(&edge1, &(p - triangle.vertices[1]));
             if Vec3::dot(&c0, &c1) >= 0.0 {
-                let edge2 = self.vertices[0] - self.vertices[2];
-                let c2 = Vec3::cross(&edge2, &(p - self.vertices[2]));
+                let edge2 = triangle.vertices[0] - triangle.vertices[2];
+                let c2 = Vec3::cross(&edge2, &(p - triangle.vertices[2]));
                 if Vec3::dot(&c1, &c2) >= 0.0 {
+                    // each sub-triangle area weights the opposite vertex
+                    let inv_double_area = 1.0 / double_area;
+                    let barycentric = [
+                        Vec3::dot(&c1, &normal) * inv_double_area,
+                        Vec3::dot(&c2, &normal) * inv_double_area,
+                        Vec3::dot(&c0, &normal) * inv_double_area,
+                    ];
+                    let shading_normal = match &triangle.normals {
+                        Some(normals) => {
+                            let interpolated = normals[0] * barycentric[0]
+                                + normals[1] * barycentric[1]
+                                + normals[2] * barycentric[2];
+                            if interpolated.length_sq() > 0.0 {
+                                interpolated.normalize()
+                            } else {
+                                normal
+                            }
+                        }
+                        None => normal,
+                    };
+                    let (uv, tangent) =
+                        triangle.surface_coords(&triangle.vertices, &barycentric, &normal);
                     let hit = Hit {
                         pos: p,
-                        normal: normal,
-                        t: t,
+                        normal,
+                        shading_normal,
+                        tangent,
+                        barycentric,
+                        uv,
+                        t,
+                        material: triangle.material,
                     };
                     return Some(hit);
                 }
@@ -46,11 +289,14 @@ impl Triangle {
         }
         None
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn random_point(rng: &mut u32, scale: f32) -> Vec3 {
+        Vec3::new(
+            random_float01(rng) - 0.5,
+            random_float01(rng) - 0.5,
+            random_float01(rng) - 0.5,
+        ) * scale
+    }
 
     #[test]
     fn triangle_intersection() {
@@ -70,4 +316,175 @@ mod tests {
         let ray_not_intersect = Ray::new(&Vec3::new(0.5, 0.5, -0.5), &Vec3::new(0.0, 0.0, 1.0));
         assert!(triangle.intersect(&ray_not_intersect, 0.0, 1.0).is_none());
     }
+
+    #[test]
+    fn shading_normal() {
+        let mut triangle = Triangle::new(
+            Vec3::new(-0.5, -0.5, 0.0),
+            Vec3::new(0.0, 0.5, 0.0),
+            Vec3::new(0.5, -0.5, 0.0),
+        );
+        let ray = Ray::new(&Vec3::new(-0.5, -0.5, -0.5), &Vec3::new(0.0, 0.0, 1.0));
+        let hit = triangle.intersect(&ray, 0.0, 1.0).unwrap();
+        assert_eq!(hit.normal, hit.shading_normal);
+
+        let n0 = Vec3::new(-1.0, 0.0, -1.0).normalize();
+        let n1 = Vec3::new(0.0, 0.0, -1.0);
+        triangle.normals = Some([n0, n1, n1]);
+        // on the first vertex
+        let hit = triangle.intersect(&ray, 0.0, 1.0).unwrap();
+        assert!((hit.shading_normal - n0).length() < 0.001);
+        assert!((hit.normal - n1).length() < 0.001);
+        // halfway between the first and third vertices
+        let ray = Ray::new(&Vec3::new(0.0, -0.5, -0.5), &Vec3::new(0.0, 0.0, 1.0));
+        let hit = triangle.intersect(&ray, 0.0, 1.0).unwrap();
+        let expected = (n0 + n1).normalize();
+        assert!((hit.shading_normal - expected).length() < 0.001);
+    }
+
+    #[test]
+    fn texture_coordinates() {
+        let mut triangle = Triangle::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        let ray = Ray::new(&Vec3::new(0.25, 0.5, -1.0), &Vec3::new(0.0, 0.0, 1.0));
+        let hit = triangle.intersect(&ray, 0.0, 2.0).unwrap();
+        assert!((hit.barycentric[0] - 0.25).abs() < 0.001);
+        assert!((hit.uv[0] - 0.25).abs() < 0.001 && (hit.uv[1] - 0.5).abs() < 0.001);
+
+        // u along y, v along -x
+        triangle.uvs = Some([[0.5, 0.5], [0.5, 0.0], [1.0, 0.5]]);
+        let hit = triangle.intersect(&ray, 0.0, 2.0).unwrap();
+        assert!((hit.uv[0] - 0.75).abs() < 0.001 && (hit.uv[1] - 0.375).abs() < 0.001);
+        assert!((hit.tangent - Vec3::new(0.0, 1.0, 0.0)).length() < 0.001);
+    }
+
+    #[test]
+    fn matches_reference() {
+        let mut rng = 0x1234567u32;
+        let mut compared = 0;
+        for _ in 0..5000 {
+            let triangle = Triangle::new(
+                random_point(&mut rng, 2.0),
+                random_point(&mut rng, 2.0),
+                random_point(&mut rng, 2.0),
+            );
+            let origin = random_point(&mut rng, 10.0);
+            let center =
+                (triangle.vertices[0] + triangle.vertices[1] + triangle.vertices[2]) * 0.333;
+            let target = center + random_point(&mut rng, 1.0);
+            let ray = Ray::new(&origin, &(target - origin).normalize());
+            let hit = triangle.intersect(&ray, 0.0, 20.0);
+            let expected = reference_intersect(&triangle, &ray, 0.0, 20.0);
+            match (hit, expected) {
+                (Some(hit), Some(expected)) => {
+                    assert!((hit.t - expected.t).abs() < 1e-3);
+                    assert!((hit.pos - expected.pos).length() < 1e-3);
+                    assert!((hit.normal - expected.normal).length() < 1e-3);
+                    for idx in 0..3 {
+                        assert!((hit.barycentric[idx] - expected.barycentric[idx]).abs() < 1e-3);
+                    }
+                    compared += 1;
+                }
+                (None, None) => {}
+                // the implementations only disagree on the edges
+                (Some(hit), None) | (None, Some(hit)) => {
+                    assert!(hit.barycentric.iter().any(|&b| b.abs() < 1e-3));
+                }
+            }
+        }
+        assert!(compared > 1000);
+    }
+
+    #[test]
+    fn watertight() {
+        // planar quad split along its diagonal, with vertices that are not exactly representable
+        let on_plane = |x: f32, y: f32| Vec3::new(x, y, 0.3 * x - 0.2 * y + 0.1);
+        let corners = [
+            on_plane(-0.3, -0.7),
+            on_plane(0.9, -0.3),
+            on_plane(0.7, 0.9),
+            on_plane(-0.1, 0.3),
+        ];
+        let first = Triangle::new(corners[0], corners[1], corners[2]);
+        let second = Triangle::new(corners[0], corners[2], corners[3]);
+        let mut rng = 0x7654321u32;
+        for idx in 0..10000 {
+            // aim at the shared edge, away from the corners of the quad
+            let fraction = 0.01 + 0.98 * idx as f32 / 10000.0;
+            let target = corners[0] + (corners[2] - corners[0]) * fraction;
+            let origin = target + random_point(&mut rng, 4.0) + Vec3::new(0.0, 0.0, 3.0);
+            let ray = Ray::new(&origin, &(target - origin).normalize());
+            let hit = first.intersect(&ray, 0.0, 100.0);
+            let hit = hit.or_else(|| second.intersect(&ray, 0.0, 100.0));
+            assert!(hit.is_some(), "ray {} went through the shared edge", idx);
+        }
+    }
+
+    #[test]
+    fn precision() {
+        // far from the origin, with a large interval
+        let triangle = Triangle::new(
+            Vec3::new(-1.0, -1.0, 50.0),
+            Vec3::new(1.0, -1.0, 50.0),
+            Vec3::new(0.0, 1.0, 50.0),
+        );
+        let dir = Vec3::new(0.01, 0.0, 1.0).normalize();
+        let ray = Ray::new(&Vec3::zero(), &dir);
+        let expected_t = 50.0 / dir.z();
+        let hit = triangle.intersect(&ray, 0.01, 1e6).unwrap();
+        assert!((hit.t - expected_t).abs() < 1e-4);
+        assert!((hit.pos.z() - 50.0).abs() < 1e-4);
+        assert!(triangle.intersect(&ray, 0.01, expected_t - 0.01).is_none());
+        assert!(triangle.intersect(&ray, expected_t + 0.01, 1e6).is_none());
+    }
+
+    #[test]
+    fn moving() {
+        let mut triangle = Triangle::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+        );
+        triangle.normals = Some([Vec3::new(0.0, 0.0, 1.0); 3]);
+        // slides by 2 along x while turning to face +x
+        triangle.motion = Some(Box::new(TriangleMotion {
+            vertices: triangle.vertices,
+            normals: triangle.normals,
+            start: Placement::default(),
+            end: Placement {
+                translate: Vec3::new(2.0, 0.0, 0.0),
+                rotate: Vec3::new(0.0, 90.0, 0.0),
+                ..Placement::default()
+            },
+        }));
+        let ray = Ray::new(&Vec3::new(0.25, 0.25, 5.0), &Vec3::new(0.0, 0.0, -1.0));
+        let hit = triangle.intersect(&ray, 0.0, 100.0).unwrap();
+        assert!((hit.t - 5.0).abs() < 1e-5);
+        assert_eq!(Vec3::new(0.0, 0.0, 1.0), hit.shading_normal);
+        assert!(triangle
+            .intersect(&ray.with_time(1.0), 0.0, 100.0)
+            .is_none());
+
+        let ray = Ray::new(&Vec3::new(5.0, 0.25, -0.25), &Vec3::new(-1.0, 0.0, 0.0));
+        assert!(triangle.intersect(&ray, 0.0, 100.0).is_none());
+        let hit = triangle.intersect(&ray.with_time(1.0), 0.0, 100.0).unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-5);
+        assert!((hit.shading_normal - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-5);
+        // past the end of the motion, where the BVH bounds it
+        let ray = Ray::new(&Vec3::new(5.0, 0.25, -0.25), &Vec3::new(-1.0, 0.0, 0.0));
+        let hit = triangle.intersect(&ray.with_time(3.0), 0.0, 100.0).unwrap();
+        assert!((hit.t - 3.0).abs() < 1e-5);
+        // halfway, at x = 1 with the triangle turned by 45 degrees and of the same size
+        let [v0, v1, v2] = triangle.vertices_at(0.5);
+        assert!(((v1 - v0).length() - 1.0).abs() < 1e-5);
+        assert!(((v2 - v0).length() - 1.0).abs() < 1e-5);
+        let ray = Ray::new(&Vec3::new(1.2, 0.25, 5.0), &Vec3::new(0.0, 0.0, -1.0));
+        let hit = triangle.intersect(&ray.with_time(0.5), 0.0, 100.0).unwrap();
+        assert!((hit.pos - Vec3::new(1.2, 0.25, -0.2)).length() < 1e-5);
+        let turned = Vec3::new(1.0, 0.0, 1.0).normalize();
+        assert!((hit.shading_normal - turned).length() < 1e-5);
+    }
 }
@@ -16,11 +16,11 @@ impl Vec3 {
     }
 
     pub fn min_value() -> Vec3 {
-        Vec3::fill(std::f32::NEG_INFINITY)
+        Vec3::fill(f32::NEG_INFINITY)
     }
 
     pub fn max_value() -> Vec3 {
-        Vec3::fill(std::f32::INFINITY)
+        Vec3::fill(f32::INFINITY)
     }
 
     pub fn new(x: f32, y: f32, z: f32) -> Vec3 {
@@ -65,7 +65,7 @@ impl Vec3 {
     }
 
     pub fn length_sq(&self) -> f32 {
-        Vec3::dot(&self, &self)
+        Vec3::dot(self, self)
     }
 
     pub fn length(&self) -> f32 {
@@ -73,7 +73,7 @@ impl Vec3 {
     }
 
     pub fn hmax(&self) -> f32 {
-        let mut result = std::f32::NEG_INFINITY;
+        let mut result = f32::NEG_INFINITY;
         for idx in 0..3 {
             result = result.max(self.data[idx]);
         }
@@ -81,13 +81,18 @@ impl Vec3 {
     }
 
     pub fn hmin(&self) -> f32 {
-        let mut result = std::f32::INFINITY;
+        let mut result = f32::INFINITY;
         for idx in 0..3 {
             result = result.min(self.data[idx]);
         }
         return result;
     }
 
+    /// Perceived brightness of a linear RGB color, with the Rec. 709 weights.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.x() + 0.7152 * self.y() + 0.0722 * self.z()
+    }
+
     pub fn to_array(self) -> [f32; 3] {
         return self.data;
     }
@@ -126,6 +131,11 @@ impl Vec3 {
     pub fn normalize(&self) -> Vec3 {
         *self * (1.0f32 / self.length())
     }
+
+    /// `self` at `t` = 0, moving linearly to `b` at `t` = 1.
+    pub fn lerp(&self, b: &Vec3, t: f32) -> Vec3 {
+        *self + (*b - *self) * t
+    }
 }
 
 impl From<[f32; 3]> for Vec3 {
@@ -0,0 +1,475 @@
+//! Four-wide BVH, collapsed from the binary `Bvh`. Each node holds the bounds of its four
+//! children in SoA form, so that a ray is tested against all of them at once with SSE or AVX
+//! when the CPU supports it.
+
+use crate::bvh::*;
+use crate::hit::*;
+use crate::ray::*;
+use crate::triangle::*;
+
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Number of children of a node.
+const WIDTH: usize = 4;
+/// `WideNode::counts` value of unused child slots.
+const EMPTY: u32 = u32::MAX;
+/// The binary tree is at most 64 levels deep, and each wide node pushes at most 3 more nodes
+/// than it pops.
+const STACK_SIZE: usize = 64 * (WIDTH - 1) + 1;
+
+/// Instruction set used to test the child boxes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SimdPath {
+    Scalar,
+    Sse,
+    Avx,
+}
+
+impl SimdPath {
+    /// Widest path supported by the running CPU.
+    pub fn detect() -> SimdPath {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx") {
+                return SimdPath::Avx;
+            }
+            // SSE2 is part of x86_64
+            return SimdPath::Sse;
+        }
+        #[allow(unreachable_code)]
+        SimdPath::Scalar
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            SimdPath::Scalar => true,
+            SimdPath::Sse => cfg!(target_arch = "x86_64"),
+            SimdPath::Avx => SimdPath::detect() == SimdPath::Avx,
+        }
+    }
+}
+
+/// 128 bytes, two cache lines.
+#[derive(Clone, Copy)]
+#[repr(C, align(32))]
+struct WideNode {
+    /// For each axis, the minimum of the four children followed by their maximum. Unused slots
+    /// are NaN so that they never hit.
+    bounds: [[f32; 2 * WIDTH]; 3],
+    /// Index of the child node, or of the first triangle of leaf children.
+    children: [u32; WIDTH],
+    /// Triangle count of leaf children, 0 for child nodes and `EMPTY` for unused slots, which
+    /// come last.
+    counts: [u32; WIDTH],
+}
+
+/// Ray data broadcast to the box tests.
+struct WideRay {
+    origin: [f32; 3],
+    dir_inv: [f32; 3],
+}
+
+/// Test of a ray against the four children of a node: the entry distance of each child, and a
+/// bit mask of the children hit between `tmin` and `tmax`.
+trait BoxTest {
+    /// # Safety
+    /// The instruction set of the implementation must be supported by the CPU.
+    unsafe fn test(node: &WideNode, ray: &WideRay, tmin: f32, tmax: f32) -> ([f32; WIDTH], u32);
+}
+
+struct ScalarTest;
+
+impl BoxTest for ScalarTest {
+    #[inline(always)]
+    unsafe fn test(node: &WideNode, ray: &WideRay, tmin: f32, tmax: f32) -> ([f32; WIDTH], u32) {
+        let mut near = [tmin; WIDTH];
+        let mut mask = 0;
+        for (child, near) in near.iter_mut().enumerate() {
+            let mut far = tmax;
+            for axis in 0..3 {
+                let t0 = (node.bounds[axis][child] - ray.origin[axis]) * ray.dir_inv[axis];
+                let t1 = (node.bounds[axis][WIDTH + child] - ray.origin[axis]) * ray.dir_inv[axis];
+                *near = near.max(t0.min(t1));
+                far = far.min(t0.max(t1));
+            }
+            if *near <= far {
+                mask |= 1 << child;
+            }
+        }
+        (near, mask)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+struct SseTest;
+
+#[cfg(target_arch = "x86_64")]
+impl BoxTest for SseTest {
+    #[inline(always)]
+    unsafe fn test(node: &WideNode, ray: &WideRay, tmin: f32, tmax: f32) -> ([f32; WIDTH], u32) {
+        let mut near = _mm_set1_ps(tmin);
+        let mut far = _mm_set1_ps(tmax);
+        for axis in 0..3 {
+            let origin = _mm_set1_ps(ray.origin[axis]);
+            let dir_inv = _mm_set1_ps(ray.dir_inv[axis]);
+            let row = node.bounds[axis].as_ptr();
+            let t0 = _mm_mul_ps(_mm_sub_ps(_mm_load_ps(row), origin), dir_inv);
+            let t1 = _mm_mul_ps(_mm_sub_ps(_mm_load_ps(row.add(WIDTH)), origin), dir_inv);
+            near = _mm_max_ps(near, _mm_min_ps(t0, t1));
+            far = _mm_min_ps(far, _mm_max_ps(t0, t1));
+        }
+        let mask = _mm_movemask_ps(_mm_cmple_ps(near, far)) as u32;
+        let mut distances = [0f32; WIDTH];
+        _mm_storeu_ps(distances.as_mut_ptr(), near);
+        (distances, mask)
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+struct AvxTest;
+
+#[cfg(target_arch = "x86_64")]
+impl BoxTest for AvxTest {
+    /// Both planes of an axis are handled by a single 8-wide operation.
+    #[inline]
+    #[target_feature(enable = "avx")]
+    unsafe fn test(node: &WideNode, ray: &WideRay, tmin: f32, tmax: f32) -> ([f32; WIDTH], u32) {
+        let mut near = _mm_set1_ps(tmin);
+        let mut far = _mm_set1_ps(tmax);
+        for axis in 0..3 {
+            let origin = _mm256_set1_ps(ray.origin[axis]);
+            let dir_inv = _mm256_set1_ps(ray.dir_inv[axis]);
+            let planes = _mm256_load_ps(node.bounds[axis].as_ptr());
+            let t = _mm256_mul_ps(_mm256_sub_ps(planes, origin), dir_inv);
+            let t0 = _mm256_castps256_ps128(t);
+            let t1 = _mm256_extractf128_ps::<1>(t);
+            near = _mm_max_ps(near, _mm_min_ps(t0, t1));
+            far = _mm_min_ps(far, _mm_max_ps(t0, t1));
+        }
+        let mask = _mm_movemask_ps(_mm_cmple_ps(near, far)) as u32;
+        let mut distances = [0f32; WIDTH];
+        _mm_storeu_ps(distances.as_mut_ptr(), near);
+        (distances, mask)
+    }
+}
+
+pub struct WideBvh {
+    nodes: Vec<WideNode>,
+    simd_path: SimdPath,
+}
+
+impl WideBvh {
+    /// Collapse `bvh`, whose triangle order is kept, using the widest SIMD path of the CPU.
+    pub fn from_bvh(bvh: &Bvh) -> WideBvh {
+        let mut nodes = Vec::with_capacity(bvh.nodes().len() / 2 + 1);
+        if !bvh.nodes().is_empty() {
+            WideBvh::collapse(bvh.nodes(), 0, &mut nodes);
+        }
+        WideBvh {
+            nodes,
+            simd_path: SimdPath::detect(),
+        }
+    }
+
+    /// Use another instruction set, which must be supported.
+    pub fn with_simd_path(mut self, simd_path: SimdPath) -> WideBvh {
+        assert!(simd_path.is_supported());
+        self.simd_path = simd_path;
+        self
+    }
+
+    pub fn simd_path(&self) -> SimdPath {
+        self.simd_path
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Append the wide node replacing the binary node `idx` and its descendants, and return its
+    /// index. Its children are found by opening the largest of the binary children until there
+    /// are four of them, or only leaves left.
+    fn collapse(binary: &[BvhNode], idx: usize, nodes: &mut Vec<WideNode>) -> usize {
+        let node_idx = nodes.len();
+        nodes.push(WideNode {
+            bounds: [[f32::NAN; 2 * WIDTH]; 3],
+            children: [0; WIDTH],
+            counts: [EMPTY; WIDTH],
+        });
+
+        let mut children: Vec<usize> = if binary[idx].is_leaf() {
+            vec![idx]
+        } else {
+            vec![idx + 1, binary[idx].right_child()]
+        };
+        while children.len() < WIDTH {
+            let largest = children
+                .iter()
+                .enumerate()
+                .filter(|(_, &child)| !binary[child].is_leaf())
+                .max_by(|(_, &a), (_, &b)| {
+                    let area_a = binary[a].bounds().surface_area();
+                    let area_b = binary[b].bounds().surface_area();
+                    area_a.partial_cmp(&area_b).unwrap()
+                })
+                .map(|(position, _)| position);
+            match largest {
+                Some(position) => {
+                    let child = children[position];
+                    children[position] = child + 1;
+                    children.insert(position + 1, binary[child].right_child());
+                }
+                None => break,
+            }
+        }
+
+        let mut node = nodes[node_idx];
+        for (slot, &child) in children.iter().enumerate() {
+            let bounds = binary[child].bounds();
+            for axis in 0..3 {
+                node.bounds[axis][slot] = bounds.min().get(axis);
+                node.bounds[axis][WIDTH + slot] = bounds.max().get(axis);
+            }
+            if binary[child].is_leaf() {
+                let triangles = binary[child].triangles();
+                node.children[slot] = triangles.start as u32;
+                node.counts[slot] = triangles.len() as u32;
+            } else {
+                node.children[slot] = WideBvh::collapse(binary, child, nodes) as u32;
+                node.counts[slot] = 0;
+            }
+        }
+        nodes[node_idx] = node;
+        return node_idx;
+    }
+
+    /// Same as `Bvh::intersect`. `triangles` must be the list the binary tree was built from.
+    pub fn intersect(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        hit_type: HitType,
+        triangles: &[Triangle],
+    ) -> Option<Hit> {
+        let mut counts = TraversalCounts::default();
+        self.dispatch::<false>(ray, tmin, tmax, hit_type, triangles, &mut counts)
+    }
+
+    /// Same as `intersect`, adding the wide nodes entered and the triangles tested to `counts`.
+    pub fn intersect_counted(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        hit_type: HitType,
+        triangles: &[Triangle],
+        counts: &mut TraversalCounts,
+    ) -> Option<Hit> {
+        self.dispatch::<true>(ray, tmin, tmax, hit_type, triangles, counts)
+    }
+
+    #[inline(always)]
+    fn dispatch<const COUNT: bool>(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        hit_type: HitType,
+        triangles: &[Triangle],
+        counts: &mut TraversalCounts,
+    ) -> Option<Hit> {
+        match self.simd_path {
+            SimdPath::Scalar => unsafe {
+                self.traverse::<ScalarTest, COUNT>(ray, tmin, tmax, hit_type, triangles, counts)
+            },
+            #[cfg(target_arch = "x86_64")]
+            SimdPath::Sse => unsafe {
+                self.traverse::<SseTest, COUNT>(ray, tmin, tmax, hit_type, triangles, counts)
+            },
+            #[cfg(target_arch = "x86_64")]
+            SimdPath::Avx => unsafe {
+                self.traverse_avx::<COUNT>(ray, tmin, tmax, hit_type, triangles, counts)
+            },
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+    }
+
+    /// Compiles the whole traversal with AVX enabled, so that the box test gets inlined.
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx")]
+    unsafe fn traverse_avx<const COUNT: bool>(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        hit_type: HitType,
+        triangles: &[Triangle],
+        counts: &mut TraversalCounts,
+    ) -> Option<Hit> {
+        self.traverse::<AvxTest, COUNT>(ray, tmin, tmax, hit_type, triangles, counts)
+    }
+
+    /// Walk the tree with an explicit stack. The children hit are visited from the nearest:
+    /// leaves are intersected right away, and child nodes pushed so that the nearest is popped
+    /// first. Entries farther than the closest hit are skipped.
+    ///
+    /// # Safety
+    /// The instruction set of `T` must be supported by the CPU.
+    #[inline(always)]
+    unsafe fn traverse<T: BoxTest, const COUNT: bool>(
+        &self,
+        ray: &Ray,
+        tmin: f32,
+        tmax: f32,
+        hit_type: HitType,
+        triangles: &[Triangle],
+        counts: &mut TraversalCounts,
+    ) -> Option<Hit> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let wide_ray = WideRay {
+            origin: ray.origin().to_array(),
+            dir_inv: ray.dir_inv().to_array(),
+        };
+        let mut tmax = tmax;
+        let mut hit = None;
+        let mut stack = [(0u32, 0f32); STACK_SIZE];
+        stack[0] = (0, tmin);
+        let mut stack_len = 1;
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (idx, t_entry) = stack[stack_len];
+            if t_entry > tmax {
+                continue;
+            }
+            let node = &self.nodes[idx as usize];
+            if COUNT {
+                counts.nodes += 1;
+            }
+            let (distances, mask) = T::test(node, &wide_ray, tmin, tmax);
+
+            // children hit, sorted by entry distance
+            let mut order = [0usize; WIDTH];
+            let mut hit_count = 0;
+            for child in 0..WIDTH {
+                if node.counts[child] == EMPTY {
+                    break;
+                }
+                if mask & (1 << child) == 0 {
+                    continue;
+                }
+                let mut position = hit_count;
+                while position > 0 && distances[order[position - 1]] > distances[child] {
+                    order[position] = order[position - 1];
+                    position -= 1;
+                }
+                order[position] = child;
+                hit_count += 1;
+            }
+
+            for &child in order[..hit_count].iter() {
+                let count = node.counts[child];
+                if count == 0 || distances[child] > tmax {
+                    continue;
+                }
+                let first = node.children[child] as usize;
+                for triangle in &triangles[first..first + count as usize] {
+                    if COUNT {
+                        counts.triangles += 1;
+                    }
+                    if let Some(tri_hit) = triangle.intersect_distance(ray, tmin, tmax) {
+                        tmax = tri_hit.t;
+                        if let HitType::Any = hit_type {
+                            return Some(triangle.hit(ray, &tri_hit));
+                        }
+                        hit = Some((triangle, tri_hit));
+                    }
+                }
+            }
+            for &child in order[..hit_count].iter().rev() {
+                if node.counts[child] == 0 && distances[child] <= tmax {
+                    stack[stack_len] = (node.children[child], distances[child]);
+                    stack_len += 1;
+                }
+            }
+        }
+        // shading data for the closest hit only
+        return hit.map(|(triangle, tri_hit)| triangle.hit(ray, &tri_hit));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::random_float01;
+    use crate::vec3::Vec3;
+
+    fn random_point(rng: &mut u32, scale: f32) -> Vec3 {
+        Vec3::new(
+            random_float01(rng) - 0.5,
+            random_float01(rng) - 0.5,
+            random_float01(rng) - 0.5,
+        ) * scale
+    }
+
+    #[test]
+    fn node_layout() {
+        assert_eq!(128, std::mem::size_of::<WideNode>());
+    }
+
+    #[test]
+    fn matches_binary_bvh() {
+        let mut rng = 0x7654321u32;
+        let mut triangles: Vec<Triangle> = (0..2000)
+            .map(|_| {
+                let center = random_point(&mut rng, 10.0);
+                Triangle::new(
+                    center + random_point(&mut rng, 1.0),
+                    center + random_point(&mut rng, 1.0),
+                    center + random_point(&mut rng, 1.0),
+                )
+            })
+            .collect();
+        let bvh = Bvh::create(&mut triangles);
+        let paths = [SimdPath::Scalar, SimdPath::Sse, SimdPath::Avx];
+        let wide_bvhs: Vec<WideBvh> = paths
+            .iter()
+            .filter(|path| path.is_supported())
+            .map(|&path| WideBvh::from_bvh(&bvh).with_simd_path(path))
+            .collect();
+        assert!(wide_bvhs[0].node_count() < bvh.nodes().len() / 2);
+
+        for _ in 0..500 {
+            let origin = random_point(&mut rng, 20.0);
+            let target = random_point(&mut rng, 5.0);
+            let ray = Ray::new(&origin, &(target - origin).normalize());
+            let expected = bvh.intersect(&ray, 0.0, 100.0, HitType::Closest, &triangles);
+            for wide_bvh in wide_bvhs.iter() {
+                let hit = wide_bvh.intersect(&ray, 0.0, 100.0, HitType::Closest, &triangles);
+                assert_eq!(expected.map(|hit| hit.t), hit.map(|hit| hit.t));
+                let any = wide_bvh.intersect(&ray, 0.0, 100.0, HitType::Any, &triangles);
+                assert_eq!(expected.is_some(), any.is_some());
+                let mut counts = TraversalCounts::default();
+                let counted = wide_bvh.intersect_counted(
+                    &ray,
+                    0.0,
+                    100.0,
+                    HitType::Closest,
+                    &triangles,
+                    &mut counts,
+                );
+                assert_eq!(hit.map(|hit| hit.t), counted.map(|hit| hit.t));
+                assert!(counts.nodes > 0 && counts.nodes <= wide_bvh.node_count());
+                assert!(counts.triangles <= triangles.len());
+            }
+        }
+
+        let empty = WideBvh::from_bvh(&Bvh::create(&mut []));
+        let ray = Ray::new(&Vec3::zero(), &Vec3::new(0.0, 0.0, 1.0));
+        assert!(empty.intersect(&ray, 0.0, 1.0, HitType::Any, &[]).is_none());
+    }
+}